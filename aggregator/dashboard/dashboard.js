@@ -0,0 +1,311 @@
+"use strict";
+
+// Rooms by lowercase name
+const rooms = new Map();
+let token = localStorage.getItem("hevn-token") || "";
+
+const BOOST_DEGREES = 2;
+const BOOST_SECONDS = 3600;
+const HOUR = 3600;
+
+function showMessage(text) {
+  const message = document.getElementById("message");
+  message.textContent = text;
+  message.hidden = !text;
+}
+
+// Paths are relative, so the dashboard also works behind a proxy under a prefix
+async function api(method, path, body) {
+  const headers = {};
+  if (token) {
+    headers["Authorization"] = "Bearer " + token;
+  }
+  if (body !== undefined) {
+    headers["Content-Type"] = "application/json";
+  }
+  const resp = await fetch(path, {
+    method,
+    headers,
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  const text = await resp.text();
+  if (!resp.ok) {
+    let message = text;
+    try {
+      message = JSON.parse(text).message || text;
+    } catch (e) {
+      // Plain text error
+    }
+    const error = new Error(message || resp.statusText);
+    error.status = resp.status;
+    throw error;
+  }
+  return text ? JSON.parse(text) : null;
+}
+
+function room(name) {
+  const key = name.toLowerCase();
+  let r = rooms.get(key);
+  if (r) {
+    return r;
+  }
+  const element = document.getElementById("room").content.firstElementChild.cloneNode(true);
+  element.querySelector(".name").textContent = name;
+  document.getElementById("rooms").appendChild(element);
+  r = { name, element, heater: null, thermostat: null, history: [], reading: null };
+  element.querySelector(".on").addEventListener("click", () => switchHeater(r, "on"));
+  element.querySelector(".off").addEventListener("click", () => switchHeater(r, "off"));
+  element.querySelector(".boost").addEventListener("click", () => boost(r));
+  rooms.set(key, r);
+  return r;
+}
+
+function field(r, selector) {
+  return r.element.querySelector(selector);
+}
+
+// The average an hour before the newest point of the history, null without one
+function hourAgo(r, quantity) {
+  const points = r.history.filter((p) => p[quantity]);
+  if (points.length === 0) {
+    return null;
+  }
+  const then = points[points.length - 1].start - HOUR;
+  const earlier = points.filter((p) => p.start <= then);
+  return earlier.length ? earlier[earlier.length - 1][quantity].avg : null;
+}
+
+function trend(now, then, unit) {
+  if (now === null || then === null) {
+    return "";
+  }
+  const change = now - then;
+  const arrow = change > 0.2 ? "↑" : change < -0.2 ? "↓" : "→";
+  return `${arrow} ${change >= 0 ? "+" : ""}${change.toFixed(1)} ${unit}/h`;
+}
+
+function showReading(r, data) {
+  r.reading = data;
+  const temperature = data.temperature / 10;
+  const humidity = data.humidity / 10;
+  field(r, ".temperature").textContent = temperature.toFixed(1);
+  field(r, ".humidity").textContent = humidity.toFixed(0);
+  field(r, ".temperature-trend").textContent = trend(temperature, hourAgo(r, "temperature"), "°C");
+  field(r, ".humidity-trend").textContent = trend(humidity, hourAgo(r, "humidity"), "%");
+}
+
+function svg(tag, attributes) {
+  const element = document.createElementNS("http://www.w3.org/2000/svg", tag);
+  for (const [name, value] of Object.entries(attributes)) {
+    element.setAttribute(name, value);
+  }
+  return element;
+}
+
+// Splits the points at gaps, so lines are not drawn across missing readings
+function runs(points, quantity) {
+  const result = [[]];
+  for (const p of points) {
+    if (p[quantity]) {
+      result[result.length - 1].push(p);
+    } else if (result[result.length - 1].length) {
+      result.push([]);
+    }
+  }
+  return result.filter((run) => run.length);
+}
+
+function drawChart(r, history) {
+  const chart = field(r, ".chart");
+  chart.replaceChildren();
+  const legend = field(r, ".chart-legend");
+  const points = r.history;
+  const temperatures = points.filter((p) => p.temperature);
+  if (temperatures.length === 0) {
+    legend.textContent = "No readings in the last 24 h";
+    return;
+  }
+
+  const low = Math.min(...temperatures.map((p) => p.temperature.min));
+  const high = Math.max(...temperatures.map((p) => p.temperature.max));
+  const span = Math.max(high - low, 1);
+  const x = (p) => ((p.start - history.from) / (history.to - history.from)) * 300;
+  const yTemperature = (t) => 95 - ((t - low) / span) * 90;
+  const yHumidity = (h) => 100 - h;
+
+  for (const run of runs(points, "temperature")) {
+    const top = run.map((p) => `${x(p)},${yTemperature(p.temperature.max)}`);
+    const bottom = [...run].reverse().map((p) => `${x(p)},${yTemperature(p.temperature.min)}`);
+    chart.appendChild(svg("polygon", { class: "band", points: top.concat(bottom).join(" ") }));
+    const line = run.map((p) => `${x(p)},${yTemperature(p.temperature.avg)}`).join(" ");
+    chart.appendChild(svg("polyline", { class: "temperature-line", points: line }));
+  }
+  for (const run of runs(points, "humidity")) {
+    const line = run.map((p) => `${x(p)},${yHumidity(p.humidity.avg)}`).join(" ");
+    chart.appendChild(svg("polyline", { class: "humidity-line", points: line }));
+  }
+  legend.textContent = `24 h: ${low.toFixed(1)}–${high.toFixed(1)} °C, humidity in blue`;
+}
+
+function showHeater(r, isOn, power) {
+  field(r, ".heater").hidden = false;
+  const state = field(r, ".heater-state");
+  state.textContent = isOn ? "Heating" : "Off";
+  state.classList.toggle("is-on", isOn);
+  if (power !== undefined) {
+    field(r, ".power").textContent = `${power.toFixed(0)} W`;
+  }
+}
+
+function showDecision(r, decision) {
+  const at = new Date(decision.at * 1000).toLocaleTimeString();
+  field(r, ".decision").textContent = `${at}: ${decision.reason}`;
+}
+
+function showThermostat(r, thermostat) {
+  r.thermostat = thermostat;
+  const boost = field(r, ".boost");
+  boost.hidden = false;
+  boost.title = `Hold ${BOOST_DEGREES} °C above the target for an hour`;
+  if (thermostat.last_decision) {
+    showDecision(r, thermostat.last_decision);
+  }
+}
+
+async function refreshHeater(r) {
+  try {
+    const status = await api("GET", `heater/${encodeURIComponent(r.heater)}`);
+    showHeater(r, status.is_on, status.power);
+  } catch (e) {
+    field(r, ".heater").hidden = false;
+    field(r, ".heater-state").textContent = "Unreachable";
+    field(r, ".power").textContent = "";
+  }
+}
+
+async function control(r, action) {
+  const buttons = r.element.querySelectorAll(".controls button");
+  buttons.forEach((b) => (b.disabled = true));
+  try {
+    await action();
+    showMessage("");
+  } catch (e) {
+    showMessage(e.status === 401 || e.status === 403 ? "A token with the control scope is needed" : e.message);
+  } finally {
+    buttons.forEach((b) => (b.disabled = false));
+  }
+}
+
+function switchHeater(r, state) {
+  return control(r, async () => {
+    const status = await api("POST", `heater/${encodeURIComponent(r.heater)}/${state}`);
+    showHeater(r, status.is_on, status.power);
+  });
+}
+
+// Holds a setpoint above the current target for an hour through the thermostat
+function boost(r) {
+  return control(r, async () => {
+    const room = encodeURIComponent(r.thermostat.room);
+    const thermostat = await api("GET", `thermostats/${room}`);
+    const base = thermostat.target === null ? thermostat.setpoint : thermostat.target;
+    await api("PUT", `thermostats/${room}/override`, {
+      setpoint: base + BOOST_DEGREES,
+      duration: BOOST_SECONDS,
+    });
+  });
+}
+
+async function loadHistory() {
+  const names = [...rooms.values()].map((r) => (r.reading ? r.reading.room : r.name));
+  if (names.length === 0) {
+    return;
+  }
+  let history;
+  try {
+    history = await api("GET", `history?room=${names.map(encodeURIComponent).join(",")}`);
+  } catch (e) {
+    const text = e.status === 404 ? "Stored history is not enabled" : "History could not be read";
+    rooms.forEach((r) => (field(r, ".chart-legend").textContent = text));
+    return;
+  }
+  for (const h of history.rooms) {
+    const r = room(h.room);
+    r.history = h.points;
+    drawChart(r, history);
+    if (r.reading) {
+      showReading(r, r.reading);
+    }
+  }
+}
+
+async function load() {
+  try {
+    const [readings, health, thermostats] = await Promise.all([
+      api("GET", "read?format=status"),
+      api("GET", "devices/health"),
+      api("GET", "thermostats"),
+    ]);
+    showMessage("");
+    for (const collector of readings) {
+      const r = room(collector.room);
+      if (collector.data) {
+        showReading(r, collector.data);
+      }
+    }
+    for (const heater of health.heaters) {
+      const r = room(heater.room);
+      r.heater = heater.id;
+      refreshHeater(r);
+    }
+    for (const thermostat of thermostats) {
+      showThermostat(room(thermostat.room), thermostat);
+    }
+  } catch (e) {
+    showMessage(e.status === 401 || e.status === 403 ? "A token with the read scope is needed" : e.message);
+    return;
+  }
+  await loadHistory();
+}
+
+function follow() {
+  const url = new URL("live", location.href);
+  url.protocol = location.protocol === "https:" ? "wss:" : "ws:";
+  if (token) {
+    url.searchParams.set("token", token);
+  }
+  const socket = new WebSocket(url);
+  const connection = document.getElementById("connection");
+  socket.onopen = () => (connection.textContent = "Live");
+  socket.onmessage = (message) => {
+    const event = JSON.parse(message.data);
+    if (event.type === "reading") {
+      showReading(room(event.data.room), event.data);
+    } else if (event.type === "heater") {
+      const r = room(event.room);
+      r.heater = event.heater;
+      showHeater(r, event.is_on);
+    } else if (event.type === "decision") {
+      showDecision(room(event.room), event.decision);
+    }
+  };
+  socket.onclose = () => {
+    connection.textContent = "Reconnecting…";
+    setTimeout(follow, 5000);
+  };
+}
+
+document.getElementById("token").addEventListener("click", () => {
+  const entered = prompt("API token, kept in this browser", token);
+  if (entered !== null) {
+    token = entered.trim();
+    localStorage.setItem("hevn-token", token);
+    location.reload();
+  }
+});
+
+load();
+follow();
+// Power is not part of the live feed, and the charts move on
+setInterval(() => rooms.forEach((r) => r.heater && refreshHeater(r)), 60 * 1000);
+setInterval(loadHistory, 5 * 60 * 1000);
@@ -0,0 +1,381 @@
+use crate::config::{AlertConfig, Condition, EventKind, Seconds};
+use crate::devices::Devices;
+use crate::notify::Notification;
+use crate::status::{CollectorStatus, Readings};
+use actix_web::{get, web, Responder};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use util::openapi::{integer, number, object, reference, string, string_enum, Schema};
+
+/// Seconds a resolved alert is still listed
+const RESOLVED_KEPT: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    /// The condition holds, but not for long enough yet
+    Pending,
+    Firing,
+    /// The condition stopped holding after the alert fired
+    Resolved,
+}
+
+/// A rule that matched one collector
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub collector: String,
+    pub room: String,
+    pub state: AlertState,
+    /// Seconds since the unix epoch the condition started holding
+    pub since: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fired_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_at: Option<u64>,
+    /// Last value seen: °C, % or seconds since the last reading
+    pub value: f32,
+    pub message: String,
+}
+
+impl Schema for AlertState {
+    const NAME: &'static str = "AlertState";
+
+    fn schema() -> serde_json::Value {
+        string_enum(&["pending", "firing", "resolved"])
+    }
+}
+
+impl Schema for Alert {
+    const NAME: &'static str = "Alert";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("rule", string()),
+                ("collector", string()),
+                ("room", string()),
+                ("state", reference::<AlertState>()),
+                ("since", integer()),
+                ("value", number()),
+                ("message", string()),
+            ],
+            &[("fired_at", integer()), ("resolved_at", integer())],
+        )
+    }
+}
+
+/// Whether the condition holds for a collector, and the value it was decided on.
+/// `None` when there is nothing to decide on, like a threshold without a reading
+fn holds(
+    condition: Condition,
+    status: &CollectorStatus,
+    now: u64,
+    started: u64,
+) -> Option<(bool, f32)> {
+    let reading = |f: fn(&util::EnvData) -> f32| status.data.as_ref().map(|d| f(d) / 10.0);
+    match condition {
+        Condition::TemperatureBelow(limit) => {
+            reading(|d| d.temperature as f32).map(|t| (t < limit, t))
+        }
+        Condition::TemperatureAbove(limit) => {
+            reading(|d| d.temperature as f32).map(|t| (t > limit, t))
+        }
+        Condition::HumidityBelow(limit) => reading(|d| d.humidity as f32).map(|h| (h < limit, h)),
+        Condition::HumidityAbove(limit) => reading(|d| d.humidity as f32).map(|h| (h > limit, h)),
+        Condition::Stale(Seconds(limit)) => {
+            let age = now.saturating_sub(status.last_success.unwrap_or(started));
+            Some((age >= limit, age as f32))
+        }
+    }
+}
+
+fn message(condition: Condition, room: &str, value: f32) -> String {
+    match condition {
+        Condition::TemperatureBelow(limit) => {
+            format!("{} is at {:.1} °C, below {:.1} °C", room, value, limit)
+        }
+        Condition::TemperatureAbove(limit) => {
+            format!("{} is at {:.1} °C, above {:.1} °C", room, value, limit)
+        }
+        Condition::HumidityBelow(limit) => {
+            format!(
+                "{} is at {:.0} % humidity, below {:.0} %",
+                room, value, limit
+            )
+        }
+        Condition::HumidityAbove(limit) => {
+            format!(
+                "{} is at {:.0} % humidity, above {:.0} %",
+                room, value, limit
+            )
+        }
+        Condition::Stale(_) => format!("No reading from {} for {} seconds", room, value),
+    }
+}
+
+/// The alert rules and what they have found, one alert for each rule and collector
+pub struct Alerts {
+    rules: Vec<AlertConfig>,
+    /// By rule and collector
+    alerts: Mutex<HashMap<(String, String), Alert>>,
+    /// When the aggregator started, collectors never read are stale from here
+    started: u64,
+    pub interval: Duration,
+}
+
+impl Alerts {
+    pub fn new(rules: Vec<AlertConfig>, interval: Duration) -> Self {
+        Self {
+            rules,
+            alerts: Mutex::new(HashMap::new()),
+            started: util::now(),
+            interval,
+        }
+    }
+
+    /// Moves the alerts on with the collectors as they are now,
+    /// returns the alerts that fired or resolved
+    pub fn evaluate(&self, statuses: &[CollectorStatus], now: u64) -> Vec<Alert> {
+        let mut alerts = self.alerts.lock().unwrap();
+        let mut changed = Vec::new();
+        for rule in &self.rules {
+            let condition = rule.condition();
+            for status in statuses.iter().filter(|s| rule.is_watching(&s.room)) {
+                let key = (rule.name.clone(), status.id.clone());
+                let (is_holding, value) = match holds(condition, status, now, self.started) {
+                    Some(decided) => decided,
+                    None => continue,
+                };
+                let alert = alerts.get_mut(&key);
+                match (alert, is_holding) {
+                    (Some(alert), true) if alert.state != AlertState::Resolved => {
+                        alert.value = value;
+                        alert.message = message(condition, &status.room, value);
+                        if alert.state == AlertState::Pending
+                            && now.saturating_sub(alert.since) >= rule.duration.0
+                        {
+                            alert.state = AlertState::Firing;
+                            alert.fired_at = Some(now);
+                            changed.push(alert.clone());
+                        }
+                    }
+                    (Some(alert), false) if alert.state == AlertState::Firing => {
+                        alert.state = AlertState::Resolved;
+                        alert.resolved_at = Some(now);
+                        alert.value = value;
+                        changed.push(alert.clone());
+                    }
+                    (Some(alert), false) if alert.state == AlertState::Pending => {
+                        alerts.remove(&key);
+                    }
+                    (_, true) => {
+                        let mut alert = Alert {
+                            rule: rule.name.clone(),
+                            collector: status.id.clone(),
+                            room: status.room.clone(),
+                            state: AlertState::Pending,
+                            since: now,
+                            fired_at: None,
+                            resolved_at: None,
+                            value,
+                            message: message(condition, &status.room, value),
+                        };
+                        if rule.duration.0 == 0 {
+                            alert.state = AlertState::Firing;
+                            alert.fired_at = Some(now);
+                            changed.push(alert.clone());
+                        }
+                        alerts.insert(key, alert);
+                    }
+                    (_, false) => {}
+                }
+            }
+        }
+        alerts.retain(|_, a| {
+            a.resolved_at
+                .is_none_or(|at| now.saturating_sub(at) < RESOLVED_KEPT)
+        });
+        changed
+    }
+
+    /// The alerts, firing ones first
+    pub fn list(&self) -> Vec<Alert> {
+        let mut alerts: Vec<Alert> = self.alerts.lock().unwrap().values().cloned().collect();
+        let order = |a: &Alert| match a.state {
+            AlertState::Firing => 0,
+            AlertState::Pending => 1,
+            AlertState::Resolved => 2,
+        };
+        alerts.sort_by(|a, b| {
+            (order(a), &a.rule, &a.collector).cmp(&(order(b), &b.rule, &b.collector))
+        });
+        alerts
+    }
+}
+
+/// Checks the alert rules every `interval`, through the same cache as `/`
+pub async fn poll(
+    alerts: web::Data<Alerts>,
+    devices: web::Data<Devices>,
+    client: web::Data<reqwest::Client>,
+    readings: web::Data<Readings>,
+) {
+    if alerts.rules.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(alerts.interval);
+    loop {
+        interval.tick().await;
+        let statuses = readings.read_all(&devices, &client, "data", None).await;
+        for alert in alerts.evaluate(&statuses, util::now()) {
+            let (event, message) = match alert.state {
+                AlertState::Firing => {
+                    warn!("Alert {} is firing, {}", alert.rule, alert.message);
+                    (
+                        EventKind::AlertFiring,
+                        format!("{}: {}", alert.rule, alert.message),
+                    )
+                }
+                _ => {
+                    info!("Alert {} for {} is resolved", alert.rule, alert.room);
+                    (
+                        EventKind::AlertResolved,
+                        format!("{} is resolved in {}", alert.rule, alert.room),
+                    )
+                }
+            };
+            devices.notify(Notification::new(
+                event,
+                &alert.room,
+                &alert.collector,
+                message,
+            ));
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AlertQuery {
+    state: Option<AlertState>,
+}
+
+/// The alerts, `?state=firing` only lists those firing
+#[get("/alerts")]
+async fn list_alerts(alerts: web::Data<Alerts>, query: web::Query<AlertQuery>) -> impl Responder {
+    let mut list = alerts.list();
+    if let Some(state) = query.state {
+        list.retain(|a| a.state == state);
+    }
+    web::Json(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use util::EnvData;
+
+    fn status(temperature: i16, last_success: Option<u64>) -> CollectorStatus {
+        CollectorStatus {
+            id: "bedroom".to_string(),
+            room: "Bedroom".to_string(),
+            latency: 1,
+            cached: false,
+            last_success,
+            data: last_success.map(|timestamp| EnvData {
+                room: "Bedroom".to_string(),
+                temperature,
+                humidity: 400,
+                timestamp,
+            }),
+            error: None,
+        }
+    }
+
+    /// A collector that did not answer
+    fn silent(last_success: Option<u64>) -> CollectorStatus {
+        CollectorStatus {
+            data: None,
+            ..status(0, last_success)
+        }
+    }
+
+    fn rules(config: &str) -> Alerts {
+        let config = Config::parse(&format!(
+            "[[collector]]\nroom = \"Bedroom\"\nurl = \"http://127.0.0.1:1\"\n{}",
+            config
+        ))
+        .unwrap();
+        let mut alerts = Alerts::new(config.alerts, Duration::from_secs(60));
+        alerts.started = 0;
+        alerts
+    }
+
+    #[test]
+    fn is_alert_firing_after_duration() {
+        let alerts = rules("[[alert]]\nname = \"frost\"\ntemperature_below = 5.0\nfor = \"2h\"");
+
+        assert!(alerts.evaluate(&[status(40, Some(1000))], 1000).is_empty());
+        assert_eq!(alerts.list()[0].state, AlertState::Pending);
+        assert!(alerts.evaluate(&[status(45, Some(3000))], 3000).is_empty());
+
+        let fired = alerts.evaluate(&[status(41, Some(8200))], 8200);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].state, AlertState::Firing);
+        assert_eq!(fired[0].since, 1000);
+        assert_eq!(fired[0].message, "Bedroom is at 4.1 °C, below 5.0 °C");
+        // Firing again is not told twice
+        assert!(alerts.evaluate(&[status(39, Some(8300))], 8300).is_empty());
+        // Without a reading nothing changes
+        assert!(alerts.evaluate(&[silent(Some(8300))], 8400).is_empty());
+
+        let resolved = alerts.evaluate(&[status(60, Some(8500))], 8500);
+        assert_eq!(resolved[0].state, AlertState::Resolved);
+        assert_eq!(resolved[0].resolved_at, Some(8500));
+        assert_eq!(alerts.list().len(), 1);
+
+        alerts.evaluate(
+            &[status(60, Some(8500 + RESOLVED_KEPT))],
+            8500 + RESOLVED_KEPT,
+        );
+        assert!(alerts.list().is_empty());
+    }
+
+    #[test]
+    fn is_pending_alert_dropped() {
+        let alerts = rules("[[alert]]\nname = \"frost\"\ntemperature_below = 5.0\nfor = 600");
+        alerts.evaluate(&[status(40, Some(1000))], 1000);
+        assert!(alerts.evaluate(&[status(60, Some(1300))], 1300).is_empty());
+        assert!(alerts.list().is_empty());
+
+        // Starts over when it holds again
+        alerts.evaluate(&[status(40, Some(1400))], 1400);
+        assert!(alerts.evaluate(&[status(40, Some(1900))], 1900).is_empty());
+        assert_eq!(alerts.evaluate(&[status(40, Some(2000))], 2000).len(), 1);
+    }
+
+    #[test]
+    fn is_silent_collector_found() {
+        let alerts = rules(
+            "[[alert]]\nname = \"silent\"\nrooms = [\"bedroom\"]\nstale = \"15m\"\n\
+             [[alert]]\nname = \"mold\"\nrooms = [\"Bedroom\"]\nhumidity_above = 70.0",
+        );
+        assert!(alerts.evaluate(&[status(200, Some(100))], 900).is_empty());
+        let fired = alerts.evaluate(&[silent(Some(100))], 1000);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].rule, "silent");
+        assert_eq!(fired[0].value, 900.0);
+        assert_eq!(
+            alerts.evaluate(&[status(200, Some(1100))], 1100)[0].state,
+            AlertState::Resolved
+        );
+
+        // Never read since the aggregator started
+        let alerts = rules("[[alert]]\nname = \"silent\"\nstale = 900");
+        assert_eq!(alerts.evaluate(&[silent(None)], 1000)[0].value, 1000.0);
+    }
+}
@@ -1,56 +1,110 @@
-use serde::Deserialize;
-use std::path::Path;
+use crate::breaker::{Breaker, Down};
+use crate::config::{DeviceType, HeaterConfig};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use util::{ShellyS1, ShellyS1Error, ShellyStatus, SmartAppliance};
 
-#[derive(Deserialize)]
-pub struct MyCollector {
-    url: String,
-    room: String,
+#[derive(Debug)]
+pub enum HeaterError {
+    /// The heater was not contacted because it is down
+    Down(Down),
+    Device(ShellyS1Error),
 }
 
-impl MyCollector {
-    pub fn from_json(json: &Path) -> Vec<Self> {
-        let file = std::fs::read_to_string(json).unwrap();
-        serde_json::from_str(&file).unwrap()
-    }
-
-    pub fn get_url(&self) -> &str {
-        &self.url
-    }
+impl std::error::Error for HeaterError {}
 
-    pub fn get_room(&self) -> &str {
-        &self.room
+impl fmt::Display for HeaterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaterError::Down(e) => write!(f, "{}", e),
+            HeaterError::Device(e) => write!(f, "{}", e),
+        }
     }
 }
 
 pub struct Heater {
+    id: String,
     room: String,
-    device: ShellyS1,
+    device: Arc<ShellyS1>,
+    breaker: Arc<Breaker>,
+    /// What the device last answered, for `/metrics`
+    last_status: Mutex<Option<ShellyStatus>>,
 }
 
 impl Heater {
-    pub fn new(url: String, room: String) -> Self {
-        let device = ShellyS1::new(room.clone(), url);
-        Self { room, device }
+    pub fn new(config: &HeaterConfig, timeout: Duration, breaker: Arc<Breaker>) -> Self {
+        let device = match config.device_type {
+            DeviceType::ShellyS1 => {
+                let device = ShellyS1::new(config.room.clone(), config.address.clone())
+                    .channel(config.channel)
+                    .timeout(timeout);
+                match &config.username {
+                    Some(username) => device.credentials(
+                        username.clone(),
+                        config.password.clone().unwrap_or_default(),
+                    ),
+                    None => device,
+                }
+            }
+        };
+        Self {
+            id: config.id.clone(),
+            room: config.room.clone(),
+            device: Arc::new(device),
+            breaker,
+            last_status: Mutex::new(None),
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
     }
 
     pub fn get_room(&self) -> &str {
         &self.room
     }
 
-    pub async fn get_status(&self) -> Result<ShellyStatus, ShellyS1Error> {
-        self.device.get_status().map_err(|e| e)
+    /// Runs a call to the device without blocking the runtime,
+    /// fails right away while the device is down
+    async fn call<T, F>(&self, f: F) -> Result<T, HeaterError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ShellyS1) -> Result<T, ShellyS1Error> + Send + 'static,
+    {
+        self.breaker.check().map_err(HeaterError::Down)?;
+        let device = self.device.clone();
+        let result = tokio::task::spawn_blocking(move || f(&device))
+            .await
+            .unwrap_or_else(|_| Err(ShellyS1Error::default()));
+        if result.is_ok() {
+            self.breaker.success();
+        } else {
+            self.breaker.failure();
+        }
+        result.map_err(HeaterError::Device)
+    }
+
+    pub async fn get_status(&self) -> Result<ShellyStatus, HeaterError> {
+        let status = self.call(|d| d.get_status()).await?;
+        *self.last_status.lock().unwrap() = Some(status.clone());
+        Ok(status)
+    }
+
+    /// The status the device last answered, without contacting it
+    pub fn last_status(&self) -> Option<ShellyStatus> {
+        self.last_status.lock().unwrap().clone()
     }
 
-    pub async fn turn_on(&self) -> Result<String, ShellyS1Error> {
-        match self.device.turn_on() {
+    pub async fn turn_on(&self) -> Result<String, HeaterError> {
+        match self.call(|d| d.turn_on()).await {
             Ok(_) => Ok(format!("{} turned on", self.room)),
             Err(e) => Err(e),
         }
     }
 
-    pub async fn turn_off(&self) -> Result<String, ShellyS1Error> {
-        match self.device.turn_off() {
+    pub async fn turn_off(&self) -> Result<String, HeaterError> {
+        match self.call(|d| d.turn_off()).await {
             Ok(_) => Ok(format!("Turned off {}", self.room)),
             Err(e) => Err(e),
         }
@@ -0,0 +1,368 @@
+use crate::config::{Config, Scope, TokenConfig};
+use crate::error::ErrorBody;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum TokenCommand {
+    /// Adds a token and prints it, only its hash is kept
+    Add {
+        name: String,
+        /// read, control, ingest or admin
+        #[structopt(short = "s", long = "scope", default_value = "read")]
+        scope: Scope,
+    },
+    /// Lists the names and scopes of the tokens
+    List,
+    /// Removes the token with the given name
+    Remove { name: String },
+}
+
+/// Changes the tokens in the config file, the aggregator must be restarted to use them
+pub fn run(command: TokenCommand, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::from_file(path)?;
+    match command {
+        TokenCommand::Add { name, scope } => {
+            if config.tokens.iter().any(|t| t.name == name) {
+                return Err(format!("There is already a token named {}", name).into());
+            }
+            let token = new_token();
+            config.tokens.push(TokenConfig {
+                name,
+                scope,
+                hash: hash_token(&token),
+            });
+            config.save(path)?;
+            println!("{}", token);
+        }
+        TokenCommand::List => {
+            for token in &config.tokens {
+                println!("{}\t{}", token.name, token.scope);
+            }
+        }
+        TokenCommand::Remove { name } => {
+            let count = config.tokens.len();
+            config.tokens.retain(|t| t.name != name);
+            if config.tokens.len() == count {
+                return Err(format!("No token named {}", name).into());
+            }
+            config.save(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// What is kept in the config instead of the token itself
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A new random token
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "control" => Ok(Scope::Control),
+            "ingest" => Ok(Scope::Ingest),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("{} is not read, control, ingest or admin", s)),
+        }
+    }
+}
+
+impl Scope {
+    /// Whether a token with this scope may do what needs `required`
+    pub fn allows(self, required: Scope) -> bool {
+        match (self, required) {
+            (Scope::Admin, _) => true,
+            (Scope::Control, Scope::Read) => true,
+            _ => self == required,
+        }
+    }
+}
+
+/// Scope needed for a request
+pub fn required_scope(method: &Method, path: &str) -> Scope {
+    if path == "/ingest" {
+        Scope::Ingest
+    } else if path.starts_with("/devices") && path != "/devices/health" {
+        Scope::Admin
+    } else if method == Method::GET || method == Method::HEAD {
+        Scope::Read
+    } else {
+        Scope::Control
+    }
+}
+
+/// The tokens the aggregator accepts, by hash
+pub struct Tokens {
+    scopes: HashMap<String, Scope>,
+    /// Scope of requests without a token
+    anonymous: Option<Scope>,
+}
+
+impl Tokens {
+    pub fn new(config: &Config) -> Self {
+        let scopes = config
+            .tokens
+            .iter()
+            .map(|t| (t.hash.clone(), t.scope))
+            .collect();
+        Self {
+            scopes,
+            anonymous: config.anonymous,
+        }
+    }
+
+    /// Scope of the request, `Err` when it carries a token that is not known
+    fn scope(&self, token: Option<&str>) -> Result<Option<Scope>, ()> {
+        match token {
+            Some(token) => self
+                .scopes
+                .get(&hash_token(token))
+                .map(|s| Some(*s))
+                .ok_or(()),
+            None => Ok(self.anonymous),
+        }
+    }
+}
+
+/// Whether anyone may get the path, the dashboard pages hold no data themselves
+pub fn is_public(path: &str) -> bool {
+    path == "/dashboard" || path.starts_with("/dashboard/")
+}
+
+/// The percent-decoded path the request is routed on, `req.path()` is still encoded
+/// so `/%64evices` would not look like `/devices` there
+fn routed_path(req: &ServiceRequest) -> &str {
+    req.match_info().as_str()
+}
+
+/// The token of the request, browsers cannot set headers on WebSockets
+/// so `/live` also takes it as the `token` query parameter
+fn request_token(req: &ServiceRequest) -> Option<&str> {
+    let header = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if header.is_some() || routed_path(req) != "/live" {
+        return header;
+    }
+    req.query_string()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+}
+
+/// Middleware turning away requests without a token allowing them
+pub struct Auth {
+    tokens: Arc<Tokens>,
+}
+
+impl Auth {
+    pub fn new(tokens: Arc<Tokens>) -> Self {
+        Self { tokens }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Auth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthMiddleware {
+            service: Rc::new(service),
+            tokens: self.tokens.clone(),
+        }))
+    }
+}
+
+pub struct AuthMiddleware<S> {
+    service: Rc<S>,
+    tokens: Arc<Tokens>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let path = routed_path(&req);
+        let required = required_scope(req.method(), path);
+        let denied = match self.tokens.scope(request_token(&req)) {
+            _ if is_public(path) => None,
+            Err(()) => Some(HttpResponse::Unauthorized().json(ErrorBody {
+                error: "unauthorized",
+                message: "The token is not known".to_string(),
+            })),
+            Ok(None) => Some(HttpResponse::Unauthorized().json(ErrorBody {
+                error: "unauthorized",
+                message: "A token is needed".to_string(),
+            })),
+            Ok(Some(scope)) if !scope.allows(required) => {
+                Some(HttpResponse::Forbidden().json(ErrorBody {
+                    error: "forbidden",
+                    message: format!("Needs the {} scope", required),
+                }))
+            }
+            Ok(Some(_)) => None,
+        };
+        if let Some(response) = denied {
+            return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+        }
+
+        let service = self.service.clone();
+        Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    #[test]
+    fn is_scope_required() {
+        assert_eq!(required_scope(&Method::GET, "/read"), Scope::Read);
+        assert_eq!(
+            required_scope(&Method::POST, "/heater/bedroom/on"),
+            Scope::Control
+        );
+        assert_eq!(required_scope(&Method::GET, "/devices"), Scope::Admin);
+        assert_eq!(required_scope(&Method::GET, "/devices/health"), Scope::Read);
+        assert_eq!(required_scope(&Method::POST, "/ingest"), Scope::Ingest);
+
+        assert!(Scope::Control.allows(Scope::Read));
+        assert!(!Scope::Read.allows(Scope::Control));
+        assert!(!Scope::Control.allows(Scope::Ingest));
+        assert!(Scope::Admin.allows(Scope::Ingest));
+    }
+
+    #[actix_web::test]
+    async fn is_request_checked() {
+        let mut config = Config::parse("").unwrap();
+        config.anonymous = Some(Scope::Read);
+        config.tokens.push(TokenConfig {
+            name: "lumberjack".to_string(),
+            scope: Scope::Control,
+            hash: hash_token("secret"),
+        });
+        let tokens = Arc::new(Tokens::new(&config));
+        let app = init_service(
+            App::new()
+                .wrap(Auth::new(tokens))
+                .route("/read", web::get().to(HttpResponse::Ok))
+                .route("/heater/bedroom/on", web::post().to(HttpResponse::Ok))
+                .route("/live", web::get().to(HttpResponse::Ok))
+                .route("/dashboard", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let status = |req: TestRequest| {
+            let app = &app;
+            async move { call_service(app, req.to_request()).await.status() }
+        };
+        assert_eq!(
+            status(TestRequest::get().uri("/read")).await,
+            StatusCode::OK
+        );
+        let on = || TestRequest::post().uri("/heater/bedroom/on");
+        assert_eq!(status(on()).await, StatusCode::FORBIDDEN);
+        let wrong = on().insert_header(("Authorization", "Bearer guess"));
+        assert_eq!(status(wrong).await, StatusCode::UNAUTHORIZED);
+        let right = on().insert_header(("Authorization", "Bearer secret"));
+        assert_eq!(status(right).await, StatusCode::OK);
+
+        let live = TestRequest::get().uri("/live?room=bedroom&token=guess");
+        assert_eq!(status(live).await, StatusCode::UNAUTHORIZED);
+        let query = TestRequest::post().uri("/heater/bedroom/on?token=secret");
+        assert_eq!(status(query).await, StatusCode::FORBIDDEN);
+        let wrong = TestRequest::get()
+            .uri("/dashboard")
+            .insert_header(("Authorization", "Bearer guess"));
+        assert_eq!(status(wrong).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn is_encoded_path_checked() {
+        let mut config = Config::parse("").unwrap();
+        config.anonymous = Some(Scope::Read);
+        for (name, scope) in [("lumberjack", Scope::Control), ("me", Scope::Admin)] {
+            config.tokens.push(TokenConfig {
+                name: name.to_string(),
+                scope,
+                hash: hash_token(name),
+            });
+        }
+        let tokens = Arc::new(Tokens::new(&config));
+        let app = init_service(
+            App::new()
+                .wrap(Auth::new(tokens))
+                .route("/devices", web::get().to(HttpResponse::Ok))
+                .route("/devices/collectors", web::post().to(HttpResponse::Ok))
+                .route("/devices/health", web::get().to(HttpResponse::Ok))
+                .route("/ingest", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let status = |req: TestRequest, token: Option<&str>| {
+            let app = &app;
+            let req = match token {
+                Some(token) => req.insert_header(("Authorization", format!("Bearer {}", token))),
+                None => req,
+            };
+            async move { call_service(app, req.to_request()).await.status() }
+        };
+        let devices = || TestRequest::get().uri("/%64evices");
+        assert_eq!(status(devices(), None).await, StatusCode::FORBIDDEN);
+        let control = Some("lumberjack");
+        assert_eq!(status(devices(), control).await, StatusCode::FORBIDDEN);
+        // It is routed to `/devices`
+        assert_eq!(status(devices(), Some("me")).await, StatusCode::OK);
+
+        let add = TestRequest::post().uri("/%64evices/collectors");
+        assert_eq!(status(add, control).await, StatusCode::FORBIDDEN);
+        let ingest = TestRequest::post().uri("/%69ngest");
+        assert_eq!(status(ingest, control).await, StatusCode::FORBIDDEN);
+        let health = TestRequest::get().uri("/devices%2Fhealth");
+        assert_eq!(status(health, None).await, StatusCode::FORBIDDEN);
+        let unknown = TestRequest::get()
+            .uri("/%64evices")
+            .insert_header(("Authorization", "Bearer guess"));
+        assert_eq!(status(unknown, None).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::config::BreakerConfig;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use util::openapi::{string_enum, Schema};
+
+/// State of the circuit breaker of a device
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// The device is used as normal
+    Closed,
+    /// The device is not contacted until the backoff is over
+    Open,
+    /// One request is let through to see if the device is back
+    HalfOpen,
+}
+
+impl Schema for State {
+    const NAME: &'static str = "BreakerState";
+
+    fn schema() -> serde_json::Value {
+        string_enum(&["closed", "open", "half_open"])
+    }
+}
+
+/// The device was not contacted because it is down
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Down {
+    pub name: String,
+    /// Seconds until the device is tried again
+    pub retry_in: u64,
+}
+
+impl std::error::Error for Down {}
+
+impl fmt::Display for Down {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is down, trying again in {} seconds",
+            self.name, self.retry_in
+        )
+    }
+}
+
+/// What the breaker of a device looks like from the outside
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct BreakerStatus {
+    pub state: State,
+    pub consecutive_failures: u32,
+    /// Seconds until the device is tried again when it is down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in: Option<u64>,
+}
+
+struct Inner {
+    failures: u32,
+    /// When the breaker opened and for how long
+    open: Option<(Instant, Duration)>,
+    /// When the request let through to check the device started
+    probe: Option<Instant>,
+}
+
+/// Stops contacting a device after it fails `failures` times in a row,
+/// then lets one request through after a backoff that doubles every time the device is still down
+pub struct Breaker {
+    name: String,
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl Breaker {
+    pub fn new(name: String, config: BreakerConfig) -> Self {
+        Self {
+            name,
+            config,
+            inner: Mutex::new(Inner {
+                failures: 0,
+                open: None,
+                probe: None,
+            }),
+        }
+    }
+
+    /// Whether the device may be contacted now
+    pub fn check(&self) -> Result<(), Down> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Result<(), Down> {
+        let mut inner = self.inner.lock().unwrap();
+        let (opened, backoff) = match inner.open {
+            Some(open) => open,
+            None => return Ok(()),
+        };
+        let retry_at = opened + backoff;
+        // A probe that never finished does not keep the device shut forever
+        let probing = inner.probe.is_some_and(|p| now < p + backoff);
+        if now < retry_at || probing {
+            let retry_at = inner.probe.map_or(retry_at, |p| p + backoff);
+            return Err(Down {
+                name: self.name.clone(),
+                retry_in: retry_at.saturating_duration_since(now).as_secs(),
+            });
+        }
+        inner.probe = Some(now);
+        Ok(())
+    }
+
+    /// Returns whether the device was down until now
+    pub fn success(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let was_down = inner.open.is_some();
+        if was_down {
+            info!("{} is back up", self.name);
+        }
+        inner.failures = 0;
+        inner.open = None;
+        inner.probe = None;
+        was_down
+    }
+
+    /// Returns whether the device is taken as down from now on
+    pub fn failure(&self) -> bool {
+        self.failure_at(Instant::now())
+    }
+
+    fn failure_at(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        let was_down = inner.open.is_some();
+        let backoff = match inner.open {
+            Some(_) if inner.probe.is_none() => return false,
+            Some((_, backoff)) => (backoff * 2).min(Duration::from_secs(self.config.max_backoff)),
+            None if inner.failures >= self.config.failures => {
+                Duration::from_secs(self.config.backoff)
+            }
+            None => return false,
+        };
+        warn!(
+            "{} failed {} times in a row, trying again in {} seconds",
+            self.name,
+            inner.failures,
+            backoff.as_secs()
+        );
+        inner.open = Some((now, backoff));
+        inner.probe = None;
+        !was_down
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        self.status_at(Instant::now())
+    }
+
+    fn status_at(&self, now: Instant) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        let (state, retry_in) = match inner.open {
+            None => (State::Closed, None),
+            Some(_) if inner.probe.is_some() => (State::HalfOpen, None),
+            Some((opened, backoff)) if now >= opened + backoff => (State::HalfOpen, Some(0)),
+            Some((opened, backoff)) => (
+                State::Open,
+                Some((opened + backoff).saturating_duration_since(now).as_secs()),
+            ),
+        };
+        BreakerStatus {
+            state,
+            consecutive_failures: inner.failures,
+            retry_in,
+        }
+    }
+}
+
+/// The breakers of the collectors and heaters, by id
+pub struct Breakers {
+    config: BreakerConfig,
+    collectors: Mutex<HashMap<String, Arc<Breaker>>>,
+    heaters: Mutex<HashMap<String, Arc<Breaker>>>,
+}
+
+fn get_or_add(
+    breakers: &Mutex<HashMap<String, Arc<Breaker>>>,
+    name: String,
+    id: &str,
+    config: BreakerConfig,
+) -> Arc<Breaker> {
+    breakers
+        .lock()
+        .unwrap()
+        .entry(id.to_string())
+        .or_insert_with(|| Arc::new(Breaker::new(name, config)))
+        .clone()
+}
+
+impl Breakers {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            collectors: Mutex::new(HashMap::new()),
+            heaters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn collector(&self, id: &str) -> Arc<Breaker> {
+        let name = format!("Collector {}", id);
+        get_or_add(&self.collectors, name, id, self.config)
+    }
+
+    pub fn heater(&self, id: &str) -> Arc<Breaker> {
+        let name = format!("Heater {}", id);
+        get_or_add(&self.heaters, name, id, self.config)
+    }
+
+    /// A breaker of its own, to try a heater before it is taken in
+    pub fn untracked_heater(&self, id: &str) -> Arc<Breaker> {
+        Arc::new(Breaker::new(format!("Heater {}", id), self.config))
+    }
+
+    /// Drops the breakers of devices that are gone or changed
+    pub fn forget(&self, collector: Option<&str>, heater: Option<&str>) {
+        if let Some(id) = collector {
+            self.collectors.lock().unwrap().remove(id);
+        }
+        if let Some(id) = heater {
+            self.heaters.lock().unwrap().remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> Breaker {
+        Breaker::new(
+            "Collector bedroom".to_string(),
+            BreakerConfig {
+                failures: 2,
+                backoff: 10,
+                max_backoff: 30,
+            },
+        )
+    }
+
+    #[test]
+    fn is_opened_after_failures() {
+        let breaker = breaker();
+        let now = Instant::now();
+        assert!(!breaker.failure_at(now));
+        assert!(breaker.check_at(now).is_ok());
+        assert!(breaker.failure_at(now));
+        assert_eq!(
+            breaker.check_at(now + Duration::from_secs(4)),
+            Err(Down {
+                name: "Collector bedroom".to_string(),
+                retry_in: 6,
+            })
+        );
+        assert_eq!(breaker.status_at(now).state, State::Open);
+
+        assert!(breaker.success());
+        assert!(!breaker.success());
+        assert!(breaker.check_at(now).is_ok());
+        assert_eq!(breaker.status_at(now).consecutive_failures, 0);
+    }
+
+    #[test]
+    fn is_one_probe_let_through() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.failure_at(now);
+        breaker.failure_at(now);
+
+        let later = now + Duration::from_secs(10);
+        assert!(breaker.check_at(later).is_ok());
+        assert_eq!(breaker.status_at(later).state, State::HalfOpen);
+        assert!(breaker.check_at(later).is_err());
+
+        // A probe that never reports back is given up on
+        assert!(breaker.check_at(later + Duration::from_secs(10)).is_ok());
+    }
+
+    #[test]
+    fn is_backoff_doubled() {
+        let breaker = breaker();
+        let mut now = Instant::now();
+        breaker.failure_at(now);
+        breaker.failure_at(now);
+        for backoff in [10, 20, 30, 30] {
+            assert_eq!(breaker.status_at(now).retry_in, Some(backoff));
+            now += Duration::from_secs(backoff);
+            assert!(breaker.check_at(now).is_ok());
+            // Still down, not newly down
+            assert!(!breaker.failure_at(now));
+        }
+        assert_eq!(breaker.status_at(now).consecutive_failures, 6);
+    }
+}
@@ -0,0 +1,110 @@
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType,
+};
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+const CA_NAME: &str = "hevn house CA";
+
+#[derive(Debug, StructOpt)]
+pub enum CertCommand {
+    /// Makes the house CA, ca.pem and ca-key.pem
+    Ca {
+        #[structopt(short = "d", long = "dir", default_value = "certs")]
+        dir: PathBuf,
+    },
+    /// Makes a certificate signed by the house CA, <name>.pem and <name>-key.pem
+    Device {
+        /// Name of the device, the aggregator or a collector
+        name: String,
+        /// Host names and addresses the device is reached on
+        hosts: Vec<String>,
+        #[structopt(short = "d", long = "dir", default_value = "certs")]
+        dir: PathBuf,
+    },
+}
+
+pub fn run(command: CertCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        CertCommand::Ca { dir } => {
+            let (cert, key) = (dir.join("ca.pem"), dir.join("ca-key.pem"));
+            if cert.exists() || key.exists() {
+                return Err(format!("{} already has a CA", dir.display()).into());
+            }
+            let ca = Certificate::from_params(ca_params(None))?;
+            fs::create_dir_all(&dir)?;
+            write(&cert, &ca.serialize_pem()?, 0o644)?;
+            write(&key, &ca.serialize_private_key_pem(), 0o600)?;
+            println!("Wrote {} and {}", cert.display(), key.display());
+        }
+        CertCommand::Device { name, hosts, dir } => {
+            let ca = load_ca(&dir)?;
+            let device = Certificate::from_params(device_params(&name, &hosts))?;
+            let (cert, key) = (
+                dir.join(format!("{}.pem", name)),
+                dir.join(format!("{}-key.pem", name)),
+            );
+            write(&cert, &device.serialize_pem_with_signer(&ca)?, 0o644)?;
+            write(&key, &device.serialize_private_key_pem(), 0o600)?;
+            println!("Wrote {} and {}", cert.display(), key.display());
+        }
+    }
+    Ok(())
+}
+
+fn ca_params(key_pair: Option<KeyPair>) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, CA_NAME);
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.key_pair = key_pair;
+    params
+}
+
+/// The CA in `dir` again, only its name and key are needed to sign with it
+fn load_ca(dir: &Path) -> Result<Certificate, Box<dyn std::error::Error>> {
+    let path = dir.join("ca-key.pem");
+    let key = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Certificate::from_params(ca_params(Some(
+        KeyPair::from_pem(&key)?,
+    )))?)
+}
+
+/// Used both for serving and as a client, so it works for either end of the connection
+fn device_params(name: &str, hosts: &[String]) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, name);
+    params.distinguished_name = dn;
+    params.subject_alt_names = hosts
+        .iter()
+        .map(|host| match host.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(host.clone()),
+        })
+        .collect();
+    params.extended_key_usages = vec![
+        ExtendedKeyUsagePurpose::ServerAuth,
+        ExtendedKeyUsagePurpose::ClientAuth,
+    ];
+    params
+}
+
+/// Writes the file, keys are only readable by the owner
+fn write(path: &Path, pem: &str, mode: u32) -> Result<(), Box<dyn std::error::Error>> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
@@ -0,0 +1,1843 @@
+use crate::schedule::{Block, Override};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use simplelog::LevelFilter;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use toml::Spanned;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, TableLike};
+use util::openapi::{integer, object, reference, string, string_enum, Schema};
+
+/// Kinds of heaters the aggregator can control
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    ShellyS1,
+}
+
+/// What a token allows, `admin` allows everything and `control` allows `read`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Reading the devices, thermostats and stored readings
+    Read,
+    /// Switching heaters and changing thermostats
+    Control,
+    /// Pushing readings to `/ingest`
+    Ingest,
+    /// Managing the devices
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Control => write!(f, "control"),
+            Scope::Ingest => write!(f, "ingest"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// A token that can be used with the API, only its hash is kept
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenConfig {
+    pub name: String,
+    pub scope: Scope,
+    /// Sha256 of the token, in hex
+    pub hash: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CollectorConfig {
+    /// Used in the urls, defaults to the room in lowercase
+    #[serde(default)]
+    pub id: String,
+    pub room: String,
+    pub url: String,
+    /// Which sensor to use when the collector has several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<String>,
+}
+
+/// A field of a device that is not right
+#[derive(Debug, PartialEq, Eq)]
+pub struct Invalid {
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+fn invalid(field: &'static str, message: &'static str) -> Result<(), Invalid> {
+    Err(Invalid { field, message })
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl CollectorConfig {
+    /// Fills in the defaults and checks the fields
+    pub fn validate(&mut self) -> Result<(), Invalid> {
+        if self.room.is_empty() {
+            return invalid("room", "must not be empty");
+        }
+        if self.id.is_empty() {
+            self.id = self.room.to_lowercase();
+        }
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return invalid("url", "must start with http:// or https://");
+        }
+        self.url = self.url.trim_end_matches('/').to_string();
+        Ok(())
+    }
+
+    /// Url all the endpoints of the sensor are found under
+    pub fn sensor_url(&self) -> String {
+        match &self.sensor {
+            Some(sensor) => format!("{}/sensor/{}", self.url, sensor),
+            None => self.url.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HeaterConfig {
+    /// Used in the urls, defaults to the room in lowercase
+    #[serde(default)]
+    pub id: String,
+    pub room: String,
+    #[serde(rename = "type", default = "default_device_type")]
+    pub device_type: DeviceType,
+    /// Host name or ip of the device
+    pub address: String,
+    #[serde(default)]
+    pub channel: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl HeaterConfig {
+    /// Fills in the defaults and checks the fields
+    pub fn validate(&mut self) -> Result<(), Invalid> {
+        if self.room.is_empty() {
+            return invalid("room", "must not be empty");
+        }
+        if self.id.is_empty() {
+            self.id = self.room.to_lowercase();
+        }
+        if self.address.is_empty() {
+            return invalid("address", "must not be empty");
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return invalid("password", "given without a username");
+        }
+        Ok(())
+    }
+}
+
+impl Schema for CollectorConfig {
+    const NAME: &'static str = "CollectorConfig";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[("room", string()), ("url", string())],
+            &[("id", string()), ("sensor", string())],
+        )
+    }
+}
+
+impl Schema for DeviceType {
+    const NAME: &'static str = "DeviceType";
+
+    fn schema() -> serde_json::Value {
+        string_enum(&["shelly_s1"])
+    }
+}
+
+impl Schema for HeaterConfig {
+    const NAME: &'static str = "HeaterConfig";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[("room", string()), ("address", string())],
+            &[
+                ("id", string()),
+                ("type", reference::<DeviceType>()),
+                ("channel", integer()),
+                ("username", string()),
+                ("password", string()),
+            ],
+        )
+    }
+}
+
+/// What the thermostat does with the heater
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Follows the override, the schedule or the setpoint
+    Auto,
+    /// Holds the away setpoint
+    Away,
+    /// Holds the frost protection setpoint
+    FrostProtect,
+    /// Keeps the heater off
+    Off,
+}
+
+impl Schema for Mode {
+    const NAME: &'static str = "Mode";
+
+    fn schema() -> serde_json::Value {
+        string_enum(&["auto", "away", "frost_protect", "off"])
+    }
+}
+
+/// Setpoints of the named modes, in °C
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModesConfig {
+    pub away: f32,
+    pub frost_protect: f32,
+}
+
+impl Default for ModesConfig {
+    fn default() -> Self {
+        Self {
+            away: 16.0,
+            frost_protect: 7.0,
+        }
+    }
+}
+
+/// Keeps a room at a temperature with a collector and a heater
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ThermostatConfig {
+    pub room: String,
+    /// Id of the collector measuring the room
+    pub collector: String,
+    /// Id of the heater warming the room
+    pub heater: String,
+    /// In °C
+    pub setpoint: f32,
+    /// How far from the setpoint the temperature may go before the heater is switched, in °C
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f32,
+    /// Seconds the heater must stay on before it is turned off
+    #[serde(default = "default_min_switch_time")]
+    pub min_on: u64,
+    /// Seconds the heater must stay off before it is turned on
+    #[serde(default = "default_min_switch_time")]
+    pub min_off: u64,
+    /// Seconds between each check
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_mode")]
+    pub mode: Mode,
+    /// Id of a collector measuring the temperature outside
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outside: Option<String>,
+    /// Temperature the room cools towards when there is no outside collector, in °C
+    #[serde(default = "default_baseline")]
+    pub baseline: f32,
+    /// Most seconds to start heating before a block, 0 turns pre-heating off
+    #[serde(default = "default_max_preheat")]
+    pub max_preheat: u64,
+    /// Temporary setpoint that wins over the schedule
+    #[serde(rename = "override", default, skip_serializing_if = "Option::is_none")]
+    pub temporary: Option<Override>,
+    /// Setpoints by weekday and time, `setpoint` is used when it is empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<Block>,
+}
+
+fn default_hysteresis() -> f32 {
+    0.5
+}
+
+fn default_min_switch_time() -> u64 {
+    300
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_baseline() -> f32 {
+    10.0
+}
+
+fn default_max_preheat() -> u64 {
+    3 * 60 * 60
+}
+
+fn default_mode() -> Mode {
+    Mode::Auto
+}
+
+fn is_setpoint(setpoint: f32) -> bool {
+    (0.0..=35.0).contains(&setpoint)
+}
+
+impl ThermostatConfig {
+    /// Checks the fields, the collector and heater must be among the given ones
+    pub fn validate(
+        &self,
+        collectors: &[CollectorConfig],
+        heaters: &[HeaterConfig],
+    ) -> Result<(), Invalid> {
+        if self.room.is_empty() {
+            return invalid("room", "must not be empty");
+        }
+        if !collectors.iter().any(|c| c.id == self.collector) {
+            return invalid("collector", "no collector with this id");
+        }
+        if !heaters.iter().any(|h| h.id == self.heater) {
+            return invalid("heater", "no heater with this id");
+        }
+        if let Some(outside) = &self.outside {
+            if !collectors.iter().any(|c| &c.id == outside) {
+                return invalid("outside", "no collector with this id");
+            }
+        }
+        self.check()
+    }
+
+    /// Checks the fields that can change while running
+    pub fn check(&self) -> Result<(), Invalid> {
+        if !is_setpoint(self.setpoint) {
+            return invalid("setpoint", "must be between 0 and 35 °C");
+        }
+        if self.schedule.iter().any(|b| !is_setpoint(b.setpoint)) {
+            return invalid("schedule", "setpoints must be between 0 and 35 °C");
+        }
+        if self.schedule.iter().any(|b| b.days.is_empty()) {
+            return invalid("schedule", "every block needs at least one day");
+        }
+        if let Some(o) = &self.temporary {
+            if !is_setpoint(o.setpoint) {
+                return invalid("override", "must be between 0 and 35 °C");
+            }
+        }
+        if !(0.0..=5.0).contains(&self.hysteresis) {
+            return invalid("hysteresis", "must be between 0 and 5 °C");
+        }
+        if self.interval == 0 {
+            return invalid("interval", "must be more than 0");
+        }
+        Ok(())
+    }
+}
+
+/// A length of time in seconds, written as 90, "90s", "30m", "2h" or "1d"
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Seconds(pub u64);
+
+impl std::str::FromStr for Seconds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+        let unit = match unit.trim() {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(format!("{} is not a time like 90s, 30m, 2h or 1d", s)),
+        };
+        number
+            .parse::<u64>()
+            .map(|n| Seconds(n * unit))
+            .map_err(|_| format!("{} is not a time like 90s, 30m, 2h or 1d", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for Seconds {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Number(u64),
+            Text(String),
+        }
+        match Written::deserialize(deserializer)? {
+            Written::Number(n) => Ok(Seconds(n)),
+            Written::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// What an alert watches for, set by exactly one of the fields of an `[[alert]]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// In °C
+    TemperatureBelow(f32),
+    TemperatureAbove(f32),
+    /// In %
+    HumidityBelow(f32),
+    HumidityAbove(f32),
+    /// No reading got through from the collector for this long
+    Stale(Seconds),
+}
+
+/// A condition on the rooms that raises an alert when it holds for long enough
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub name: String,
+    /// Rooms watched, every room with a collector when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_below: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_above: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity_below: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity_above: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale: Option<Seconds>,
+    /// How long the condition must hold before the alert fires
+    #[serde(rename = "for", default)]
+    pub duration: Seconds,
+}
+
+impl AlertConfig {
+    /// The condition of a checked alert
+    pub fn condition(&self) -> Condition {
+        match self.conditions().as_slice() {
+            [condition] => *condition,
+            _ => panic!("alert {} must have exactly one condition", self.name),
+        }
+    }
+
+    fn conditions(&self) -> Vec<Condition> {
+        [
+            self.temperature_below.map(Condition::TemperatureBelow),
+            self.temperature_above.map(Condition::TemperatureAbove),
+            self.humidity_below.map(Condition::HumidityBelow),
+            self.humidity_above.map(Condition::HumidityAbove),
+            self.stale.map(Condition::Stale),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Whether the rule watches the room
+    pub fn is_watching(&self, room: &str) -> bool {
+        self.rooms.is_empty() || self.rooms.iter().any(|r| r.eq_ignore_ascii_case(room))
+    }
+
+    /// Checks the fields, the rooms must be among the rooms of the collectors
+    pub fn validate(&self, collectors: &[CollectorConfig]) -> Result<(), Invalid> {
+        if self.name.is_empty() {
+            return invalid("name", "must not be empty");
+        }
+        if self.conditions().len() != 1 {
+            return invalid(
+                "name",
+                "needs exactly one of temperature_below, temperature_above, humidity_below, humidity_above or stale",
+            );
+        }
+        if self.stale == Some(Seconds(0)) {
+            return invalid("stale", "must be more than 0");
+        }
+        let is_room = |room: &String| collectors.iter().any(|c| c.room.eq_ignore_ascii_case(room));
+        if !self.rooms.iter().all(is_room) {
+            return invalid("rooms", "has a room without a collector");
+        }
+        Ok(())
+    }
+}
+
+/// Things the aggregator can send notifications about
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    HeaterSwitched,
+    AlertFiring,
+    AlertResolved,
+    CollectorOffline,
+    CollectorOnline,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventKind::HeaterSwitched => write!(f, "heater_switched"),
+            EventKind::AlertFiring => write!(f, "alert_firing"),
+            EventKind::AlertResolved => write!(f, "alert_resolved"),
+            EventKind::CollectorOffline => write!(f, "collector_offline"),
+            EventKind::CollectorOnline => write!(f, "collector_online"),
+        }
+    }
+}
+
+/// A JSON POST to any url
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Extra headers, like `Authorization`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// JSON to send, strings in it can hold `{{event}}`, `{{room}}`, `{{device}}`, `{{message}}`
+    /// and `{{timestamp}}`. The notification itself is sent when it is left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+}
+
+/// How the connection to the mail server is secured
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain text, only for mail servers on the same machine or for testing
+    None,
+    Starttls,
+    Tls,
+}
+
+/// Mail sent through an SMTP server
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub server: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default = "default_smtp_security")]
+    pub security: SmtpSecurity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Can hold the same fields as a webhook body
+    #[serde(default = "default_subject")]
+    pub subject: String,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_smtp_security() -> SmtpSecurity {
+    SmtpSecurity::Starttls
+}
+
+fn default_subject() -> String {
+    "hevn: {{message}}".to_string()
+}
+
+/// Where notifications are sent, set by one of `webhook` or `email`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub name: String,
+    /// Events sent here, every event when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<EventKind>,
+    /// Least time between two notifications of the same event and device, 0 sends them all
+    #[serde(default)]
+    pub rate_limit: Seconds,
+    /// Tries after the first one fails
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    #[serde(default = "default_backoff")]
+    pub backoff: Seconds,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<EmailConfig>,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_backoff() -> Seconds {
+    Seconds(5)
+}
+
+impl NotifyConfig {
+    /// Whether the event is sent here
+    pub fn is_routed(&self, event: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+
+    pub fn validate(&self) -> Result<(), Invalid> {
+        if self.name.is_empty() {
+            return invalid("name", "must not be empty");
+        }
+        match (&self.webhook, &self.email) {
+            (Some(webhook), None) => {
+                if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                    return invalid("webhook.url", "must start with http:// or https://");
+                }
+            }
+            (None, Some(email)) => {
+                if email.server.is_empty() {
+                    return invalid("email.server", "must not be empty");
+                }
+                if email.from.parse::<lettre::message::Mailbox>().is_err() {
+                    return invalid("email.from", "not a mail address");
+                }
+                if email.to.is_empty() {
+                    return invalid("email.to", "needs at least one address");
+                }
+                if email
+                    .to
+                    .iter()
+                    .any(|to| to.parse::<lettre::message::Mailbox>().is_err())
+                {
+                    return invalid("email.to", "has something that is not a mail address");
+                }
+                if email.username.is_some() != email.password.is_some() {
+                    return invalid("email.password", "username and password go together");
+                }
+            }
+            _ => return invalid("name", "needs exactly one of webhook or email"),
+        }
+        Ok(())
+    }
+}
+
+/// Ways of storing the readings
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Sqlite,
+}
+
+/// Where and how long readings are kept
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub path: String,
+    /// Seconds between each time the devices are read
+    pub interval: u64,
+    /// Days to keep readings, 0 keeps them forever
+    pub retention: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Sqlite,
+            path: "hevn.db".to_string(),
+            interval: 60,
+            retention: 30,
+        }
+    }
+}
+
+/// Certificate and key for serving HTTPS
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain
+    pub cert: String,
+    /// PEM file with the private key
+    pub key: String,
+    /// Address to answer plain HTTP on with a redirect to HTTPS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<SocketAddr>,
+}
+
+/// Certificates for talking to collectors that require mTLS
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CollectorTlsConfig {
+    /// PEM file with the house CA the collectors' certificates are signed by
+    pub ca: String,
+    /// PEM file with the certificate presented to the collectors
+    pub cert: String,
+    /// PEM file with its private key
+    pub key: String,
+}
+
+/// When to stop contacting devices that do not answer
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Failures in a row before a device is taken as down
+    pub failures: u32,
+    /// Seconds to wait before trying a device that is down again, doubled every time it is still down
+    pub backoff: u64,
+    /// Most seconds to wait
+    pub max_backoff: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: 3,
+            backoff: 10,
+            max_backoff: 300,
+        }
+    }
+}
+
+/// Configuration of the aggregator
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// Seconds to wait for the devices
+    pub timeout: u64,
+    /// Seconds a reading from a collector is given out again before it is read anew
+    pub cache_max_age: u64,
+    /// Seconds between each read of the devices while someone follows `/live`
+    pub live_interval: u64,
+    /// Seconds between each check of the alerts
+    pub alert_interval: u64,
+    pub log_file: String,
+    pub log_level: LevelFilter,
+    /// Scope of requests without a token, they are turned away when it is not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anonymous: Option<Scope>,
+    /// File the learned thermal models of the rooms are kept in
+    pub model_file: String,
+    /// Time zone the schedules are in
+    pub timezone: Tz,
+    pub modes: ModesConfig,
+    pub breaker: BreakerConfig,
+    /// HTTPS is served on `bind` when this is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Client certificate used for https:// collectors when this is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collector_tls: Option<CollectorTlsConfig>,
+    /// Readings are only stored when this is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageConfig>,
+    #[serde(rename = "collector", skip_serializing_if = "Vec::is_empty")]
+    pub collectors: Vec<CollectorConfig>,
+    #[serde(rename = "heater", skip_serializing_if = "Vec::is_empty")]
+    pub heaters: Vec<HeaterConfig>,
+    #[serde(rename = "thermostat", skip_serializing_if = "Vec::is_empty")]
+    pub thermostats: Vec<ThermostatConfig>,
+    #[serde(rename = "token", skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<TokenConfig>,
+    #[serde(rename = "alert", skip_serializing_if = "Vec::is_empty")]
+    pub alerts: Vec<AlertConfig>,
+    #[serde(rename = "notify", skip_serializing_if = "Vec::is_empty")]
+    pub notify: Vec<NotifyConfig>,
+}
+
+/// Something wrong in the config file
+#[derive(Debug)]
+pub struct ConfigError {
+    line: Option<usize>,
+    field: String,
+    message: String,
+}
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The errors from the toml parser already tell where they are
+        match self.line {
+            _ if self.field.is_empty() => write!(f, "{}", self.message),
+            Some(line) => write!(f, "line {}, {}: {}", line, self.field, self.message),
+            None => write!(f, "{}: {}", self.field, self.message),
+        }
+    }
+}
+
+/// The config as written, with the positions kept around for error messages
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    bind: Option<Spanned<String>>,
+    timeout: Option<Spanned<u64>>,
+    #[serde(default = "default_cache_max_age")]
+    cache_max_age: u64,
+    live_interval: Option<Spanned<u64>>,
+    alert_interval: Option<Spanned<u64>>,
+    #[serde(default = "default_log_file")]
+    log_file: String,
+    log_level: Option<Spanned<String>>,
+    anonymous: Option<Scope>,
+    timezone: Option<Spanned<String>>,
+    modes: Option<Spanned<ModesConfig>>,
+    breaker: Option<Spanned<BreakerConfig>>,
+    tls: Option<TlsConfig>,
+    collector_tls: Option<CollectorTlsConfig>,
+    storage: Option<Spanned<StorageConfig>>,
+    #[serde(default = "default_model_file")]
+    model_file: String,
+    #[serde(default)]
+    collector: Vec<RawCollector>,
+    #[serde(default)]
+    heater: Vec<RawHeater>,
+    #[serde(default)]
+    thermostat: Vec<RawThermostat>,
+    #[serde(default)]
+    token: Vec<RawToken>,
+    #[serde(default)]
+    alert: Vec<RawAlert>,
+    #[serde(default)]
+    notify: Vec<RawNotify>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawToken {
+    name: Spanned<String>,
+    scope: Scope,
+    hash: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCollector {
+    id: Option<Spanned<String>>,
+    room: Spanned<String>,
+    url: Spanned<String>,
+    sensor: Option<String>,
+}
+
+impl RawCollector {
+    fn span(&self, field: &str) -> (usize, usize) {
+        match field {
+            "id" => self.id.as_ref().map_or(self.room.span(), |id| id.span()),
+            "url" => self.url.span(),
+            _ => self.room.span(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHeater {
+    id: Option<Spanned<String>>,
+    room: Spanned<String>,
+    #[serde(rename = "type", default = "default_device_type")]
+    device_type: DeviceType,
+    address: Spanned<String>,
+    #[serde(default)]
+    channel: usize,
+    username: Option<String>,
+    password: Option<Spanned<String>>,
+}
+
+impl RawHeater {
+    fn span(&self, field: &str) -> (usize, usize) {
+        match field {
+            "id" => self.id.as_ref().map_or(self.room.span(), |id| id.span()),
+            "address" => self.address.span(),
+            "password" => self
+                .password
+                .as_ref()
+                .map_or(self.room.span(), |p| p.span()),
+            _ => self.room.span(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawThermostat {
+    room: Spanned<String>,
+    collector: Spanned<String>,
+    heater: Spanned<String>,
+    setpoint: Spanned<f32>,
+    hysteresis: Option<Spanned<f32>>,
+    #[serde(default = "default_min_switch_time")]
+    min_on: u64,
+    #[serde(default = "default_min_switch_time")]
+    min_off: u64,
+    interval: Option<Spanned<u64>>,
+    #[serde(default = "default_mode")]
+    mode: Mode,
+    outside: Option<Spanned<String>>,
+    #[serde(default = "default_baseline")]
+    baseline: f32,
+    #[serde(default = "default_max_preheat")]
+    max_preheat: u64,
+    #[serde(rename = "override")]
+    temporary: Option<Override>,
+    schedule: Option<Spanned<Vec<Block>>>,
+}
+
+impl RawThermostat {
+    fn span(&self, field: &str) -> (usize, usize) {
+        match field {
+            "collector" => self.collector.span(),
+            "heater" => self.heater.span(),
+            "outside" => self.outside.as_ref().map_or(self.room.span(), |o| o.span()),
+            "setpoint" => self.setpoint.span(),
+            "hysteresis" => self
+                .hysteresis
+                .as_ref()
+                .map_or(self.room.span(), |h| h.span()),
+            "interval" => self
+                .interval
+                .as_ref()
+                .map_or(self.room.span(), |i| i.span()),
+            "schedule" => self
+                .schedule
+                .as_ref()
+                .map_or(self.room.span(), |s| s.span()),
+            _ => self.room.span(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAlert {
+    name: Spanned<String>,
+    rooms: Option<Spanned<Vec<String>>>,
+    temperature_below: Option<f32>,
+    temperature_above: Option<f32>,
+    humidity_below: Option<f32>,
+    humidity_above: Option<f32>,
+    stale: Option<Spanned<Seconds>>,
+    #[serde(rename = "for", default)]
+    duration: Seconds,
+}
+
+impl RawAlert {
+    fn span(&self, field: &str) -> (usize, usize) {
+        match (field, &self.rooms, &self.stale) {
+            ("rooms", Some(rooms), _) => rooms.span(),
+            ("stale", _, Some(stale)) => stale.span(),
+            _ => self.name.span(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawNotify {
+    name: Spanned<String>,
+    #[serde(default)]
+    events: Vec<EventKind>,
+    #[serde(default)]
+    rate_limit: Seconds,
+    #[serde(default = "default_retries")]
+    retries: u32,
+    #[serde(default = "default_backoff")]
+    backoff: Seconds,
+    webhook: Option<Spanned<WebhookConfig>>,
+    email: Option<Spanned<EmailConfig>>,
+}
+
+impl RawNotify {
+    fn span(&self, field: &str) -> (usize, usize) {
+        match (field, &self.webhook, &self.email) {
+            ("webhook.url", Some(webhook), _) => webhook.span(),
+            (field, _, Some(email)) if field.starts_with("email.") => email.span(),
+            _ => self.name.span(),
+        }
+    }
+}
+
+fn default_model_file() -> String {
+    "thermal_models.json".to_string()
+}
+
+fn default_cache_max_age() -> u64 {
+    5
+}
+
+fn default_log_file() -> String {
+    "aggregator.log".to_string()
+}
+
+fn default_device_type() -> DeviceType {
+    DeviceType::ShellyS1
+}
+
+/// Applies the changes from `old` to `new` to a table of the file,
+/// `written` is the new table as it is written from scratch
+fn update_table(
+    file: &mut dyn TableLike,
+    written: &dyn TableLike,
+    old: &toml::value::Table,
+    new: &toml::value::Table,
+) {
+    for (key, value) in new {
+        let before = old.get(key);
+        let written = match written.get(key) {
+            Some(written) if before != Some(value) => written,
+            _ => continue,
+        };
+        match (file.get_mut(key), before) {
+            (Some(item), Some(before)) => update_item(item, written, before, value),
+            // Left out of the file, so it had the default value
+            _ => {
+                let mut item = written.clone();
+                place(&mut item, usize::MAX);
+                file.insert(key, item);
+            }
+        }
+    }
+    for key in old.keys().filter(|k| !new.contains_key(*k)) {
+        file.remove(key);
+    }
+}
+
+fn update_item(item: &mut Item, written: &Item, old: &toml::Value, new: &toml::Value) {
+    match (old, new) {
+        (toml::Value::Table(old), toml::Value::Table(new))
+            if item.is_table_like() && written.is_table_like() =>
+        {
+            update_table(
+                item.as_table_like_mut().unwrap(),
+                written.as_table_like().unwrap(),
+                old,
+                new,
+            )
+        }
+        (toml::Value::Array(old), toml::Value::Array(new))
+            if item.is_array_of_tables() && written.is_array_of_tables() =>
+        {
+            update_tables(
+                item.as_array_of_tables_mut().unwrap(),
+                written.as_array_of_tables().unwrap(),
+                old,
+                new,
+            )
+        }
+        _ => {
+            let mut value = written.clone();
+            // Written the way the file had it, with its comments
+            if let Some(before) = item.as_value() {
+                if let Ok(mut after) = value.clone().into_value() {
+                    *after.decor_mut() = before.decor().clone();
+                    value = Item::Value(after);
+                }
+            }
+            place(&mut value, last_position(item).unwrap_or(usize::MAX));
+            *item = value;
+        }
+    }
+}
+
+/// Keeps the tables of the file that are still there,
+/// they are told apart by their id, name or room
+fn update_tables(
+    file: &mut ArrayOfTables,
+    written: &ArrayOfTables,
+    old: &[toml::Value],
+    new: &[toml::Value],
+) {
+    let same = |a: &toml::Value, b: &toml::Value| {
+        ["id", "name", "room"]
+            .iter()
+            .find_map(|k| Some(a.get(k)? == b.get(k)?))
+            .unwrap_or(false)
+    };
+    // New tables go right after the one before them
+    let mut position = file
+        .iter()
+        .filter_map(Table::position)
+        .min()
+        .unwrap_or(usize::MAX);
+    let mut tables = ArrayOfTables::new();
+    for (value, written) in new.iter().zip(written.iter()) {
+        let kept = old
+            .iter()
+            .position(|o| same(o, value))
+            .and_then(|i| Some((&old[i], file.get(i)?.clone())));
+        let table = match (kept, value) {
+            (Some((toml::Value::Table(before), mut table)), toml::Value::Table(after)) => {
+                update_table(&mut table, written, before, after);
+                table
+            }
+            _ => {
+                let mut item = Item::Table(written.clone());
+                place(&mut item, position);
+                match item {
+                    Item::Table(table) => table,
+                    _ => continue,
+                }
+            }
+        };
+        position = last_position(&Item::Table(table.clone())).unwrap_or(position);
+        tables.push(table);
+    }
+    *file = tables;
+}
+
+/// Where the item and the tables in it are written in the file
+fn place(item: &mut Item, position: usize) {
+    let place_table = |table: &mut Table| {
+        table.set_position(position);
+        for (_, child) in table.iter_mut() {
+            place(child, position);
+        }
+    };
+    match item {
+        Item::Table(table) => place_table(table),
+        Item::ArrayOfTables(tables) => tables.iter_mut().for_each(place_table),
+        _ => (),
+    }
+}
+
+/// Position of the last table in the item
+fn last_position(item: &Item) -> Option<usize> {
+    let last_in = |table: &Table| {
+        table
+            .iter()
+            .filter_map(|(_, child)| last_position(child))
+            .chain(table.position())
+            .max()
+    };
+    match item {
+        Item::Table(table) => last_in(table),
+        Item::ArrayOfTables(tables) => tables.iter().filter_map(last_in).max(),
+        _ => None,
+    }
+}
+
+impl Config {
+    /// Writes the config to a new file and moves it in place,
+    /// so a crash never leaves half a config behind.
+    /// Only the settings that changed since the file was read are written,
+    /// everything else in the file, comments included, is kept as it is
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let text = toml::to_string(self)?;
+        let current = match std::fs::read_to_string(path) {
+            Ok(current) => Some(current),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let text = match current
+            .as_deref()
+            .map(|c| (c.parse::<DocumentMut>(), Self::parse(c)))
+        {
+            Some((Ok(mut document), Ok(old))) => {
+                let written = text.parse::<DocumentMut>()?;
+                let old = toml::Value::try_from(&old)?;
+                let new = toml::Value::try_from(self)?;
+                if let (Some(old), Some(new)) = (old.as_table(), new.as_table()) {
+                    update_table(document.as_table_mut(), written.as_table(), old, new);
+                }
+                document.to_string()
+            }
+            // Nothing worth keeping
+            _ => text,
+        };
+        let tmp = path.with_extension("toml.tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let file = std::fs::read_to_string(path).map_err(|e| ConfigError {
+            line: None,
+            field: String::new(),
+            message: format!("Could not read {}, {}", path.display(), e),
+        })?;
+        Self::parse(&file)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let raw: RawConfig = toml::from_str(text).map_err(|e| ConfigError {
+            line: e.line_col().map(|(line, _)| line + 1),
+            field: String::new(),
+            message: e.to_string(),
+        })?;
+
+        let error = |field: String, span: (usize, usize), message: &str| ConfigError {
+            line: Some(text[..span.0].matches('\n').count() + 1),
+            field,
+            message: message.to_string(),
+        };
+
+        let bind = match raw.bind {
+            Some(bind) => bind
+                .get_ref()
+                .parse()
+                .map_err(|_| error("bind".to_string(), bind.span(), "not an ip and port"))?,
+            None => SocketAddr::from(([0, 0, 0, 0], 65535)),
+        };
+
+        let timeout = match raw.timeout {
+            Some(timeout) if *timeout.get_ref() == 0 => {
+                return Err(error(
+                    "timeout".to_string(),
+                    timeout.span(),
+                    "must be more than 0",
+                ))
+            }
+            Some(timeout) => timeout.into_inner(),
+            None => 5,
+        };
+        let live_interval = match raw.live_interval {
+            Some(interval) if *interval.get_ref() == 0 => {
+                return Err(error(
+                    "live_interval".to_string(),
+                    interval.span(),
+                    "must be more than 0",
+                ))
+            }
+            Some(interval) => interval.into_inner(),
+            None => 5,
+        };
+        let alert_interval = match raw.alert_interval {
+            Some(interval) if *interval.get_ref() == 0 => {
+                return Err(error(
+                    "alert_interval".to_string(),
+                    interval.span(),
+                    "must be more than 0",
+                ))
+            }
+            Some(interval) => interval.into_inner(),
+            None => 60,
+        };
+
+        let log_level = match raw.log_level {
+            Some(log_level) => log_level.get_ref().parse().map_err(|_| {
+                error(
+                    "log_level".to_string(),
+                    log_level.span(),
+                    "must be one of off, error, warn, info, debug or trace",
+                )
+            })?,
+            None => LevelFilter::Info,
+        };
+
+        let timezone = match raw.timezone {
+            Some(timezone) => timezone.get_ref().parse().map_err(|_| {
+                error(
+                    "timezone".to_string(),
+                    timezone.span(),
+                    "not a time zone like Europe/Oslo",
+                )
+            })?,
+            None => Tz::UTC,
+        };
+
+        let modes = match raw.modes {
+            Some(modes) if !is_setpoint(modes.get_ref().away) => {
+                return Err(error(
+                    "modes.away".to_string(),
+                    modes.span(),
+                    "must be between 0 and 35 °C",
+                ))
+            }
+            Some(modes) if !is_setpoint(modes.get_ref().frost_protect) => {
+                return Err(error(
+                    "modes.frost_protect".to_string(),
+                    modes.span(),
+                    "must be between 0 and 35 °C",
+                ))
+            }
+            Some(modes) => modes.into_inner(),
+            None => ModesConfig::default(),
+        };
+
+        let breaker = match raw.breaker {
+            Some(breaker) if breaker.get_ref().failures == 0 => {
+                return Err(error(
+                    "breaker.failures".to_string(),
+                    breaker.span(),
+                    "must be more than 0",
+                ))
+            }
+            Some(breaker) if breaker.get_ref().backoff == 0 => {
+                return Err(error(
+                    "breaker.backoff".to_string(),
+                    breaker.span(),
+                    "must be more than 0",
+                ))
+            }
+            Some(breaker) if breaker.get_ref().max_backoff < breaker.get_ref().backoff => {
+                return Err(error(
+                    "breaker.max_backoff".to_string(),
+                    breaker.span(),
+                    "must not be less than backoff",
+                ))
+            }
+            Some(breaker) => breaker.into_inner(),
+            None => BreakerConfig::default(),
+        };
+
+        let storage = match raw.storage {
+            Some(storage) if storage.get_ref().interval == 0 => {
+                return Err(error(
+                    "storage.interval".to_string(),
+                    storage.span(),
+                    "must be more than 0",
+                ))
+            }
+            Some(storage) => Some(storage.into_inner()),
+            None => None,
+        };
+
+        let mut collectors: Vec<CollectorConfig> = Vec::new();
+        for (i, raw) in raw.collector.into_iter().enumerate() {
+            let mut c = CollectorConfig {
+                id: raw
+                    .id
+                    .as_ref()
+                    .map(|id| id.get_ref().clone())
+                    .unwrap_or_default(),
+                room: raw.room.get_ref().clone(),
+                url: raw.url.get_ref().clone(),
+                sensor: raw.sensor.clone(),
+            };
+            let checked = c.validate().and_then(|_| {
+                if collectors.iter().any(|other| other.id == c.id) {
+                    invalid("id", "is used by another collector, give one of them an id")
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = checked {
+                return Err(error(
+                    format!("collector[{}].{}", i, e.field),
+                    raw.span(e.field),
+                    e.message,
+                ));
+            }
+            collectors.push(c);
+        }
+
+        let mut heaters: Vec<HeaterConfig> = Vec::new();
+        for (i, raw) in raw.heater.into_iter().enumerate() {
+            let mut h = HeaterConfig {
+                id: raw
+                    .id
+                    .as_ref()
+                    .map(|id| id.get_ref().clone())
+                    .unwrap_or_default(),
+                room: raw.room.get_ref().clone(),
+                device_type: raw.device_type,
+                address: raw.address.get_ref().clone(),
+                channel: raw.channel,
+                username: raw.username.clone(),
+                password: raw.password.as_ref().map(|p| p.get_ref().clone()),
+            };
+            let checked = h.validate().and_then(|_| {
+                if heaters.iter().any(|other| other.id == h.id) {
+                    invalid("id", "is used by another heater, give one of them an id")
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = checked {
+                return Err(error(
+                    format!("heater[{}].{}", i, e.field),
+                    raw.span(e.field),
+                    e.message,
+                ));
+            }
+            heaters.push(h);
+        }
+
+        let mut thermostats: Vec<ThermostatConfig> = Vec::new();
+        for (i, raw) in raw.thermostat.into_iter().enumerate() {
+            let t = ThermostatConfig {
+                room: raw.room.get_ref().clone(),
+                collector: raw.collector.get_ref().clone(),
+                heater: raw.heater.get_ref().clone(),
+                setpoint: *raw.setpoint.get_ref(),
+                hysteresis: raw
+                    .hysteresis
+                    .as_ref()
+                    .map_or(default_hysteresis(), |h| *h.get_ref()),
+                min_on: raw.min_on,
+                min_off: raw.min_off,
+                interval: raw
+                    .interval
+                    .as_ref()
+                    .map_or(default_interval(), |i| *i.get_ref()),
+                mode: raw.mode,
+                outside: raw.outside.as_ref().map(|o| o.get_ref().clone()),
+                baseline: raw.baseline,
+                max_preheat: raw.max_preheat,
+                temporary: raw.temporary.clone(),
+                schedule: raw
+                    .schedule
+                    .as_ref()
+                    .map(|s| s.get_ref().clone())
+                    .unwrap_or_default(),
+            };
+            let checked = t.validate(&collectors, &heaters).and_then(|_| {
+                if thermostats.iter().any(|other| other.room == t.room) {
+                    invalid("room", "already has a thermostat")
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = checked {
+                return Err(error(
+                    format!("thermostat[{}].{}", i, e.field),
+                    raw.span(e.field),
+                    e.message,
+                ));
+            }
+            thermostats.push(t);
+        }
+
+        let mut tokens: Vec<TokenConfig> = Vec::new();
+        for (i, raw) in raw.token.into_iter().enumerate() {
+            let t = TokenConfig {
+                name: raw.name.get_ref().clone(),
+                scope: raw.scope,
+                hash: raw.hash.get_ref().to_lowercase(),
+            };
+            let checked = if t.name.is_empty() {
+                invalid("name", "must not be empty")
+            } else if tokens.iter().any(|other| other.name == t.name) {
+                invalid("name", "is used by another token")
+            } else if t.hash.len() != 64 || !t.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                invalid("hash", "must be a sha256 in hex")
+            } else {
+                Ok(())
+            };
+            if let Err(e) = checked {
+                let span = match e.field {
+                    "hash" => raw.hash.span(),
+                    _ => raw.name.span(),
+                };
+                return Err(error(format!("token[{}].{}", i, e.field), span, e.message));
+            }
+            tokens.push(t);
+        }
+
+        let mut alerts: Vec<AlertConfig> = Vec::new();
+        for (i, raw) in raw.alert.into_iter().enumerate() {
+            let a = AlertConfig {
+                name: raw.name.get_ref().clone(),
+                rooms: raw
+                    .rooms
+                    .as_ref()
+                    .map(|r| r.get_ref().clone())
+                    .unwrap_or_default(),
+                temperature_below: raw.temperature_below,
+                temperature_above: raw.temperature_above,
+                humidity_below: raw.humidity_below,
+                humidity_above: raw.humidity_above,
+                stale: raw.stale.as_ref().map(|s| *s.get_ref()),
+                duration: raw.duration,
+            };
+            let checked = a.validate(&collectors).and_then(|_| {
+                if alerts.iter().any(|other| other.name == a.name) {
+                    invalid("name", "is used by another alert")
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = checked {
+                return Err(error(
+                    format!("alert[{}].{}", i, e.field),
+                    raw.span(e.field),
+                    e.message,
+                ));
+            }
+            alerts.push(a);
+        }
+
+        let mut notify: Vec<NotifyConfig> = Vec::new();
+        for (i, raw) in raw.notify.into_iter().enumerate() {
+            let n = NotifyConfig {
+                name: raw.name.get_ref().clone(),
+                events: raw.events.clone(),
+                rate_limit: raw.rate_limit,
+                retries: raw.retries,
+                backoff: raw.backoff,
+                webhook: raw.webhook.as_ref().map(|w| w.get_ref().clone()),
+                email: raw.email.as_ref().map(|e| e.get_ref().clone()),
+            };
+            let checked = n.validate().and_then(|_| {
+                if notify.iter().any(|other| other.name == n.name) {
+                    invalid("name", "is used by another notification channel")
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = checked {
+                return Err(error(
+                    format!("notify[{}].{}", i, e.field),
+                    raw.span(e.field),
+                    e.message,
+                ));
+            }
+            notify.push(n);
+        }
+
+        Ok(Self {
+            bind,
+            timeout,
+            cache_max_age: raw.cache_max_age,
+            live_interval,
+            alert_interval,
+            log_file: raw.log_file,
+            log_level,
+            anonymous: raw.anonymous,
+            timezone,
+            modes,
+            breaker,
+            tls: raw.tls,
+            collector_tls: raw.collector_tls,
+            storage,
+            model_file: raw.model_file,
+            collectors,
+            heaters,
+            thermostats,
+            tokens,
+            alerts,
+            notify,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_config() {
+        let config = Config::parse(
+            r#"
+bind = "127.0.0.1:8080"
+
+[[collector]]
+room = "Bedroom"
+url = "http://192.168.0.114:5000/"
+
+[[heater]]
+room = "Bedroom"
+address = "192.168.0.101"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.timeout, 5);
+        assert_eq!(config.cache_max_age, 5);
+        assert_eq!(config.live_interval, 5);
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert_eq!(config.collectors[0].url, "http://192.168.0.114:5000");
+        assert_eq!(config.heaters[0].id, "bedroom");
+        assert_eq!(config.heaters[0].device_type, DeviceType::ShellyS1);
+    }
+
+    #[test]
+    fn is_saved_and_loaded() {
+        let config = Config::parse(
+            r#"
+log_level = "debug"
+timezone = "Europe/Oslo"
+anonymous = "read"
+
+[modes]
+away = 15.0
+
+[storage]
+retention = 7
+
+[tls]
+cert = "cert.pem"
+key = "key.pem"
+redirect = "0.0.0.0:80"
+
+[collector_tls]
+ca = "certs/ca.pem"
+cert = "certs/aggregator.pem"
+key = "certs/aggregator-key.pem"
+
+[[collector]]
+room = "Bedroom"
+url = "http://192.168.0.114:5000"
+sensor = "bed"
+
+[[heater]]
+room = "Bedroom"
+address = "192.168.0.101"
+username = "admin"
+password = "secret"
+
+[[thermostat]]
+room = "Bedroom"
+collector = "bedroom"
+heater = "bedroom"
+setpoint = 20.0
+override = { setpoint = 22.0, until = 1790000000 }
+
+[[thermostat.schedule]]
+days = ["Mon", "Tue"]
+start = "06:30"
+setpoint = 21.0
+
+[[token]]
+name = "lumberjack"
+scope = "control"
+hash = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+
+[[alert]]
+name = "mold"
+rooms = ["Bedroom"]
+humidity_above = 70.0
+for = "2h"
+
+[[notify]]
+name = "phone"
+events = ["alert_firing"]
+rate_limit = "10m"
+[notify.webhook]
+url = "https://ntfy.sh/hevn"
+headers = { Authorization = "Bearer secret" }
+body = { title = "{{event}}", tags = ["house"], priority = 4 }
+
+[[notify]]
+name = "mail"
+[notify.email]
+server = "smtp.example.com"
+username = "hevn"
+password = "secret"
+from = "hevn <hevn@example.com>"
+to = ["me@example.com"]
+"#,
+        )
+        .unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("hevn-aggregator-{}.toml", std::process::id()));
+        config.save(&path).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap(), config);
+        assert_eq!(config.modes.frost_protect, 7.0);
+        assert_eq!(config.thermostats[0].schedule[0].setpoint, 21.0);
+        assert_eq!(config.alerts[0].duration, Seconds(7200));
+        assert_eq!(config.notify[1].email.as_ref().unwrap().port, 587);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn is_notify_checked() {
+        let e = Config::parse(
+            r#"
+[[notify]]
+name = "phone"
+webhook = { url = "ntfy.sh/hevn" }
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.field, "notify[0].webhook.url");
+        assert_eq!(e.line, Some(4));
+
+        let e = Config::parse(
+            r#"
+[[notify]]
+name = "both"
+webhook = { url = "https://ntfy.sh/hevn" }
+
+[notify.email]
+server = "smtp.example.com"
+from = "hevn@example.com"
+to = ["me@example.com"]
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.field, "notify[0].name");
+        assert_eq!(e.line, Some(3));
+
+        let e = Config::parse(
+            r#"
+[[notify]]
+name = "mail"
+
+[notify.email]
+server = "smtp.example.com"
+from = "hevn@example.com"
+to = ["me"]
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.field, "notify[0].email.to");
+    }
+
+    #[test]
+    fn is_alert_checked() {
+        let collector = "[[collector]]\nroom = \"Bedroom\"\nurl = \"http://192.168.0.114:5000\"\n";
+        let config = Config::parse(&format!(
+            "{}\n[[alert]]\nname = \"silent\"\nstale = \"15m\"\n",
+            collector
+        ))
+        .unwrap();
+        assert_eq!(config.alerts[0].condition(), Condition::Stale(Seconds(900)));
+        assert_eq!(config.alerts[0].duration, Seconds(0));
+        assert_eq!(config.alert_interval, 60);
+
+        let e = Config::parse(&format!(
+            "{}\n[[alert]]\nname = \"frost\"\ntemperature_below = 5.0\nstale = 600\n",
+            collector
+        ))
+        .unwrap_err();
+        assert_eq!(e.field, "alert[0].name");
+        assert_eq!(e.line, Some(6));
+
+        let e = Config::parse(&format!(
+            "{}\n[[alert]]\nname = \"frost\"\nrooms = [\"Attic\"]\ntemperature_below = 5.0\n",
+            collector
+        ))
+        .unwrap_err();
+        assert_eq!(e.field, "alert[0].rooms");
+
+        let e = Config::parse(&format!(
+            "{}\n[[alert]]\nname = \"frost\"\ntemperature_below = 5.0\nfor = \"2 weeks\"\n",
+            collector
+        ))
+        .unwrap_err();
+        assert!(e.to_string().contains("30m"), "{}", e);
+    }
+
+    #[test]
+    fn is_thermostat_checked() {
+        let devices = r#"
+[[collector]]
+room = "Bedroom"
+url = "http://192.168.0.114:5000"
+
+[[heater]]
+room = "Bedroom"
+address = "192.168.0.101"
+"#;
+        let config = Config::parse(&format!(
+            "{}\n[[thermostat]]\nroom = \"Bedroom\"\ncollector = \"bedroom\"\nheater = \"bedroom\"\nsetpoint = 20.5\n",
+            devices
+        ))
+        .unwrap();
+        assert_eq!(config.thermostats[0].hysteresis, 0.5);
+        assert_eq!(config.thermostats[0].mode, Mode::Auto);
+
+        let e = Config::parse(&format!(
+            "{}\n[[thermostat]]\nroom = \"Bedroom\"\ncollector = \"bedroom\"\nheater = \"kitchen\"\nsetpoint = 20.5\n",
+            devices
+        ))
+        .unwrap_err();
+        assert_eq!(e.field, "thermostat[0].heater");
+        assert_eq!(e.line, Some(13));
+    }
+
+    #[test]
+    fn is_error_with_line() {
+        let e = Config::parse(
+            r#"
+[[collector]]
+room = "Bedroom"
+url = "192.168.0.114:5000"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.line, Some(4));
+        assert_eq!(e.field, "collector[0].url");
+
+        let e = Config::parse(
+            r#"
+[[heater]]
+room = "Bedroom"
+address = "192.168.0.101"
+
+[[heater]]
+room = "bedroom"
+address = "192.168.0.102"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.line, Some(7));
+        assert_eq!(e.field, "heater[1].id");
+
+        let e = Config::parse(
+            r#"
+[[heater]]
+room = "Bedroom"
+address = 101
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.line, Some(4));
+        assert!(e.to_string().contains("address"), "{}", e);
+
+        let e = Config::parse(
+            r#"
+[breaker]
+backoff = 60
+max_backoff = 30
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.field, "breaker.max_backoff");
+
+        let e = Config::parse(
+            r#"
+[[token]]
+name = "lumberjack"
+scope = "read"
+hash = "secret"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(e.line, Some(5));
+        assert_eq!(e.field, "token[0].hash");
+    }
+
+    #[test]
+    fn is_saved_in_place() {
+        let path = std::env::temp_dir().join(format!("hevn-in-place-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"# The house
+bind = "127.0.0.1:8080" # next to the router
+
+# Upstairs
+[[collector]]
+room = "Bedroom"
+url = "http://192.168.0.114:5000"
+
+[[collector]]
+room = "Kitchen"
+url = "http://192.168.0.115:5000"
+
+[[heater]]
+room = "Bedroom"
+address = "192.168.0.101" # the shelly
+"#,
+        )
+        .unwrap();
+
+        let mut config = Config::from_file(&path).unwrap();
+        config.collectors.remove(1);
+        config.heaters[0].address = "192.168.0.102".to_string();
+        config.heaters.push(HeaterConfig {
+            id: "kitchen".to_string(),
+            room: "Kitchen".to_string(),
+            ..config.heaters[0].clone()
+        });
+        config.save(&path).unwrap();
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.starts_with(
+            "# The house\nbind = \"127.0.0.1:8080\" # next to the router\n\n# Upstairs\n[[collector]]\n"
+        ));
+        assert!(saved.contains("address = \"192.168.0.102\" # the shelly\n"));
+        assert!(!saved.contains("192.168.0.115"));
+        assert!(!saved.contains("timeout"));
+        assert_eq!(Config::from_file(&path).unwrap(), config);
+        std::fs::remove_file(path).unwrap();
+    }
+}
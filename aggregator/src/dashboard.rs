@@ -0,0 +1,68 @@
+use actix_web::{get, web, HttpResponse};
+
+/// The files of the dashboard, compiled into the binary: name, content type and content
+const ASSETS: [(&str, &str, &str); 3] = [
+    (
+        "index.html",
+        "text/html; charset=utf-8",
+        include_str!("../dashboard/index.html"),
+    ),
+    (
+        "dashboard.js",
+        "text/javascript; charset=utf-8",
+        include_str!("../dashboard/dashboard.js"),
+    ),
+    (
+        "dashboard.css",
+        "text/css; charset=utf-8",
+        include_str!("../dashboard/dashboard.css"),
+    ),
+];
+
+fn asset(name: &str) -> HttpResponse {
+    match ASSETS.iter().find(|(n, _, _)| *n == name) {
+        Some((_, content_type, content)) => HttpResponse::Ok()
+            .content_type(*content_type)
+            .insert_header(("Cache-Control", "no-cache"))
+            .body(*content),
+        None => HttpResponse::NotFound().body("No such file"),
+    }
+}
+
+#[get("/dashboard")]
+async fn dashboard() -> HttpResponse {
+    asset("index.html")
+}
+
+#[get("/dashboard/{name}")]
+async fn dashboard_asset(name: web::Path<String>) -> HttpResponse {
+    asset(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn is_dashboard_served() {
+        let app = init_service(App::new().service(dashboard).service(dashboard_asset)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/dashboard").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let page = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        for (name, content_type, _) in &ASSETS[1..] {
+            assert!(page.contains(&format!("\"dashboard/{}\"", name)));
+            let uri = format!("/dashboard/{}", name);
+            let resp = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), *content_type);
+        }
+
+        let missing = TestRequest::get().uri("/dashboard/secrets.toml");
+        let resp = call_service(&app, missing.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
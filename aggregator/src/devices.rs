@@ -0,0 +1,549 @@
+use crate::appliance::Heater;
+use crate::breaker::{BreakerStatus, Breakers, State};
+use crate::config::{CollectorConfig, Config, EventKind, HeaterConfig, Invalid, ThermostatConfig};
+use crate::error::ErrorBody;
+use crate::metrics::Latest;
+use crate::notify::{Notification, Notifier};
+use crate::status::{self, FetchError};
+use actix_web::{delete, get, http::StatusCode, post, put, web, HttpResponse};
+use actix_web::{Responder, ResponseError};
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use util::openapi::{array, integer, object, reference, string, Schema};
+use util::{CollectorInfo, EnvData};
+
+#[derive(Debug)]
+pub enum DeviceError {
+    NotFound(String),
+    Conflict(String),
+    InUse(String),
+    Invalid(Invalid),
+    Unreachable(String),
+    Save(String),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::NotFound(id) => write!(f, "No device named {}", id),
+            DeviceError::Conflict(id) => write!(f, "There is already a device named {}", id),
+            DeviceError::InUse(id) => write!(f, "{} is used by a thermostat", id),
+            DeviceError::Invalid(e) => write!(f, "{}", e),
+            DeviceError::Unreachable(e) => write!(f, "Could not reach the device, {}", e),
+            DeviceError::Save(e) => write!(f, "Could not save the config, {}", e),
+        }
+    }
+}
+
+impl DeviceError {
+    fn kind(&self) -> &'static str {
+        match self {
+            DeviceError::NotFound(_) => "not_found",
+            DeviceError::Conflict(_) => "conflict",
+            DeviceError::InUse(_) => "in_use",
+            DeviceError::Invalid(_) => "invalid",
+            DeviceError::Unreachable(_) => "unreachable",
+            DeviceError::Save(_) => "save",
+        }
+    }
+}
+
+impl ResponseError for DeviceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DeviceError::NotFound(_) => StatusCode::NOT_FOUND,
+            DeviceError::Conflict(_) | DeviceError::InUse(_) => StatusCode::CONFLICT,
+            DeviceError::Invalid(_) => StatusCode::BAD_REQUEST,
+            DeviceError::Unreachable(_) => StatusCode::BAD_GATEWAY,
+            DeviceError::Save(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+        })
+    }
+}
+
+/// The collectors and heaters in use, changes are written back to the config file
+pub struct Devices {
+    path: PathBuf,
+    timeout: Duration,
+    config: RwLock<Config>,
+    /// By id
+    heaters: RwLock<HashMap<String, Arc<Heater>>>,
+    breakers: Breakers,
+    /// Only one change at the time
+    changing: Mutex<()>,
+    notifier: OnceLock<Notifier>,
+    latest: Latest,
+}
+
+impl Devices {
+    pub fn new(config: Config, path: PathBuf) -> Self {
+        let timeout = Duration::from_secs(config.timeout);
+        let breakers = Breakers::new(config.breaker);
+        let heaters = make_heaters(&config, timeout, &breakers);
+        Self {
+            path,
+            timeout,
+            config: RwLock::new(config),
+            heaters: RwLock::new(heaters),
+            breakers,
+            changing: Mutex::new(()),
+            notifier: OnceLock::new(),
+            latest: Latest::default(),
+        }
+    }
+
+    /// Sends notifications through the notifier from now on
+    pub fn notify_with(&self, notifier: Notifier) {
+        let _ = self.notifier.set(notifier);
+    }
+
+    /// Sends the notification, if there is a notifier
+    pub fn notify(&self, notification: Notification) {
+        if let Some(notifier) = self.notifier.get() {
+            notifier.notify(notification);
+        }
+    }
+
+    /// What the collectors last answered
+    pub fn latest(&self) -> &Latest {
+        &self.latest
+    }
+
+    pub async fn collector_configs(&self) -> Vec<CollectorConfig> {
+        self.config.read().await.collectors.clone()
+    }
+
+    pub async fn heaters(&self) -> Vec<Arc<Heater>> {
+        self.heaters.read().await.values().cloned().collect()
+    }
+
+    /// Room of the collector a pushed reading comes from,
+    /// the collector is found by its id or room
+    pub async fn pushed_room(&self, room: &str) -> Option<String> {
+        self.config
+            .read()
+            .await
+            .collectors
+            .iter()
+            .find(|c| c.id == room.to_lowercase() || c.room.eq_ignore_ascii_case(room))
+            .map(|c| c.room.clone())
+    }
+
+    pub async fn collector(&self, id: &str) -> Option<CollectorConfig> {
+        self.config
+            .read()
+            .await
+            .collectors
+            .iter()
+            .find(|c| c.id == id)
+            .cloned()
+    }
+
+    /// Reads `path` on the sensor of the collector, without contacting it while it is down
+    pub async fn read_collector(
+        &self,
+        collector: &CollectorConfig,
+        path: &str,
+        client: &reqwest::Client,
+    ) -> Result<EnvData, FetchError> {
+        let breaker = self.breakers.collector(&collector.id);
+        // Only readings are kept for `/metrics`, the other paths are raw sensor values
+        let is_reading = path == "data";
+        if let Err(down) = breaker.check() {
+            let result = Err(FetchError::from(down));
+            if is_reading {
+                self.latest.scraped(collector, &result, Duration::ZERO);
+            }
+            return result;
+        }
+        let url = format!("{}/{}", collector.sensor_url(), path);
+        let started = Instant::now();
+        let result = status::fetch_env_data(&url, client).await;
+        if is_reading {
+            self.latest.scraped(collector, &result, started.elapsed());
+        }
+        let changed = match result {
+            Ok(_) if breaker.success() => Some((EventKind::CollectorOnline, "is back up")),
+            Err(_) if breaker.failure() => Some((EventKind::CollectorOffline, "stopped answering")),
+            _ => None,
+        };
+        if let Some((event, what)) = changed {
+            let message = format!("Collector {} in {} {}", collector.id, collector.room, what);
+            self.notify(Notification::new(
+                event,
+                &collector.room,
+                &collector.id,
+                message,
+            ));
+        }
+        result
+    }
+
+    pub async fn heater(&self, id: &str) -> Option<Arc<Heater>> {
+        self.heaters.read().await.get(id).cloned()
+    }
+
+    /// Saves a changed thermostat, the room must already have one
+    pub async fn save_thermostat(&self, thermostat: ThermostatConfig) -> Result<(), DeviceError> {
+        let _changing = self.changing.lock().await;
+        let mut config = self.config.read().await.clone();
+        let existing = config
+            .thermostats
+            .iter_mut()
+            .find(|t| t.room == thermostat.room)
+            .ok_or_else(|| DeviceError::NotFound(thermostat.room.clone()))?;
+        *existing = thermostat;
+        self.apply(config, None, None).await
+    }
+
+    async fn probe_collector(
+        &self,
+        collector: &CollectorConfig,
+        client: &reqwest::Client,
+    ) -> Result<CollectorInfo, DeviceError> {
+        let url = format!("{}/info", collector.sensor_url());
+        let response = client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| DeviceError::Unreachable(e.to_string()))?;
+        response
+            .json()
+            .await
+            .map_err(|e| DeviceError::Unreachable(e.to_string()))
+    }
+
+    async fn probe_heater(&self, heater: &HeaterConfig) -> Result<(), DeviceError> {
+        Heater::new(
+            heater,
+            self.timeout,
+            self.breakers.untracked_heater(&heater.id),
+        )
+        .get_status()
+        .await
+        .map(|_| ())
+        .map_err(|e| DeviceError::Unreachable(e.to_string()))
+    }
+
+    /// Saves the new config and starts using it,
+    /// the collector and heater that changed start over with a closed breaker
+    async fn apply(
+        &self,
+        config: Config,
+        collector: Option<&str>,
+        heater: Option<&str>,
+    ) -> Result<(), DeviceError> {
+        config
+            .save(&self.path)
+            .map_err(|e| DeviceError::Save(e.to_string()))?;
+        self.breakers.forget(collector, heater);
+        if let Some(id) = collector {
+            self.latest.forget(id);
+        }
+        let heaters = make_heaters(&config, self.timeout, &self.breakers);
+        *self.heaters.write().await = heaters;
+        *self.config.write().await = config;
+        Ok(())
+    }
+
+    /// Adds or replaces the collector with the given id
+    async fn set_collector(
+        &self,
+        id: Option<&str>,
+        mut collector: CollectorConfig,
+        client: &reqwest::Client,
+    ) -> Result<CollectorConfig, DeviceError> {
+        let _changing = self.changing.lock().await;
+        if let Some(id) = id {
+            collector.id = id.to_string();
+        }
+        collector.validate().map_err(DeviceError::Invalid)?;
+
+        let mut config = self.config.read().await.clone();
+        let existing = config.collectors.iter().position(|c| c.id == collector.id);
+        match (id, existing) {
+            (Some(id), None) => return Err(DeviceError::NotFound(id.to_string())),
+            (None, Some(_)) => return Err(DeviceError::Conflict(collector.id)),
+            _ => (),
+        }
+
+        self.probe_collector(&collector, client).await?;
+
+        match existing {
+            Some(i) => config.collectors[i] = collector.clone(),
+            None => config.collectors.push(collector.clone()),
+        }
+        self.apply(config, Some(&collector.id), None).await?;
+        Ok(collector)
+    }
+
+    async fn remove_collector(&self, id: &str) -> Result<(), DeviceError> {
+        let _changing = self.changing.lock().await;
+        let mut config = self.config.read().await.clone();
+        let i = config
+            .collectors
+            .iter()
+            .position(|c| c.id == id)
+            .ok_or_else(|| DeviceError::NotFound(id.to_string()))?;
+        if config.thermostats.iter().any(|t| t.collector == id) {
+            return Err(DeviceError::InUse(id.to_string()));
+        }
+        config.collectors.remove(i);
+        self.apply(config, Some(id), None).await?;
+        Ok(())
+    }
+
+    /// Adds or replaces the heater with the given id,
+    /// a heater updated without a password keeps the one it had
+    async fn set_heater(
+        &self,
+        id: Option<&str>,
+        mut heater: HeaterConfig,
+    ) -> Result<HeaterConfig, DeviceError> {
+        let _changing = self.changing.lock().await;
+        if let Some(id) = id {
+            heater.id = id.to_string();
+        }
+
+        let mut config = self.config.read().await.clone();
+        let existing = config.heaters.iter().position(|h| h.id == heater.id);
+        if let Some(i) = existing {
+            if heater.password.is_none() && heater.username == config.heaters[i].username {
+                heater.password = config.heaters[i].password.clone();
+            }
+        }
+        heater.validate().map_err(DeviceError::Invalid)?;
+        match (id, existing) {
+            (Some(id), None) => return Err(DeviceError::NotFound(id.to_string())),
+            (None, Some(_)) => return Err(DeviceError::Conflict(heater.id)),
+            _ => (),
+        }
+
+        self.probe_heater(&heater).await?;
+
+        match existing {
+            Some(i) => config.heaters[i] = heater.clone(),
+            None => config.heaters.push(heater.clone()),
+        }
+        self.apply(config, None, Some(&heater.id)).await?;
+        Ok(hide_password(heater))
+    }
+
+    async fn remove_heater(&self, id: &str) -> Result<(), DeviceError> {
+        let _changing = self.changing.lock().await;
+        let mut config = self.config.read().await.clone();
+        let i = config
+            .heaters
+            .iter()
+            .position(|h| h.id == id)
+            .ok_or_else(|| DeviceError::NotFound(id.to_string()))?;
+        if config.thermostats.iter().any(|t| t.heater == id) {
+            return Err(DeviceError::InUse(id.to_string()));
+        }
+        config.heaters.remove(i);
+        self.apply(config, None, Some(id)).await?;
+        Ok(())
+    }
+}
+
+fn make_heaters(
+    config: &Config,
+    timeout: Duration,
+    breakers: &Breakers,
+) -> HashMap<String, Arc<Heater>> {
+    config
+        .heaters
+        .iter()
+        .map(|h| {
+            let heater = Heater::new(h, timeout, breakers.heater(&h.id));
+            (h.id.clone(), Arc::new(heater))
+        })
+        .collect()
+}
+
+fn hide_password(mut heater: HeaterConfig) -> HeaterConfig {
+    heater.password = None;
+    heater
+}
+
+#[derive(Serialize)]
+pub struct DeviceList {
+    collectors: Vec<CollectorConfig>,
+    heaters: Vec<HeaterConfig>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceHealth {
+    id: String,
+    room: String,
+    #[serde(flatten)]
+    breaker: BreakerStatus,
+}
+
+#[derive(Serialize)]
+pub struct HealthList {
+    collectors: Vec<DeviceHealth>,
+    heaters: Vec<DeviceHealth>,
+}
+
+impl Schema for DeviceHealth {
+    const NAME: &'static str = "DeviceHealth";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("id", string()),
+                ("room", string()),
+                ("state", reference::<State>()),
+                ("consecutive_failures", integer()),
+            ],
+            &[("retry_in", integer())],
+        )
+    }
+}
+
+impl Schema for DeviceList {
+    const NAME: &'static str = "DeviceList";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("collectors", array(reference::<CollectorConfig>())),
+                ("heaters", array(reference::<HeaterConfig>())),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for HealthList {
+    const NAME: &'static str = "HealthList";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("collectors", array(reference::<DeviceHealth>())),
+                ("heaters", array(reference::<DeviceHealth>())),
+            ],
+            &[],
+        )
+    }
+}
+
+#[get("/devices")]
+async fn list_devices(devices: web::Data<Devices>) -> Result<impl Responder, DeviceError> {
+    let config = devices.config.read().await;
+    Ok(web::Json(DeviceList {
+        collectors: config.collectors.clone(),
+        heaters: config.heaters.iter().cloned().map(hide_password).collect(),
+    }))
+}
+
+/// Whether each device is taken as up or down
+#[get("/devices/health")]
+async fn device_health(devices: web::Data<Devices>) -> impl Responder {
+    let config = devices.config.read().await;
+    let collectors = config
+        .collectors
+        .iter()
+        .map(|c| DeviceHealth {
+            id: c.id.clone(),
+            room: c.room.clone(),
+            breaker: devices.breakers.collector(&c.id).status(),
+        })
+        .collect();
+    let heaters = config
+        .heaters
+        .iter()
+        .map(|h| DeviceHealth {
+            id: h.id.clone(),
+            room: h.room.clone(),
+            breaker: devices.breakers.heater(&h.id).status(),
+        })
+        .collect();
+    web::Json(HealthList {
+        collectors,
+        heaters,
+    })
+}
+
+#[post("/devices/collectors")]
+async fn add_collector(
+    devices: web::Data<Devices>,
+    client: web::Data<reqwest::Client>,
+    collector: web::Json<CollectorConfig>,
+) -> Result<impl Responder, DeviceError> {
+    let collector = devices
+        .set_collector(None, collector.into_inner(), &client)
+        .await?;
+    info!("Added collector {}", collector.id);
+    Ok(HttpResponse::Created().json(collector))
+}
+
+#[put("/devices/collectors/{id}")]
+async fn update_collector(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+    client: web::Data<reqwest::Client>,
+    collector: web::Json<CollectorConfig>,
+) -> Result<impl Responder, DeviceError> {
+    let collector = devices
+        .set_collector(Some(&id), collector.into_inner(), &client)
+        .await?;
+    info!("Updated collector {}", collector.id);
+    Ok(web::Json(collector))
+}
+
+#[delete("/devices/collectors/{id}")]
+async fn remove_collector(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+) -> Result<impl Responder, DeviceError> {
+    devices.remove_collector(&id).await?;
+    info!("Removed collector {}", id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/devices/heaters")]
+async fn add_heater(
+    devices: web::Data<Devices>,
+    heater: web::Json<HeaterConfig>,
+) -> Result<impl Responder, DeviceError> {
+    let heater = devices.set_heater(None, heater.into_inner()).await?;
+    info!("Added heater {}", heater.id);
+    Ok(HttpResponse::Created().json(heater))
+}
+
+#[put("/devices/heaters/{id}")]
+async fn update_heater(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+    heater: web::Json<HeaterConfig>,
+) -> Result<impl Responder, DeviceError> {
+    let heater = devices.set_heater(Some(&id), heater.into_inner()).await?;
+    info!("Updated heater {}", heater.id);
+    Ok(web::Json(heater))
+}
+
+#[delete("/devices/heaters/{id}")]
+async fn remove_heater(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+) -> Result<impl Responder, DeviceError> {
+    devices.remove_heater(&id).await?;
+    info!("Removed heater {}", id);
+    Ok(HttpResponse::NoContent().finish())
+}
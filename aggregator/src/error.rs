@@ -0,0 +1,17 @@
+use serde::Serialize;
+use util::openapi::{object, string, Schema};
+
+/// What every failed request is answered with, `error` is short and meant for programs
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl Schema for ErrorBody {
+    const NAME: &'static str = "Error";
+
+    fn schema() -> serde_json::Value {
+        object(&[("error", string()), ("message", string())], &[])
+    }
+}
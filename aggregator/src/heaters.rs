@@ -0,0 +1,188 @@
+use crate::appliance::{Heater, HeaterError};
+use crate::config::EventKind;
+use crate::devices::Devices;
+use crate::error::ErrorBody;
+use crate::live::{Event, Feed};
+use crate::notify::Notification;
+use actix_web::{get, http::StatusCode, post, put, web, HttpResponse, ResponseError};
+use log::{error, info};
+use serde::Deserialize;
+use std::fmt;
+use std::sync::Arc;
+use util::openapi::{boolean, object, Schema};
+
+/// Why a heater request failed, sent as JSON
+#[derive(Debug)]
+pub enum HeaterApiError {
+    NotFound(String),
+    Heater(HeaterError),
+}
+
+impl fmt::Display for HeaterApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaterApiError::NotFound(id) => write!(f, "No heater named {}", id),
+            HeaterApiError::Heater(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl HeaterApiError {
+    fn kind(&self) -> &'static str {
+        match self {
+            HeaterApiError::NotFound(_) => "not_found",
+            HeaterApiError::Heater(HeaterError::Down(_)) => "down",
+            HeaterApiError::Heater(HeaterError::Device(e)) if e.is_timeout() => "timeout",
+            HeaterApiError::Heater(HeaterError::Device(_)) => "device",
+        }
+    }
+}
+
+impl ResponseError for HeaterApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HeaterApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            HeaterApiError::Heater(HeaterError::Down(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            HeaterApiError::Heater(HeaterError::Device(e)) if e.is_timeout() => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            HeaterApiError::Heater(HeaterError::Device(_)) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+        })
+    }
+}
+
+impl From<HeaterError> for HeaterApiError {
+    fn from(e: HeaterError) -> Self {
+        error!("{}", e);
+        HeaterApiError::Heater(e)
+    }
+}
+
+async fn find(devices: &Devices, id: &str) -> Result<Arc<Heater>, HeaterApiError> {
+    devices
+        .heater(id)
+        .await
+        .ok_or_else(|| HeaterApiError::NotFound(id.to_string()))
+}
+
+/// Switches the heater and answers with its new status
+async fn switch(
+    heater: &Heater,
+    on: bool,
+    devices: &Devices,
+    feed: &Feed,
+) -> Result<HttpResponse, HeaterApiError> {
+    let r = if on {
+        heater.turn_on().await?
+    } else {
+        heater.turn_off().await?
+    };
+    info!("{}", r);
+    let status = heater.get_status().await?;
+    feed.publish(Event::heater(heater, status.is_on));
+    devices.notify(switched(heater, status.is_on, "through the API"));
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Tells that the heater was switched and by what
+pub fn switched(heater: &Heater, is_on: bool, by: &str) -> Notification {
+    let message = format!(
+        "Heater {} in {} was turned {} {}",
+        heater.get_id(),
+        heater.get_room(),
+        if is_on { "on" } else { "off" },
+        by
+    );
+    Notification::new(
+        EventKind::HeaterSwitched,
+        heater.get_room(),
+        heater.get_id(),
+        message,
+    )
+}
+
+#[derive(Deserialize)]
+pub struct HeaterState {
+    is_on: bool,
+}
+
+impl Schema for HeaterState {
+    const NAME: &'static str = "HeaterState";
+
+    fn schema() -> serde_json::Value {
+        object(&[("is_on", boolean())], &[])
+    }
+}
+
+#[get("/heater/{id}")]
+async fn heater_status(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+) -> Result<HttpResponse, HeaterApiError> {
+    let heater = find(&devices, &id).await?;
+    Ok(HttpResponse::Ok().json(heater.get_status().await?))
+}
+
+#[put("/heater/{id}")]
+async fn set_heater_state(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+    feed: web::Data<Feed>,
+    state: web::Json<HeaterState>,
+) -> Result<HttpResponse, HeaterApiError> {
+    let heater = find(&devices, &id).await?;
+    switch(&heater, state.is_on, &devices, &feed).await
+}
+
+#[post("/heater/{id}/on")]
+async fn heater_on(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+    feed: web::Data<Feed>,
+) -> Result<HttpResponse, HeaterApiError> {
+    let heater = find(&devices, &id).await?;
+    switch(&heater, true, &devices, &feed).await
+}
+
+#[post("/heater/{id}/off")]
+async fn heater_off(
+    id: web::Path<String>,
+    devices: web::Data<Devices>,
+    feed: web::Data<Feed>,
+) -> Result<HttpResponse, HeaterApiError> {
+    let heater = find(&devices, &id).await?;
+    switch(&heater, false, &devices, &feed).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::Down;
+    use actix_web::body::MessageBody;
+
+    #[test]
+    fn is_error_json() {
+        let e = HeaterApiError::NotFound("attic".to_string());
+        assert_eq!(e.status_code(), StatusCode::NOT_FOUND);
+        let body = e.error_response().into_body().try_into_bytes().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"error": "not_found", "message": "No heater named attic"})
+        );
+
+        let e = HeaterApiError::Heater(HeaterError::Down(Down {
+            name: "Heater bedroom".to_string(),
+            retry_in: 10,
+        }));
+        assert_eq!(e.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        let e = HeaterApiError::Heater(HeaterError::Device(Default::default()));
+        assert_eq!(e.status_code(), StatusCode::BAD_GATEWAY);
+    }
+}
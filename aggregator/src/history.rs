@@ -0,0 +1,290 @@
+use crate::storage::{self, Bucket, Storage};
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use util::openapi::{array, boolean, integer, nullable, number, object, reference, string, Schema};
+
+/// Most buckets returned for each room
+const MAX_BUCKETS: u64 = 10_000;
+
+/// Min, average and max of a bucket, in °C or %
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Summary {
+    pub min: f32,
+    pub avg: f32,
+    pub max: f32,
+}
+
+impl Summary {
+    fn new((min, sum, max): (i64, i64, i64), count: u64) -> Self {
+        Self {
+            min: min as f32 / 10.0,
+            avg: (sum as f64 / count as f64 / 10.0) as f32,
+            max: max as f32 / 10.0,
+        }
+    }
+}
+
+/// The readings in a time span, `gap` is set when there are none
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Point {
+    pub start: u64,
+    pub count: u64,
+    pub gap: bool,
+    pub temperature: Option<Summary>,
+    pub humidity: Option<Summary>,
+}
+
+#[derive(Serialize)]
+struct RoomHistory {
+    room: String,
+    points: Vec<Point>,
+}
+
+#[derive(Serialize)]
+pub struct History {
+    from: u64,
+    to: u64,
+    step: u64,
+    rooms: Vec<RoomHistory>,
+}
+
+impl Schema for Summary {
+    const NAME: &'static str = "Summary";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[("min", number()), ("avg", number()), ("max", number())],
+            &[],
+        )
+    }
+}
+
+impl Schema for Point {
+    const NAME: &'static str = "Point";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("start", integer()),
+                ("count", integer()),
+                ("gap", boolean()),
+                ("temperature", nullable(reference::<Summary>())),
+                ("humidity", nullable(reference::<Summary>())),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for History {
+    const NAME: &'static str = "History";
+
+    fn schema() -> serde_json::Value {
+        let room = object(
+            &[("room", string()), ("points", array(reference::<Point>()))],
+            &[],
+        );
+        object(
+            &[
+                ("from", integer()),
+                ("to", integer()),
+                ("step", integer()),
+                ("rooms", array(room)),
+            ],
+            &[],
+        )
+    }
+}
+
+/// One point for every step from `from` to `to`, with the missing ones marked as gaps
+pub fn fill_gaps(buckets: Vec<Bucket>, from: u64, to: u64, step: u64) -> Vec<Point> {
+    let mut buckets = buckets.into_iter().peekable();
+    (from..to)
+        .step_by(step as usize)
+        .map(|start| match buckets.next_if(|b| b.start == start) {
+            Some(b) => Point {
+                start,
+                count: b.count,
+                gap: false,
+                temperature: Some(Summary::new(b.temperature, b.count)),
+                humidity: Some(Summary::new(b.humidity, b.count)),
+            },
+            None => Point {
+                start,
+                count: 0,
+                gap: true,
+                temperature: None,
+                humidity: None,
+            },
+        })
+        .collect()
+}
+
+/// The parameters of a history query
+#[derive(Debug, PartialEq)]
+struct Query {
+    rooms: Vec<String>,
+    from: u64,
+    to: u64,
+    step: u64,
+}
+
+impl Query {
+    /// Rooms can be given several times or separated by commas,
+    /// the last day is used by default with about 200 points
+    fn parse(pairs: &[(String, String)], now: u64) -> Result<Self, String> {
+        let mut rooms = Vec::new();
+        let (mut from, mut to, mut step) = (None, None, None);
+        for (key, value) in pairs {
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("{} must be a number of seconds", key))
+            };
+            match key.as_str() {
+                "room" => rooms.extend(
+                    value
+                        .split(',')
+                        .filter(|r| !r.is_empty())
+                        .map(|r| r.to_string()),
+                ),
+                "from" => from = Some(number()?),
+                "to" => to = Some(number()?),
+                "step" => step = Some(number()?),
+                _ => return Err(format!("Unknown parameter {}", key)),
+            }
+        }
+
+        if rooms.is_empty() {
+            return Err("At least one room is needed".to_string());
+        }
+        let to = to.unwrap_or(now + 1);
+        let from = from.unwrap_or_else(|| to.saturating_sub(24 * 60 * 60));
+        if from >= to {
+            return Err("from must be before to".to_string());
+        }
+        let step = step.unwrap_or_else(|| ((to - from) / 200).max(60));
+        if step == 0 {
+            return Err("step must be more than 0".to_string());
+        }
+        if (to - from).div_ceil(step) > MAX_BUCKETS {
+            return Err(format!("At most {} steps can be asked for", MAX_BUCKETS));
+        }
+        Ok(Self {
+            rooms,
+            from,
+            to,
+            step,
+        })
+    }
+}
+
+/// Downsampled readings of one or more rooms
+#[get("/history")]
+async fn history(
+    storage: Option<web::Data<dyn Storage>>,
+    query: web::Query<Vec<(String, String)>>,
+) -> Result<HttpResponse, storage::StorageError> {
+    let storage = match storage {
+        Some(storage) => storage.into_inner(),
+        None => return Ok(storage::not_enabled()),
+    };
+    let query = match Query::parse(&query, util::now()) {
+        Ok(query) => query,
+        Err(e) => return Ok(storage::invalid_query(e)),
+    };
+
+    let (from, to, step) = (query.from, query.to, query.step);
+    let mut rooms = Vec::new();
+    for room in query.rooms {
+        let name = room.clone();
+        let buckets =
+            storage::blocking(&storage, move |s| s.buckets(&name, from, to, step)).await?;
+        rooms.push(RoomHistory {
+            room,
+            points: fill_gaps(buckets, from, to, step),
+        });
+    }
+    Ok(HttpResponse::Ok().json(History {
+        from,
+        to,
+        step,
+        rooms,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(query: &str) -> Vec<(String, String)> {
+        query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn is_query_parsed() {
+        let query = Query::parse(
+            &pairs("room=Bedroom,Kitchen&room=Hall&from=100&to=200&step=10"),
+            0,
+        );
+        assert_eq!(
+            query,
+            Ok(Query {
+                rooms: vec![
+                    "Bedroom".to_string(),
+                    "Kitchen".to_string(),
+                    "Hall".to_string()
+                ],
+                from: 100,
+                to: 200,
+                step: 10,
+            })
+        );
+
+        let query = Query::parse(&pairs("room=Bedroom"), 86400).unwrap();
+        assert_eq!((query.from, query.to, query.step), (1, 86401, 432));
+
+        assert!(Query::parse(&pairs("from=100"), 0).is_err());
+        assert!(Query::parse(&pairs("room=Bedroom&from=200&to=100"), 0).is_err());
+        assert!(Query::parse(&pairs("room=Bedroom&step=0"), 1000).is_err());
+        assert!(Query::parse(&pairs("room=Bedroom&from=0&to=100000&step=1"), 0).is_err());
+        assert!(Query::parse(&pairs("rooms=Bedroom"), 0).is_err());
+    }
+
+    #[test]
+    fn is_gap_marked() {
+        let buckets = vec![
+            Bucket {
+                start: 100,
+                count: 2,
+                temperature: (200, 410, 210),
+                humidity: (400, 800, 400),
+            },
+            Bucket {
+                start: 120,
+                count: 1,
+                temperature: (220, 220, 220),
+                humidity: (410, 410, 410),
+            },
+        ];
+        let points = fill_gaps(buckets, 100, 135, 10);
+        assert_eq!(points.len(), 4);
+        assert_eq!(
+            points[0].temperature,
+            Some(Summary {
+                min: 20.0,
+                avg: 20.5,
+                max: 21.0
+            })
+        );
+        assert!(points[1].gap);
+        assert_eq!(points[1].start, 110);
+        assert!(points[1].temperature.is_none());
+        assert!(!points[2].gap);
+        assert!(points[3].gap);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::appliance::Heater;
+use crate::devices::Devices;
+use crate::status::Readings;
+use crate::thermostat::Decision;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, Codec, Frame, Message};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use util::EnvData;
+
+/// Something that happened in the house, sent to the viewers of `/live`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A new reading from a collector
+    Reading { collector: String, data: EnvData },
+    /// A heater was switched, or found to be on or off
+    Heater {
+        heater: String,
+        room: String,
+        is_on: bool,
+    },
+    /// What a thermostat did and why
+    Decision { room: String, decision: Decision },
+    /// The rooms the viewer gets events for now, all when empty
+    Subscribed { rooms: Vec<String> },
+}
+
+impl Event {
+    pub fn heater(heater: &Heater, is_on: bool) -> Self {
+        Event::Heater {
+            heater: heater.get_id().to_string(),
+            room: heater.get_room().to_string(),
+            is_on,
+        }
+    }
+
+    fn room(&self) -> Option<&str> {
+        match self {
+            Event::Reading { data, .. } => Some(&data.room),
+            Event::Heater { room, .. } | Event::Decision { room, .. } => Some(room),
+            Event::Subscribed { .. } => None,
+        }
+    }
+
+    /// Events with the same key replace each other
+    fn key(&self) -> String {
+        match self {
+            Event::Reading { collector, .. } => format!("reading/{}", collector),
+            Event::Heater { heater, .. } => format!("heater/{}", heater),
+            Event::Decision { room, .. } => format!("decision/{}", room),
+            Event::Subscribed { .. } => "subscribed".to_string(),
+        }
+    }
+}
+
+/// Sends the events to everyone following `/live`
+pub struct Feed {
+    sender: broadcast::Sender<Event>,
+    /// The last event of each key, given to new viewers
+    last: Mutex<HashMap<String, Event>>,
+    interval: Duration,
+}
+
+impl Feed {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            sender: broadcast::channel(64).0,
+            last: Mutex::new(HashMap::new()),
+            interval,
+        }
+    }
+
+    /// Sends the event unless it is the same as the last one of its kind
+    pub fn publish(&self, event: Event) {
+        let mut last = self.last.lock().unwrap();
+        if last.get(&event.key()) == Some(&event) {
+            return;
+        }
+        last.insert(event.key(), event.clone());
+        // Nobody is listening when this fails
+        let _ = self.sender.send(event);
+    }
+
+    /// The latest events and the ones to come
+    fn subscribe(&self) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        let last = self.last.lock().unwrap();
+        (last.values().cloned().collect(), self.sender.subscribe())
+    }
+}
+
+/// Reads the devices while someone follows `/live`, through the same cache as `/`
+pub async fn poll(
+    feed: web::Data<Feed>,
+    devices: web::Data<Devices>,
+    client: web::Data<reqwest::Client>,
+    readings: web::Data<Readings>,
+) {
+    let mut interval = tokio::time::interval(feed.interval);
+    loop {
+        interval.tick().await;
+        if feed.sender.receiver_count() == 0 {
+            continue;
+        }
+        for status in readings.read_all(&devices, &client, "data", None).await {
+            if let Some(data) = status.data {
+                feed.publish(Event::Reading {
+                    collector: status.id,
+                    data,
+                });
+            }
+        }
+        for heater in devices.heaters().await {
+            match heater.get_status().await {
+                Ok(status) => feed.publish(Event::heater(&heater, status.is_on)),
+                Err(e) => error!("Could not read heater {}, {}", heater.get_id(), e),
+            }
+        }
+    }
+}
+
+/// Which rooms a viewer follows, in lowercase
+struct Rooms(Vec<String>);
+
+impl Rooms {
+    fn new<'a>(rooms: impl IntoIterator<Item = &'a str>) -> Self {
+        Rooms(
+            rooms
+                .into_iter()
+                .map(|r| r.trim().to_lowercase())
+                .filter(|r| !r.is_empty())
+                .collect(),
+        )
+    }
+
+    fn allows(&self, event: &Event) -> bool {
+        match event.room() {
+            Some(room) => self.0.is_empty() || self.0.contains(&room.to_lowercase()),
+            None => true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LiveQuery {
+    /// Rooms separated by commas, all rooms when left out
+    room: Option<String>,
+}
+
+/// Sent by a viewer to change the rooms it follows
+#[derive(Deserialize)]
+struct Subscribe {
+    rooms: Vec<String>,
+}
+
+type Sender = mpsc::Sender<Result<Bytes, actix_web::Error>>;
+
+/// Writes the message as a frame, `Err` when the viewer is gone
+async fn send(out: &mut Sender, codec: &mut Codec, message: Message) -> Result<(), ()> {
+    let mut buf = BytesMut::new();
+    codec.encode(message, &mut buf).map_err(|_| ())?;
+    out.send(Ok(buf.freeze())).await.map_err(|_| ())
+}
+
+async fn send_event(out: &mut Sender, codec: &mut Codec, event: &Event) -> Result<(), ()> {
+    let json = serde_json::to_string(event).map_err(|_| ())?;
+    send(out, codec, Message::Text(json.into())).await
+}
+
+/// Passes the events on to one viewer until it leaves
+async fn follow(
+    mut payload: web::Payload,
+    mut out: Sender,
+    feed: web::Data<Feed>,
+    mut rooms: Rooms,
+) -> Result<(), ()> {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+    let (latest, mut events) = feed.subscribe();
+    for event in latest.iter().filter(|e| rooms.allows(e)) {
+        send_event(&mut out, &mut codec, event).await?;
+    }
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if rooms.allows(&event) => send_event(&mut out, &mut codec, &event).await?,
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => warn!("A viewer of /live missed {} events", n),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            chunk = payload.next() => {
+                match chunk {
+                    Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                    _ => return Ok(()),
+                }
+                while let Some(frame) = codec.decode(&mut buf).map_err(|_| ())? {
+                    match frame {
+                        Frame::Text(text) => match serde_json::from_slice::<Subscribe>(&text) {
+                            Ok(subscribe) => {
+                                rooms = Rooms::new(subscribe.rooms.iter().map(String::as_str));
+                                let event = Event::Subscribed { rooms: rooms.0.clone() };
+                                send_event(&mut out, &mut codec, &event).await?;
+                            }
+                            Err(e) => info!("Ignoring a message on /live, {}", e),
+                        },
+                        Frame::Ping(ping) => send(&mut out, &mut codec, Message::Pong(ping)).await?,
+                        Frame::Close(reason) => {
+                            return send(&mut out, &mut codec, Message::Close(reason)).await;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Upgrades to a WebSocket sending the events of the rooms asked for as JSON
+#[get("/live")]
+async fn live(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<LiveQuery>,
+    feed: web::Data<Feed>,
+) -> HttpResponse {
+    if let Err(e) = ws::verify_handshake(req.head()) {
+        return actix_http::Response::from(e).into();
+    }
+    let key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) => ws::hash_key(key.as_bytes()),
+        None => return HttpResponse::BadRequest().finish(),
+    };
+    let rooms = Rooms::new(query.room.as_deref().unwrap_or_default().split(','));
+
+    let (out, stream) = mpsc::channel(16);
+    actix_web::rt::spawn(async move {
+        let _ = follow(payload, out, feed, rooms).await;
+    });
+    HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, &key[..]))
+        .streaming(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpServer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn reading(collector: &str, room: &str, temperature: i16) -> Event {
+        Event::Reading {
+            collector: collector.to_string(),
+            data: EnvData {
+                room: room.to_string(),
+                temperature,
+                humidity: 400,
+                timestamp: 1700000000,
+            },
+        }
+    }
+
+    #[test]
+    fn is_same_event_sent_once() {
+        let feed = Feed::new(Duration::from_secs(5));
+        let (_, mut events) = feed.subscribe();
+        feed.publish(reading("bedroom", "Bedroom", 215));
+        feed.publish(reading("bedroom", "Bedroom", 215));
+        feed.publish(reading("bedroom", "Bedroom", 216));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            reading("bedroom", "Bedroom", 215)
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            reading("bedroom", "Bedroom", 216)
+        );
+        assert!(events.try_recv().is_err());
+
+        let (latest, _) = feed.subscribe();
+        assert_eq!(latest, vec![reading("bedroom", "Bedroom", 216)]);
+        assert_eq!(
+            serde_json::to_value(&latest[0]).unwrap(),
+            serde_json::json!({
+                "type": "reading",
+                "collector": "bedroom",
+                "data": {"room": "Bedroom", "temperature": 216, "humidity": 400, "timestamp": 1700000000},
+            })
+        );
+    }
+
+    /// A bare WebSocket client, enough to follow the feed
+    struct Viewer {
+        stream: TcpStream,
+        codec: Codec,
+        buf: BytesMut,
+    }
+
+    impl Viewer {
+        async fn connect(port: u16, path: &str) -> Self {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                path
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut buf = BytesMut::new();
+            let end = loop {
+                stream.read_buf(&mut buf).await.unwrap();
+                if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end;
+                }
+            };
+            let head = String::from_utf8_lossy(&buf[..end]).to_string();
+            assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+            assert!(head.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{}", head);
+            let _ = buf.split_to(end + 4);
+            Self {
+                stream,
+                codec: Codec::new().client_mode(),
+                buf,
+            }
+        }
+
+        async fn next(&mut self) -> serde_json::Value {
+            loop {
+                if let Some(Frame::Text(text)) = self.codec.decode(&mut self.buf).unwrap() {
+                    return serde_json::from_slice(&text).unwrap();
+                }
+                self.stream.read_buf(&mut self.buf).await.unwrap();
+            }
+        }
+
+        async fn send(&mut self, text: &str) {
+            let mut buf = BytesMut::new();
+            self.codec
+                .encode(Message::Text(text.to_string().into()), &mut buf)
+                .unwrap();
+            self.stream.write_all(&buf).await.unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn is_room_followed() {
+        let feed = web::Data::new(Feed::new(Duration::from_secs(5)));
+        feed.publish(reading("bedroom", "Bedroom", 215));
+        feed.publish(reading("kitchen", "Kitchen", 190));
+
+        let app_feed = feed.clone();
+        let server = HttpServer::new(move || App::new().app_data(app_feed.clone()).service(live))
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut viewer = Viewer::connect(port, "/live?room=bedroom").await;
+        assert_eq!(viewer.next().await["data"]["room"], "Bedroom");
+
+        feed.publish(reading("kitchen", "Kitchen", 191));
+        feed.publish(Event::Heater {
+            heater: "bedroom".to_string(),
+            room: "Bedroom".to_string(),
+            is_on: true,
+        });
+        let event = viewer.next().await;
+        assert_eq!(event["type"], "heater");
+        assert_eq!(event["is_on"], true);
+
+        viewer.send(r#"{"rooms": ["Kitchen"]}"#).await;
+        assert_eq!(
+            viewer.next().await,
+            serde_json::json!({"type": "subscribed", "rooms": ["kitchen"]})
+        );
+        feed.publish(reading("bedroom", "Bedroom", 216));
+        feed.publish(reading("kitchen", "Kitchen", 192));
+        assert_eq!(viewer.next().await["data"]["temperature"], 192);
+
+        handle.stop(false).await;
+    }
+}
@@ -1,18 +1,49 @@
+mod alerts;
 #[macro_use]
 mod appliance;
+mod auth;
+mod breaker;
+mod certs;
+mod config;
+mod dashboard;
+mod devices;
+mod error;
+mod heaters;
+mod history;
+mod live;
+mod metrics;
+mod model;
+mod notify;
+mod openapi;
+mod schedule;
+mod status;
+mod storage;
+mod thermostat;
+mod tls;
 extern crate log;
 extern crate simplelog;
 extern crate util;
 
-use actix_web::{get, web, App, HttpRequest, HttpServer, Responder};
-use appliance::{Heater, MyCollector};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use alerts::Alerts;
+use auth::{Auth, TokenCommand, Tokens};
+use certs::CertCommand;
+use config::Config;
+use devices::Devices;
+use live::Feed;
 use log::{error, info};
+use metrics::{HttpMetrics, Measure};
+use model::Models;
 use simplelog::*;
+use status::{Format, ReadQuery, Readings};
 use std::fs::File;
-use std::io::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use storage::Storage;
 use structopt::StructOpt;
-use util::{Collector, EnvData, ShellyStatus};
+use thermostat::Thermostats;
+use util::EnvData;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -20,179 +51,338 @@ use util::{Collector, EnvData, ShellyStatus};
     about = "Connection point for the SmartAppliances"
 )]
 struct Opt {
-    #[structopt(short = "l", long = "log-file", default_value = "aggregator.log")]
-    log_file: String,
+    #[structopt(short = "c", long = "config", default_value = "aggregator.toml")]
+    config: PathBuf,
 
-    #[structopt(short = "c", long = "collectors", default_value = "collectors.json")]
-    collectors: String,
+    /// Overrides the log file from the config
+    #[structopt(short = "l", long = "log-file")]
+    log_file: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-async fn get_env_data(url: &str, client: &reqwest::Client) -> Option<EnvData> {
-    match client.get(url).send().await {
-        Ok(response) => match response.json().await {
-            Ok(data) => Some(data),
-            Err(e) => {
-                error!("{}", e);
-                None
-            }
-        },
-        Err(e) => {
-            error!("{}", e);
-            None
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Manages the API tokens in the config
+    Token(TokenCommand),
+    /// Makes the house CA and the certificates for mTLS with the collectors
+    Cert(CertCommand),
+}
+
+/// The readings of every collector, in the asked for format
+async fn read_collectors(
+    req: &HttpRequest,
+    devices: &Devices,
+    client: &reqwest::Client,
+    readings: &Readings,
+    query: ReadQuery,
+    path: &str,
+) -> HttpResponse {
+    let max_age = query.max_age.or_else(|| {
+        req.headers()
+            .get("Cache-Control")
+            .and_then(|h| h.to_str().ok())
+            .and_then(status::cache_control_max_age)
+    });
+    let statuses = readings.read_all(devices, client, path, max_age).await;
+
+    let con_info = req.connection_info();
+    for data in statuses.iter().filter_map(|s| s.data.as_ref()) {
+        info!("{},{}", con_info.host(), data);
+    }
+    match query.format {
+        Format::Array => {
+            let resp: Vec<EnvData> = statuses.into_iter().filter_map(|s| s.data).collect();
+            HttpResponse::Ok().json(resp)
         }
+        Format::Status => HttpResponse::Ok().json(statuses),
     }
 }
 
 #[get("/")]
 async fn collect(
     req: HttpRequest,
-    collectors: web::Data<Vec<Collector>>,
+    devices: web::Data<Devices>,
     client: web::Data<reqwest::Client>,
-) -> Result<impl Responder, Error> {
-    let mut futures = Vec::new();
-    for collector in collectors.iter() {
-        let url = collector.url().clone();
-        let local_client = client.clone();
-        futures.push(tokio::spawn(async move {
-            get_env_data(format!("{}/data", &url).as_str(), &local_client).await
-        }));
-    }
-
-    let mut resp = Vec::new();
-    for f in futures {
-        match f.await? {
-            Some(data) => resp.push(data),
-            None => {
-                error!("Error while collecting data");
-            }
-        }
-    }
-
-    let con_info = req.connection_info();
-    for data in &resp {
-        info!("{},{}", con_info.host(), data);
-    }
-    Ok(web::Json(resp))
+    readings: web::Data<Readings>,
+    query: web::Query<ReadQuery>,
+) -> impl Responder {
+    read_collectors(
+        &req,
+        &devices,
+        &client,
+        &readings,
+        query.into_inner(),
+        "data",
+    )
+    .await
 }
 
 #[get("/read")]
 async fn read(
     req: HttpRequest,
-    collectors: web::Data<Vec<Collector>>,
+    devices: web::Data<Devices>,
     client: web::Data<reqwest::Client>,
-) -> Result<impl Responder, Error> {
-    let mut futures = Vec::new();
-    for collector in collectors.iter() {
-        let url = collector.url().clone();
-        let local_client = client.clone();
-        futures.push(tokio::spawn(async move {
-            get_env_data(format!("{}/read", &url).as_str(), &local_client).await
-        }));
-    }
+    readings: web::Data<Readings>,
+    query: web::Query<ReadQuery>,
+) -> impl Responder {
+    read_collectors(
+        &req,
+        &devices,
+        &client,
+        &readings,
+        query.into_inner(),
+        "read",
+    )
+    .await
+}
 
-    let mut resp = Vec::new();
-    for f in futures {
-        match f.await? {
-            Some(data) => resp.push(data),
-            None => {
-                error!("Error while collecting data");
-            }
+#[post("/ingest")]
+async fn ingest(
+    req: HttpRequest,
+    devices: web::Data<Devices>,
+    storage: Option<web::Data<dyn Storage>>,
+    readings: web::Json<Vec<EnvData>>,
+) -> impl Responder {
+    // Kept under the room of the config, like the polled readings
+    let mut readings = readings.into_inner();
+    for data in readings.iter_mut() {
+        if let Some(room) = devices.pushed_room(&data.room).await {
+            data.room = room;
         }
     }
-
-    let con_info = req.connection_info();
-    for data in &resp {
-        info!("{},{}", con_info.host(), data);
-    }
-    Ok(web::Json(resp))
-}
-
-#[get("/heater/{id}")]
-async fn heater_status(id: web::Path<String>, heaters: web::Data<Vec<Heater>>) -> impl Responder {
-    for h in heaters.iter() {
-        if h.get_room() == id.to_string() {
-            return web::Json(h.get_status().await.unwrap());
+    {
+        let con_info = req.connection_info();
+        for data in readings.iter() {
+            info!("{},{},{}", con_info.host(), data.timestamp, data);
         }
     }
-    web::Json(ShellyStatus::default())
-}
-
-#[get("/heater/{id}/on")]
-async fn heater_on(id: web::Path<String>, heaters: web::Data<Vec<Heater>>) -> impl Responder {
-    for h in heaters.iter() {
-        if h.get_room() == id.to_string() {
-            let r = h.turn_on().await.map_err(|e| error!("{}", e));
-            info!("{:?}", r);
-            return web::Json(h.get_status().await.unwrap());
+    devices.latest().pushed(&readings);
+    if let Some(storage) = storage {
+        let storage = storage.into_inner();
+        let result = storage::blocking(&storage, move |s| s.add_readings(&readings)).await;
+        if let Err(e) = result {
+            error!("Could not store pushed readings, {}", e);
+            return HttpResponse::InternalServerError().finish();
         }
     }
-    web::Json(ShellyStatus::default())
+    HttpResponse::NoContent().finish()
 }
 
-#[get("/heater/{id}/off")]
-async fn heater_off(id: web::Path<String>, heaters: web::Data<Vec<Heater>>) -> impl Responder {
-    for h in heaters.iter() {
-        if h.get_room() == id.to_string() {
-            let r = h.turn_off().await.map_err(|e| error!("{}", e));
-            info!("{:?}", r);
-            return web::Json(h.get_status().await.unwrap());
-        }
-    }
-    web::Json(ShellyStatus::default())
+/// Every route of the aggregator
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(collect)
+        .service(read)
+        .service(heaters::heater_status)
+        .service(heaters::set_heater_state)
+        .service(heaters::heater_on)
+        .service(heaters::heater_off)
+        .service(ingest)
+        .service(live::live)
+        .service(alerts::list_alerts)
+        .service(devices::list_devices)
+        .service(devices::device_health)
+        .service(devices::add_collector)
+        .service(devices::update_collector)
+        .service(devices::remove_collector)
+        .service(devices::add_heater)
+        .service(devices::update_heater)
+        .service(devices::remove_heater)
+        .service(history::history)
+        .service(storage::stored_readings)
+        .service(storage::stored_heater_samples)
+        .service(thermostat::list_thermostats)
+        .service(thermostat::get_thermostat)
+        .service(thermostat::update_thermostat)
+        .service(thermostat::get_schedule)
+        .service(thermostat::update_schedule)
+        .service(thermostat::remove_schedule)
+        .service(thermostat::set_override)
+        .service(thermostat::remove_override)
+        .service(dashboard::dashboard)
+        .service(dashboard::dashboard_asset)
+        .service(metrics::metrics)
+        .service(openapi::openapi);
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let opt = Opt::from_args();
 
+    match opt.command {
+        Some(Command::Token(command)) => {
+            if let Err(e) = auth::run(command, &opt.config) {
+                eprintln!("{}: {}", opt.config.display(), e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Cert(command)) => {
+            if let Err(e) = certs::run(command) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let config = match Config::from_file(&opt.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {}", opt.config.display(), e);
+            std::process::exit(1);
+        }
+    };
+
     CombinedLogger::init(vec![
         TermLogger::new(
             LevelFilter::Off,
-            Config::default(),
+            simplelog::Config::default(),
             TerminalMode::Mixed,
             ColorChoice::Auto,
         ),
         WriteLogger::new(
-            LevelFilter::Info,
-            Config::default(),
-            File::create(opt.log_file).unwrap(),
+            config.log_level,
+            simplelog::Config::default(),
+            File::create(opt.log_file.as_ref().unwrap_or(&config.log_file)).unwrap(),
         ),
     ])
     .unwrap();
 
-    let client_builder = reqwest::ClientBuilder::new().timeout(std::time::Duration::from_secs(5));
-    let client = client_builder.build().unwrap();
-
-    HttpServer::new(move || {
-        // They should be outside i know due to every thread getting copy instead of reference
-        let collectors: Vec<Collector> =
-            MyCollector::from_json(&PathBuf::from(opt.collectors.clone()))
-                .iter()
-                .map(|my_collector| {
-                    Collector::new(
-                        my_collector.get_room().to_string(),
-                        my_collector.get_url().to_string(),
-                    )
-                })
-                .collect();
-
-        let my_heater: Vec<Heater> = vec![Heater::new(
-            "192.168.0.101".to_string(),
-            "bedroom".to_string(),
-        )];
-
-        App::new()
-            .service(collect)
-            .service(read)
-            .service(heater_status)
-            .service(heater_on)
-            .service(heater_off)
-            .app_data(web::Data::new(collectors))
-            .app_data(web::Data::new(my_heater))
-            .app_data(web::Data::new(client.clone()))
-    })
-    .bind("0.0.0.0:65535")
-    .unwrap()
-    .run()
-    .await
+    let timeout = Duration::from_secs(config.timeout);
+    let mut client_builder = reqwest::ClientBuilder::new().timeout(timeout);
+    if let Some(collector_tls) = &config.collector_tls {
+        client_builder = match tls::collector_client(client_builder, collector_tls) {
+            Ok(client_builder) => client_builder,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+    let client = web::Data::new(client_builder.build().unwrap());
+    let tokens = Arc::new(Tokens::new(&config));
+    let readings = web::Data::new(Readings::new(Duration::from_secs(config.cache_max_age)));
+    let feed = web::Data::new(Feed::new(Duration::from_secs(config.live_interval)));
+    let alerts = web::Data::new(Alerts::new(
+        config.alerts.clone(),
+        Duration::from_secs(config.alert_interval),
+    ));
+    let bind = config.bind;
+    let tls_config = config.tls.clone();
+    let storage_config = config.storage.clone();
+    let thermostats = web::Data::new(Thermostats::new(
+        &config.thermostats,
+        config.modes,
+        config.timezone,
+        Models::load(PathBuf::from(&config.model_file)),
+    ));
+    let notify_config = config.notify.clone();
+    let devices = web::Data::new(Devices::new(config, opt.config.clone()));
+    if !notify_config.is_empty() {
+        // Webhooks go anywhere, not only to collectors trusting the house CA
+        let notify_client = reqwest::ClientBuilder::new()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+        match notify::Notifier::new(notify_config, notify_client) {
+            Ok((notifier, worker)) => {
+                actix_web::rt::spawn(worker);
+                devices.notify_with(notifier);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    thermostats.start(
+        devices.clone(),
+        client.clone(),
+        readings.clone(),
+        feed.clone(),
+    );
+    actix_web::rt::spawn(live::poll(
+        feed.clone(),
+        devices.clone(),
+        client.clone(),
+        readings.clone(),
+    ));
+    actix_web::rt::spawn(alerts::poll(
+        alerts.clone(),
+        devices.clone(),
+        client.clone(),
+        readings.clone(),
+    ));
+
+    let storage: Option<Arc<dyn Storage>> = match &storage_config {
+        Some(storage_config) => {
+            let storage = match storage_config.backend {
+                config::StorageBackend::Sqlite => {
+                    storage::Sqlite::open(Path::new(&storage_config.path))
+                }
+            };
+            let storage: Arc<dyn Storage> = match storage {
+                Ok(storage) => Arc::new(storage),
+                Err(e) => {
+                    eprintln!("Could not open {}: {}", storage_config.path, e);
+                    std::process::exit(1);
+                }
+            };
+            actix_web::rt::spawn(storage::poll(
+                storage.clone(),
+                devices.clone(),
+                client.clone(),
+                readings.clone(),
+                Duration::from_secs(storage_config.interval),
+                storage_config.retention,
+            ));
+            Some(storage)
+        }
+        None => None,
+    };
+
+    let http_metrics = Arc::new(HttpMetrics::default());
+    let server = HttpServer::new(move || {
+        let app = match &storage {
+            Some(storage) => App::new().app_data(web::Data::from(storage.clone())),
+            None => App::new(),
+        };
+        app.wrap(Auth::new(tokens.clone()))
+            .wrap(Measure::new(http_metrics.clone()))
+            .configure(routes)
+            .app_data(devices.clone())
+            .app_data(thermostats.clone())
+            .app_data(client.clone())
+            .app_data(readings.clone())
+            .app_data(feed.clone())
+            .app_data(alerts.clone())
+            .app_data(web::Data::from(http_metrics.clone()))
+    });
+
+    match tls_config {
+        Some(tls_config) => {
+            let certificates = match tls::Certificates::load(
+                PathBuf::from(&tls_config.cert),
+                PathBuf::from(&tls_config.key),
+            ) {
+                Ok(certificates) => Arc::new(certificates),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            actix_web::rt::spawn(tls::reload_on_hangup(certificates.clone()));
+            if let Some(redirect) = tls_config.redirect {
+                actix_web::rt::spawn(tls::redirect_server(redirect, bind.port())?);
+            }
+            server
+                .bind_rustls(bind, certificates.server_config())?
+                .run()
+                .await
+        }
+        None => server.bind(bind)?.run().await,
+    }
 }
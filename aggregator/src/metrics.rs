@@ -0,0 +1,508 @@
+use crate::config::CollectorConfig;
+use crate::devices::Devices;
+use crate::status::FetchError;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{get, web, Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use util::EnvData;
+
+/// Upper bounds of the buckets of the request durations, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The last read of a collector
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scrape {
+    pub room: String,
+    pub success: bool,
+    pub latency: Duration,
+    /// Seconds since the unix epoch of the last read that got through
+    pub last_success: Option<u64>,
+    /// Newest reading that got through
+    pub reading: Option<EnvData>,
+}
+
+/// What the collectors last answered and what was pushed, so `/metrics` never reads a device
+#[derive(Default)]
+pub struct Latest {
+    /// By collector id
+    scrapes: Mutex<HashMap<String, Scrape>>,
+    /// Pushed readings, by room
+    pushed: Mutex<HashMap<String, EnvData>>,
+}
+
+impl Latest {
+    pub fn scraped(
+        &self,
+        collector: &CollectorConfig,
+        result: &Result<EnvData, FetchError>,
+        latency: Duration,
+    ) {
+        let mut scrapes = self.scrapes.lock().unwrap();
+        let scrape = scrapes
+            .entry(collector.id.clone())
+            .or_insert_with(|| Scrape {
+                room: collector.room.clone(),
+                success: false,
+                latency,
+                last_success: None,
+                reading: None,
+            });
+        scrape.success = result.is_ok();
+        scrape.latency = latency;
+        if let Ok(data) = result {
+            let timestamp = if data.timestamp > 0 {
+                data.timestamp
+            } else {
+                util::now()
+            };
+            scrape.last_success = Some(timestamp);
+            scrape.reading = Some(EnvData {
+                room: collector.room.clone(),
+                timestamp,
+                ..data.clone()
+            });
+        }
+    }
+
+    pub fn pushed(&self, readings: &[EnvData]) {
+        let mut pushed = self.pushed.lock().unwrap();
+        for data in readings {
+            let newer = pushed
+                .get(&data.room)
+                .is_none_or(|p| p.timestamp <= data.timestamp);
+            if newer {
+                pushed.insert(data.room.clone(), data.clone());
+            }
+        }
+    }
+
+    /// Drops a collector that is gone or changed
+    pub fn forget(&self, collector: &str) {
+        self.scrapes.lock().unwrap().remove(collector);
+    }
+
+    fn scrapes(&self) -> BTreeMap<String, Scrape> {
+        self.scrapes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, s)| (id.clone(), s.clone()))
+            .collect()
+    }
+
+    /// The newest reading of each room, pulled or pushed
+    fn rooms(&self) -> BTreeMap<String, EnvData> {
+        let mut rooms: BTreeMap<String, EnvData> = BTreeMap::new();
+        let scraped = self.scrapes.lock().unwrap();
+        let pushed = self.pushed.lock().unwrap();
+        let readings = scraped.values().filter_map(|s| s.reading.as_ref());
+        for data in readings.chain(pushed.values()) {
+            let newer = rooms
+                .get(&data.room)
+                .is_none_or(|r| r.timestamp <= data.timestamp);
+            if newer {
+                rooms.insert(data.room.clone(), data.clone());
+            }
+        }
+        rooms
+    }
+}
+
+/// Requests handled by one route
+#[derive(Default)]
+struct RouteRequests {
+    /// By status code
+    counts: BTreeMap<u16, u64>,
+    /// Count in each of `BUCKETS`, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    seconds: f64,
+}
+
+/// The requests the aggregator has answered, by method and route
+#[derive(Default)]
+pub struct HttpMetrics {
+    routes: Mutex<BTreeMap<(String, String), RouteRequests>>,
+}
+
+impl HttpMetrics {
+    fn observe(&self, method: &str, route: &str, status: u16, took: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        let requests = routes
+            .entry((method.to_string(), route.to_string()))
+            .or_default();
+        *requests.counts.entry(status).or_default() += 1;
+        let seconds = took.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| seconds <= *b) {
+            requests.buckets[i] += 1;
+        }
+        requests.count += 1;
+        requests.seconds += seconds;
+    }
+}
+
+/// Middleware counting the requests and how long they took
+pub struct Measure {
+    http: Arc<HttpMetrics>,
+}
+
+impl Measure {
+    pub fn new(http: Arc<HttpMetrics>) -> Self {
+        Self { http }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Measure
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = MeasureMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MeasureMiddleware {
+            service: Rc::new(service),
+            http: self.http.clone(),
+        }))
+    }
+}
+
+pub struct MeasureMiddleware<S> {
+    service: Rc<S>,
+    http: Arc<HttpMetrics>,
+}
+
+impl<S, B> Service<ServiceRequest> for MeasureMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let service = self.service.clone();
+        let http = self.http.clone();
+        Box::pin(async move {
+            let res = service.call(req).await?;
+            // The pattern and not the path, so ids do not make new series
+            let route = res
+                .request()
+                .match_pattern()
+                .unwrap_or_else(|| "unmatched".to_string());
+            let status = res.status().as_u16();
+            http.observe(&method, &route, status, started.elapsed());
+            Ok(res)
+        })
+    }
+}
+
+/// A value of the heater status, as a sample
+type HeaterValue = fn(&util::ShellyStatus) -> f64;
+
+/// Text in the Prometheus exposition format
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| {
+                    let v = v
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    format!("{}=\"{}\"", k, v)
+                })
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn flag(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// The metrics of the house and the aggregator, from what the devices last answered
+async fn render(devices: &Devices, http: &HttpMetrics) -> String {
+    let mut out = Exposition(String::new());
+    let latest = devices.latest();
+
+    let rooms = latest.rooms();
+    out.family(
+        "hevn_room_temperature_celsius",
+        "gauge",
+        "Newest temperature read in the room",
+    );
+    for (room, data) in &rooms {
+        let value = data.temperature as f64 / 10.0;
+        out.sample("hevn_room_temperature_celsius", &[("room", room)], value);
+    }
+    out.family(
+        "hevn_room_humidity_percent",
+        "gauge",
+        "Newest relative humidity read in the room",
+    );
+    for (room, data) in &rooms {
+        let value = data.humidity as f64 / 10.0;
+        out.sample("hevn_room_humidity_percent", &[("room", room)], value);
+    }
+    out.family(
+        "hevn_room_reading_timestamp_seconds",
+        "gauge",
+        "When the newest reading of the room was taken",
+    );
+    for (room, data) in &rooms {
+        let value = data.timestamp as f64;
+        out.sample(
+            "hevn_room_reading_timestamp_seconds",
+            &[("room", room)],
+            value,
+        );
+    }
+
+    let scrapes = latest.scrapes();
+    out.family(
+        "hevn_collector_up",
+        "gauge",
+        "Whether the last read of the collector got through",
+    );
+    for (id, s) in &scrapes {
+        let labels = [("collector", id.as_str()), ("room", s.room.as_str())];
+        out.sample("hevn_collector_up", &labels, flag(s.success));
+    }
+    out.family(
+        "hevn_collector_scrape_duration_seconds",
+        "gauge",
+        "How long the last read of the collector took",
+    );
+    for (id, s) in &scrapes {
+        let labels = [("collector", id.as_str()), ("room", s.room.as_str())];
+        let value = s.latency.as_secs_f64();
+        out.sample("hevn_collector_scrape_duration_seconds", &labels, value);
+    }
+    out.family(
+        "hevn_collector_last_success_timestamp_seconds",
+        "gauge",
+        "When a read of the collector last got through",
+    );
+    for (id, s) in &scrapes {
+        if let Some(at) = s.last_success {
+            let labels = [("collector", id.as_str()), ("room", s.room.as_str())];
+            out.sample(
+                "hevn_collector_last_success_timestamp_seconds",
+                &labels,
+                at as f64,
+            );
+        }
+    }
+
+    let mut heaters: Vec<_> = devices
+        .heaters()
+        .await
+        .into_iter()
+        .filter_map(|h| h.last_status().map(|s| (h, s)))
+        .collect();
+    heaters.sort_by(|(a, _), (b, _)| a.get_id().cmp(b.get_id()));
+    let heater_families: [(&str, &str, HeaterValue); 4] = [
+        ("hevn_heater_on", "Whether the heater is on", |s| {
+            flag(s.is_on)
+        }),
+        ("hevn_heater_power_watts", "Power the heater draws", |s| {
+            s.power as f64
+        }),
+        (
+            "hevn_heater_temperature_celsius",
+            "Temperature inside the heater relay",
+            |s| s.temperature as f64,
+        ),
+        (
+            "hevn_heater_overpower",
+            "Whether the relay turned off for drawing too much power",
+            |s| flag(s.overpower),
+        ),
+    ];
+    for (name, help, value) in heater_families {
+        out.family(name, "gauge", help);
+        for (heater, status) in &heaters {
+            let labels = [("heater", heater.get_id()), ("room", heater.get_room())];
+            out.sample(name, &labels, value(status));
+        }
+    }
+
+    let routes = http.routes.lock().unwrap();
+    out.family(
+        "hevn_http_requests_total",
+        "counter",
+        "Requests answered by the aggregator",
+    );
+    for ((method, route), requests) in routes.iter() {
+        for (status, count) in &requests.counts {
+            let status = status.to_string();
+            let labels = [
+                ("method", method.as_str()),
+                ("route", route.as_str()),
+                ("status", status.as_str()),
+            ];
+            out.sample("hevn_http_requests_total", &labels, *count as f64);
+        }
+    }
+    out.family(
+        "hevn_http_request_duration_seconds",
+        "histogram",
+        "How long the aggregator took to answer",
+    );
+    for ((method, route), requests) in routes.iter() {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(requests.buckets) {
+            cumulative += count;
+            let bound = bound.to_string();
+            let labels = [
+                ("method", method.as_str()),
+                ("route", route.as_str()),
+                ("le", bound.as_str()),
+            ];
+            out.sample(
+                "hevn_http_request_duration_seconds_bucket",
+                &labels,
+                cumulative as f64,
+            );
+        }
+        let labels = [("method", method.as_str()), ("route", route.as_str())];
+        let inf = [labels[0], labels[1], ("le", "+Inf")];
+        let count = requests.count as f64;
+        out.sample("hevn_http_request_duration_seconds_bucket", &inf, count);
+        out.sample(
+            "hevn_http_request_duration_seconds_sum",
+            &labels,
+            requests.seconds,
+        );
+        out.sample("hevn_http_request_duration_seconds_count", &labels, count);
+    }
+    out.0
+}
+
+/// Metrics for Prometheus, from cached values only
+#[get("/metrics")]
+async fn metrics(devices: web::Data<Devices>, http: web::Data<HttpMetrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(render(&devices, &http).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::status::ErrorKind;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use std::path::PathBuf;
+
+    fn reading(room: &str, temperature: i16, timestamp: u64) -> EnvData {
+        EnvData {
+            room: room.to_string(),
+            temperature,
+            humidity: 455,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn is_newest_reading_kept() {
+        let config = Config::parse(
+            "[[collector]]\nroom = \"Bedroom\"\nurl = \"http://127.0.0.1:1\"\n\
+             [[collector]]\nroom = \"Kitchen\"\nurl = \"http://127.0.0.1:2\"\n",
+        )
+        .unwrap();
+        let latest = Latest::default();
+        let ms = Duration::from_millis(12);
+        latest.scraped(&config.collectors[0], &Ok(reading("bed", 210, 1000)), ms);
+        let down = FetchError {
+            kind: ErrorKind::Unreachable,
+            message: "refused".to_string(),
+        };
+        latest.scraped(&config.collectors[0], &Err(down), ms);
+        latest.pushed(&[reading("Kitchen", 190, 900), reading("Bedroom", 205, 900)]);
+
+        let rooms = latest.rooms();
+        assert_eq!(rooms["Bedroom"], reading("Bedroom", 210, 1000));
+        assert_eq!(rooms["Kitchen"].temperature, 190);
+        let scrapes = latest.scrapes();
+        assert!(!scrapes["bedroom"].success);
+        assert_eq!(scrapes["bedroom"].last_success, Some(1000));
+    }
+
+    #[actix_web::test]
+    async fn is_exposed_without_reading_devices() {
+        let config = Config::parse(
+            "[[collector]]\nroom = \"Bedroom\"\nurl = \"http://127.0.0.1:1\"\n\
+             [[heater]]\nroom = \"Bedroom\"\naddress = \"127.0.0.1:1\"\n",
+        )
+        .unwrap();
+        let collector = config.collectors[0].clone();
+        let devices = web::Data::new(Devices::new(config, PathBuf::new()));
+        devices.latest().scraped(
+            &collector,
+            &Ok(reading("Bedroom", 215, 1000)),
+            Duration::from_millis(250),
+        );
+        let http = Arc::new(HttpMetrics::default());
+        let app = init_service(
+            App::new()
+                .wrap(Measure::new(http.clone()))
+                .app_data(web::Data::from(http))
+                .app_data(devices.clone())
+                .service(metrics),
+        )
+        .await;
+
+        let missing = TestRequest::get().uri("/nothing").to_request();
+        call_service(&app, missing).await;
+        let resp = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        for line in [
+            "hevn_room_temperature_celsius{room=\"Bedroom\"} 21.5",
+            "hevn_room_humidity_percent{room=\"Bedroom\"} 45.5",
+            "hevn_collector_up{collector=\"bedroom\",room=\"Bedroom\"} 1",
+            "hevn_collector_scrape_duration_seconds{collector=\"bedroom\",room=\"Bedroom\"} 0.25",
+            "hevn_http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"} 1",
+            "hevn_http_request_duration_seconds_count{method=\"GET\",route=\"unmatched\"} 1",
+        ] {
+            assert!(lines.contains(&line), "{} not in\n{}", line, body);
+        }
+        // The heater was never read, so it is left out instead of being read now
+        assert!(!body.contains("heater=\"bedroom\""), "{}", body);
+        assert_eq!(devices.heater("bedroom").await.unwrap().last_status(), None);
+    }
+}
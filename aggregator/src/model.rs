@@ -0,0 +1,206 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+use util::openapi::{integer, number, object, Schema};
+
+/// Samples needed of both heating and cooling before the model is used
+const MIN_SAMPLES: u32 = 3;
+
+/// How a room warms and cools, as Newton's law of cooling with a heater:
+/// dT/dt = heating_rate * on - cooling * (T - baseline)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ThermalModel {
+    /// °C per hour the heater adds
+    pub heating_rate: f32,
+    /// Share of the difference to the baseline lost per hour
+    pub cooling: f32,
+    pub heating_samples: u32,
+    pub cooling_samples: u32,
+}
+
+impl Default for ThermalModel {
+    fn default() -> Self {
+        Self {
+            heating_rate: 1.0,
+            cooling: 0.1,
+            heating_samples: 0,
+            cooling_samples: 0,
+        }
+    }
+}
+
+impl Schema for ThermalModel {
+    const NAME: &'static str = "ThermalModel";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("heating_rate", number()),
+                ("cooling", number()),
+                ("heating_samples", integer()),
+                ("cooling_samples", integer()),
+            ],
+            &[],
+        )
+    }
+}
+
+/// Weight of a new sample, the first ones count more so the defaults are left quickly
+fn weight(samples: u32) -> f32 {
+    (1.0 / (samples as f32 + 1.0)).max(0.2)
+}
+
+impl ThermalModel {
+    pub fn is_ready(&self) -> bool {
+        self.heating_samples >= MIN_SAMPLES && self.cooling_samples >= MIN_SAMPLES
+    }
+
+    /// Learns from the temperature going from `start` to `end` over `hours`
+    /// with the heater on or off the whole time
+    pub fn learn(&mut self, start: f32, end: f32, hours: f32, heater_on: bool, baseline: f32) {
+        if hours <= 0.0 {
+            return;
+        }
+        if !heater_on {
+            // Too close to the baseline the readings are mostly noise
+            if start - baseline < 2.0 || end - baseline <= 0.0 {
+                return;
+            }
+            let cooling = ((start - baseline) / (end - baseline)).ln() / hours;
+            if !(0.0..=2.0).contains(&cooling) {
+                return;
+            }
+            let w = weight(self.cooling_samples);
+            self.cooling += w * (cooling - self.cooling);
+            self.cooling_samples += 1;
+        } else {
+            let decay = (-self.cooling * hours).exp();
+            let heating_rate = if decay < 1.0 {
+                let equilibrium = (end - start * decay) / (1.0 - decay);
+                self.cooling * (equilibrium - baseline)
+            } else {
+                (end - start) / hours
+            };
+            if !(0.0..=20.0).contains(&heating_rate) {
+                return;
+            }
+            let w = weight(self.heating_samples);
+            self.heating_rate += w * (heating_rate - self.heating_rate);
+            self.heating_samples += 1;
+        }
+    }
+
+    /// Hours of heating needed to go from `from` to `to`, none if it can not be reached
+    pub fn time_to_reach(&self, from: f32, to: f32, baseline: f32) -> Option<f32> {
+        if from >= to {
+            return Some(0.0);
+        }
+        if self.cooling <= 0.0 {
+            return Some((to - from) / self.heating_rate);
+        }
+        let equilibrium = baseline + self.heating_rate / self.cooling;
+        if to >= equilibrium {
+            return None;
+        }
+        Some(((from - equilibrium) / (to - equilibrium)).ln() / self.cooling)
+    }
+}
+
+/// The models of all the rooms, kept in a file so they survive restarts
+pub struct Models {
+    path: PathBuf,
+    models: Mutex<HashMap<String, ThermalModel>>,
+}
+
+impl Models {
+    pub fn load(path: PathBuf) -> Self {
+        let models = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                error!("Could not read {}, {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path,
+            models: Mutex::new(models),
+        }
+    }
+
+    pub async fn get(&self, room: &str) -> ThermalModel {
+        self.models
+            .lock()
+            .await
+            .get(room)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub async fn set(&self, room: &str, model: ThermalModel) {
+        let mut models = self.models.lock().await;
+        models.insert(room.to_string(), model);
+        if let Err(e) = self.save(&models) {
+            error!("Could not save {}, {}", self.path.display(), e);
+        }
+    }
+
+    fn save(
+        &self,
+        models: &HashMap<String, ThermalModel>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(models)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Temperature after `hours` in a room with the given heating rate and cooling
+    fn simulate(start: f32, hours: f32, on: bool, rate: f32, cooling: f32, baseline: f32) -> f32 {
+        let equilibrium = baseline + if on { rate / cooling } else { 0.0 };
+        equilibrium + (start - equilibrium) * (-cooling * hours).exp()
+    }
+
+    #[test]
+    fn is_learned() {
+        let mut model = ThermalModel::default();
+        let mut t = 20.0;
+        for _ in 0..20 {
+            let end = simulate(t, 0.5, false, 2.0, 0.25, 5.0);
+            model.learn(t, end, 0.5, false, 5.0);
+            t = end;
+            let end = simulate(t, 0.5, true, 2.0, 0.25, 5.0);
+            model.learn(t, end, 0.5, true, 5.0);
+            t = end;
+        }
+        assert!(model.is_ready());
+        assert!((model.cooling - 0.25).abs() < 0.01, "{:?}", model);
+        assert!((model.heating_rate - 2.0).abs() < 0.05, "{:?}", model);
+    }
+
+    #[test]
+    fn is_time_to_reach() {
+        let model = ThermalModel {
+            heating_rate: 2.0,
+            cooling: 0.25,
+            heating_samples: 3,
+            cooling_samples: 3,
+        };
+        let hours = model.time_to_reach(18.0, 20.0, 15.0).unwrap();
+        let reached = simulate(18.0, hours, true, 2.0, 0.25, 15.0);
+        assert!((reached - 20.0).abs() < 0.01);
+
+        assert_eq!(model.time_to_reach(21.0, 20.0, 15.0), Some(0.0));
+        // The heater can only hold 15 + 2 / 0.25 = 23 °C
+        assert_eq!(model.time_to_reach(18.0, 24.0, 15.0), None);
+    }
+}
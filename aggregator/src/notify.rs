@@ -0,0 +1,491 @@
+use crate::config::{EmailConfig, EventKind, NotifyConfig, SmtpSecurity};
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{debug, error, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Something worth telling someone about
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub event: EventKind,
+    pub room: String,
+    /// Id of the heater or collector
+    pub device: String,
+    pub message: String,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl Notification {
+    pub fn new(event: EventKind, room: &str, device: &str, message: String) -> Self {
+        Self {
+            event,
+            room: room.to_string(),
+            device: device.to_string(),
+            message,
+            timestamp: util::now(),
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "event" => Some(self.event.to_string()),
+            "room" => Some(self.room.clone()),
+            "device" => Some(self.device.clone()),
+            "message" => Some(self.message.clone()),
+            "timestamp" => Some(self.timestamp.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Fills in the `{{field}}`s of the template, unknown fields are left as they are
+pub fn render(template: &str, notification: &Notification) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        rendered.push_str(&rest[..start]);
+        match notification.field(rest[start + 2..end].trim()) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Fills in every string of a JSON template
+fn render_json(template: &Value, notification: &Notification) -> Value {
+    match template {
+        Value::String(s) => Value::String(render(s, notification)),
+        Value::Array(values) => values
+            .iter()
+            .map(|v| render_json(v, notification))
+            .collect(),
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(k, v)| (k.clone(), render_json(v, notification)))
+            .collect(),
+        other => other.clone(),
+    }
+}
+
+fn mailer(email: &EmailConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+    let builder = match email.security {
+        SmtpSecurity::None => {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&email.server)
+        }
+        SmtpSecurity::Starttls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&email.server)
+                .map_err(|e| e.to_string())?
+        }
+        SmtpSecurity::Tls => {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&email.server).map_err(|e| e.to_string())?
+        }
+    };
+    let builder = match (&email.username, &email.password) {
+        (Some(username), Some(password)) => {
+            builder.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => builder,
+    };
+    Ok(builder.port(email.port).build())
+}
+
+/// One place notifications are sent to
+struct Channel {
+    config: NotifyConfig,
+    mailer: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    /// When each event and device was last sent
+    last: Mutex<HashMap<(EventKind, String), u64>>,
+}
+
+impl Channel {
+    fn new(config: NotifyConfig) -> Result<Self, String> {
+        let mailer = match &config.email {
+            Some(email) => {
+                Some(mailer(email).map_err(|e| format!("notify {}: {}", config.name, e))?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            config,
+            mailer,
+            last: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether the notification goes out here, remembering it when it does
+    fn takes(&self, notification: &Notification) -> bool {
+        if !self.config.is_routed(notification.event) {
+            return false;
+        }
+        let mut last = self.last.lock().unwrap();
+        let key = (notification.event, notification.device.clone());
+        let now = notification.timestamp;
+        match last.get(&key) {
+            Some(at) if now.saturating_sub(*at) < self.config.rate_limit.0 => {
+                debug!(
+                    "Not sending {} for {} to {}, one was sent {} seconds ago",
+                    notification.event,
+                    notification.device,
+                    self.config.name,
+                    now.saturating_sub(*at)
+                );
+                false
+            }
+            _ => {
+                last.insert(key, now);
+                true
+            }
+        }
+    }
+
+    async fn send_once(
+        &self,
+        client: &reqwest::Client,
+        notification: &Notification,
+    ) -> Result<(), String> {
+        if let Some(webhook) = &self.config.webhook {
+            let body = match &webhook.body {
+                Some(template) => render_json(template, notification),
+                None => serde_json::to_value(notification).map_err(|e| e.to_string())?,
+            };
+            let mut request = client.post(&webhook.url).json(&body);
+            for (name, value) in &webhook.headers {
+                request = request.header(name, render(value, notification));
+            }
+            request
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?;
+        }
+        if let (Some(email), Some(mailer)) = (&self.config.email, &self.mailer) {
+            let mut message = Message::builder()
+                .from(email.from.parse().map_err(|e| format!("{}", e))?)
+                .subject(render(&email.subject, notification))
+                .header(ContentType::TEXT_PLAIN);
+            for to in &email.to {
+                message = message.to(to.parse().map_err(|e| format!("{}", e))?);
+            }
+            let text = format!(
+                "{}\n\nEvent: {}\nRoom: {}\nDevice: {}\nAt: {}\n",
+                notification.message,
+                notification.event,
+                notification.room,
+                notification.device,
+                chrono::DateTime::from_timestamp(notification.timestamp as i64, 0)
+                    .map_or(notification.timestamp.to_string(), |t| t.to_rfc2822()),
+            );
+            let message = message.body(text).map_err(|e| e.to_string())?;
+            mailer.send(message).await.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Sends the notification, trying again `retries` times with a doubling backoff
+    async fn send(&self, client: &reqwest::Client, notification: &Notification) {
+        let mut backoff = Duration::from_secs(self.config.backoff.0);
+        for attempt in 0..=self.config.retries {
+            match self.send_once(client, notification).await {
+                Ok(()) => return,
+                Err(e) if attempt < self.config.retries => {
+                    warn!(
+                        "Could not send {} to {}, trying again in {} seconds: {}",
+                        notification.event,
+                        self.config.name,
+                        backoff.as_secs(),
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => error!(
+                    "Could not send {} to {}, giving up: {}",
+                    notification.event, self.config.name, e
+                ),
+            }
+        }
+    }
+}
+
+/// Hands notifications to the channels in the background, cheap to clone
+#[derive(Clone)]
+pub struct Notifier {
+    sender: mpsc::UnboundedSender<Notification>,
+}
+
+impl Notifier {
+    /// The notifier and the task sending for it, which must be spawned
+    pub fn new(
+        channels: Vec<NotifyConfig>,
+        client: reqwest::Client,
+    ) -> Result<(Self, impl Future<Output = ()>), String> {
+        let channels = channels
+            .into_iter()
+            .map(|c| Channel::new(c).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok((Self { sender }, run(channels, client, receiver)))
+    }
+
+    pub fn notify(&self, notification: Notification) {
+        let _ = self.sender.send(notification);
+    }
+}
+
+async fn run(
+    channels: Vec<Arc<Channel>>,
+    client: reqwest::Client,
+    mut receiver: mpsc::UnboundedReceiver<Notification>,
+) {
+    while let Some(notification) = receiver.recv().await {
+        for channel in &channels {
+            if channel.takes(&notification) {
+                let channel = channel.clone();
+                let client = client.clone();
+                let notification = notification.clone();
+                // A slow channel does not hold up the others
+                actix_web::rt::spawn(async move { channel.send(&client, &notification).await });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn notification(event: EventKind, device: &str, timestamp: u64) -> Notification {
+        Notification {
+            event,
+            room: "Bedroom".to_string(),
+            device: device.to_string(),
+            message: "Bedroom is at 4.0 °C, below 5.0 °C".to_string(),
+            timestamp,
+        }
+    }
+
+    fn channels(config: &str) -> Vec<NotifyConfig> {
+        Config::parse(config).unwrap().notify
+    }
+
+    #[test]
+    fn is_template_rendered() {
+        let n = notification(EventKind::AlertFiring, "bedroom", 1700000000);
+        assert_eq!(
+            render("{{ event }} in {{room}}: {{message}} {{unknown}} {{", &n),
+            "alert_firing in Bedroom: Bedroom is at 4.0 °C, below 5.0 °C {{unknown}} {{"
+        );
+        let body = serde_json::json!({"text": "{{message}}", "at": ["{{timestamp}}", 1]});
+        assert_eq!(
+            render_json(&body, &n),
+            serde_json::json!({"text": "Bedroom is at 4.0 °C, below 5.0 °C", "at": ["1700000000", 1]})
+        );
+    }
+
+    #[test]
+    fn is_routed_and_rate_limited() {
+        let config = channels(
+            r#"
+[[notify]]
+name = "phone"
+events = ["alert_firing", "collector_offline"]
+rate_limit = "10m"
+webhook = { url = "http://127.0.0.1:1" }
+"#,
+        );
+        let channel = Channel::new(config[0].clone()).unwrap();
+        assert!(!channel.takes(&notification(EventKind::HeaterSwitched, "bedroom", 1000)));
+        assert!(channel.takes(&notification(EventKind::AlertFiring, "bedroom", 1000)));
+        assert!(!channel.takes(&notification(EventKind::AlertFiring, "bedroom", 1500)));
+        assert!(channel.takes(&notification(EventKind::AlertFiring, "kitchen", 1500)));
+        assert!(channel.takes(&notification(EventKind::CollectorOffline, "bedroom", 1500)));
+        assert!(channel.takes(&notification(EventKind::AlertFiring, "bedroom", 1600)));
+    }
+
+    #[actix_web::test]
+    async fn is_webhook_retried() {
+        let (sender, mut received) = mpsc::unbounded_channel::<(Option<String>, Value)>();
+        let calls = web::Data::new(Mutex::new(0u32));
+        let server = HttpServer::new(move || {
+            let sender = sender.clone();
+            App::new().app_data(calls.clone()).route(
+                "/hook",
+                web::post().to(
+                    move |req: actix_web::HttpRequest,
+                          body: web::Json<Value>,
+                          calls: web::Data<Mutex<u32>>| {
+                        let sender = sender.clone();
+                        async move {
+                            let mut calls = calls.lock().unwrap();
+                            *calls += 1;
+                            if *calls == 1 {
+                                return HttpResponse::InternalServerError().finish();
+                            }
+                            let token = req
+                                .headers()
+                                .get("X-Token")
+                                .map(|t| t.to_str().unwrap().to_string());
+                            sender.send((token, body.into_inner())).unwrap();
+                            HttpResponse::Ok().finish()
+                        }
+                    },
+                ),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let port = server.addrs()[0].port();
+        actix_web::rt::spawn(server.run());
+
+        let config = channels(&format!(
+            r#"
+[[notify]]
+name = "sink"
+backoff = 0
+[notify.webhook]
+url = "http://127.0.0.1:{}/hook"
+headers = {{ X-Token = "secret" }}
+body = {{ title = "hevn {{{{event}}}}", text = "{{{{message}}}}", room = "{{{{room}}}}" }}
+"#,
+            port
+        ));
+        let (notifier, worker) = Notifier::new(config, reqwest::Client::new()).unwrap();
+        actix_web::rt::spawn(worker);
+        notifier.notify(notification(EventKind::AlertFiring, "bedroom", 1000));
+
+        let (token, body) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.as_deref(), Some("secret"));
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "title": "hevn alert_firing",
+                "text": "Bedroom is at 4.0 °C, below 5.0 °C",
+                "room": "Bedroom",
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn is_offline_collector_told() {
+        let config =
+            Config::parse("[[collector]]\nroom = \"Bedroom\"\nurl = \"http://127.0.0.1:1\"\n")
+                .unwrap();
+        let devices = crate::devices::Devices::new(config, std::path::PathBuf::new());
+        let (sender, mut received) = mpsc::unbounded_channel();
+        devices.notify_with(Notifier { sender });
+
+        let collector = devices.collector("bedroom").await.unwrap();
+        let client = reqwest::Client::new();
+        for _ in 0..4 {
+            let _ = devices.read_collector(&collector, "data", &client).await;
+        }
+        let notification = received.try_recv().unwrap();
+        assert_eq!(notification.event, EventKind::CollectorOffline);
+        assert_eq!(
+            notification.message,
+            "Collector bedroom in Bedroom stopped answering"
+        );
+        assert!(received.try_recv().is_err());
+    }
+
+    /// Takes one mail the way an SMTP server would and gives back what came after DATA
+    async fn smtp_sink(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+        let mut data = String::new();
+        let mut in_data = false;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if in_data {
+                if line == "." {
+                    in_data = false;
+                    writer.write_all(b"250 OK\r\n").await.unwrap();
+                } else {
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+                continue;
+            }
+            let command = line.to_uppercase();
+            let reply: &[u8] = if command.starts_with("EHLO") {
+                b"250 sink\r\n"
+            } else if command.starts_with("DATA") {
+                in_data = true;
+                b"354 Go ahead\r\n"
+            } else if command.starts_with("QUIT") {
+                writer.write_all(b"221 Bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 OK\r\n"
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+        data
+    }
+
+    #[actix_web::test]
+    async fn is_mail_sent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = actix_web::rt::spawn(smtp_sink(listener));
+
+        let config = channels(&format!(
+            r#"
+[[notify]]
+name = "mail"
+events = ["collector_offline"]
+[notify.email]
+server = "127.0.0.1"
+port = {}
+security = "none"
+from = "hevn <hevn@house.local>"
+to = ["me@house.local"]
+subject = "hevn: {{{{device}}}} is offline"
+"#,
+            port
+        ));
+        let (notifier, worker) = Notifier::new(config, reqwest::Client::new()).unwrap();
+        actix_web::rt::spawn(worker);
+        notifier.notify(notification(
+            EventKind::CollectorOffline,
+            "bedroom",
+            1700000000,
+        ));
+
+        let data = tokio::time::timeout(Duration::from_secs(5), sink)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            data.contains("Subject: hevn: bedroom is offline"),
+            "{}",
+            data
+        );
+        assert!(data.contains("To: me@house.local"), "{}", data);
+        assert!(data.contains("Event: collector_offline"), "{}", data);
+    }
+}
@@ -0,0 +1,505 @@
+use crate::alerts::{Alert, AlertState};
+use crate::breaker::State;
+use crate::config::{CollectorConfig, DeviceType, HeaterConfig, Mode};
+use crate::devices::{DeviceHealth, DeviceList, HealthList};
+use crate::error::ErrorBody;
+use crate::heaters::HeaterState;
+use crate::history::{History, Point, Summary};
+use crate::model::ThermalModel;
+use crate::schedule::{Block, Override};
+use crate::status::{CollectorStatus, ErrorKind, FetchError};
+use crate::storage::HeaterSample;
+use crate::thermostat::{Decision, OverrideRequest, ThermostatStatus, ThermostatUpdate};
+use actix_web::{get, web, Responder};
+use serde_json::{json, Value};
+use util::openapi::{any, any_of, array, integer, reference, string, string_enum, Operation, Spec};
+use util::{EnvData, ShellyStatus};
+
+fn read(summary: &str) -> Operation {
+    Operation::new(summary)
+        .query(
+            "format",
+            "array gives the readings, status every collector with why it could not be read",
+            string_enum(&["array", "status"]),
+        )
+        .query(
+            "max_age",
+            "Seconds a cached reading may be, Cache-Control: max-age works as well",
+            integer(),
+        )
+        .json(
+            200,
+            "The readings",
+            any_of(vec![
+                array(reference::<EnvData>()),
+                array(reference::<CollectorStatus>()),
+            ]),
+        )
+}
+
+/// A heater request, answered with the status of the heater
+fn heater(summary: &str) -> Operation {
+    let error = reference::<ErrorBody>();
+    Operation::new(summary)
+        .json(200, "The status of the heater", reference::<ShellyStatus>())
+        .json(404, "No heater with that id", error.clone())
+        .json(502, "The heater answered with an error", error.clone())
+        .json(503, "The heater is down and not contacted", error.clone())
+        .json(504, "The heater did not answer in time", error)
+}
+
+/// A change to the devices, saved to the config file
+fn device_change(summary: &str) -> Operation {
+    let error = reference::<ErrorBody>();
+    Operation::new(summary)
+        .json(400, "A field is not right", error.clone())
+        .json(404, "No device with that id", error.clone())
+        .json(
+            409,
+            "The id is taken, or the device is used by a thermostat",
+            error.clone(),
+        )
+        .json(500, "The config could not be saved", error)
+}
+
+/// A thermostat request, answered with the thermostat
+fn thermostat(summary: &str) -> Operation {
+    Operation::new(summary)
+        .json(200, "The thermostat", reference::<ThermostatStatus>())
+        .json(404, "No thermostat in that room", reference::<ErrorBody>())
+}
+
+/// A change to a thermostat, saved to the config file
+fn thermostat_change(summary: &str) -> Operation {
+    let error = reference::<ErrorBody>();
+    thermostat(summary)
+        .json(400, "A field is not right", error.clone())
+        .json(500, "The config could not be saved", error)
+}
+
+/// A request for stored data
+fn stored(summary: &str, description: &str, schema: Value) -> Operation {
+    Operation::new(summary)
+        .query(
+            "from",
+            "Seconds since the unix epoch, the start by default",
+            integer(),
+        )
+        .query(
+            "to",
+            "Seconds since the unix epoch, now by default",
+            integer(),
+        )
+        .json(200, description, schema)
+        .json(400, "A parameter is not right", reference::<ErrorBody>())
+        .json(404, "Storage is not enabled", reference::<ErrorBody>())
+        .json(
+            500,
+            "The storage could not be read",
+            reference::<ErrorBody>(),
+        )
+}
+
+/// The OpenAPI document of the aggregator
+pub fn document() -> Value {
+    let new_collector = json!({"room": "Attic", "url": "http://127.0.0.1:1"});
+    let new_heater = json!({"room": "Attic", "address": "127.0.0.1:1"});
+    let mut document = Spec::new("hevn aggregator", env!("CARGO_PKG_VERSION"))
+        .schema::<EnvData>()
+        .schema::<ShellyStatus>()
+        .schema::<ErrorKind>()
+        .schema::<FetchError>()
+        .schema::<CollectorStatus>()
+        .schema::<ErrorBody>()
+        .schema::<HeaterState>()
+        .schema::<State>()
+        .schema::<DeviceHealth>()
+        .schema::<HealthList>()
+        .schema::<DeviceType>()
+        .schema::<CollectorConfig>()
+        .schema::<HeaterConfig>()
+        .schema::<DeviceList>()
+        .schema::<Summary>()
+        .schema::<Point>()
+        .schema::<History>()
+        .schema::<HeaterSample>()
+        .schema::<Mode>()
+        .schema::<Block>()
+        .schema::<Override>()
+        .schema::<ThermalModel>()
+        .schema::<Decision>()
+        .schema::<ThermostatStatus>()
+        .schema::<ThermostatUpdate>()
+        .schema::<OverrideRequest>()
+        .schema::<AlertState>()
+        .schema::<Alert>()
+        .route("GET", "/", read("Reads every collector"))
+        .route(
+            "GET",
+            "/read",
+            read("Reads every collector without filtering the readings"),
+        )
+        .route("GET", "/heater/{id}", heater("Status of the heater"))
+        .route(
+            "PUT",
+            "/heater/{id}",
+            heater("Turns the heater on or off")
+                .body(reference::<HeaterState>(), json!({"is_on": true})),
+        )
+        .route("POST", "/heater/{id}/on", heater("Turns the heater on"))
+        .route("POST", "/heater/{id}/off", heater("Turns the heater off"))
+        .route(
+            "GET",
+            "/heater/{id}/samples",
+            stored(
+                "Every stored sample of the heater",
+                "The samples, oldest first",
+                array(reference::<HeaterSample>()),
+            ),
+        )
+        .route(
+            "POST",
+            "/ingest",
+            Operation::new("Readings pushed by the collectors")
+                .body(
+                    array(reference::<EnvData>()),
+                    json!([{"room": "Bedroom", "temperature": 215, "humidity": 400, "timestamp": 1700000000}]),
+                )
+                .empty(204, "The readings are taken")
+                .text(400, "The readings are not right")
+                .empty(500, "The readings could not be stored"),
+        )
+        .route(
+            "GET",
+            "/live",
+            Operation::new("Readings, heater switches and thermostat decisions as they happen")
+                .query("room", "Rooms to follow, separated by commas, every room by default", string())
+                .query("token", "The token, as browsers cannot send headers on WebSockets", string())
+                .empty(101, "Switches to a WebSocket sending the events as JSON")
+                .text(400, "Not a WebSocket handshake"),
+        )
+        .route(
+            "GET",
+            "/alerts",
+            Operation::new("The alerts, firing ones first")
+                .query("state", "Only the alerts in this state", reference::<AlertState>())
+                .json(200, "The alerts", array(reference::<Alert>()))
+                .text(400, "Not a state"),
+        )
+        .route(
+            "GET",
+            "/devices",
+            Operation::new("The collectors and heaters, without passwords").json(
+                200,
+                "The devices",
+                reference::<DeviceList>(),
+            ),
+        )
+        .route(
+            "GET",
+            "/devices/health",
+            Operation::new("Whether each device is taken as up or down").json(
+                200,
+                "The devices",
+                reference::<HealthList>(),
+            ),
+        )
+        .route(
+            "POST",
+            "/devices/collectors",
+            device_change("Adds a collector once it answers")
+                .body(reference::<CollectorConfig>(), new_collector.clone())
+                .json(201, "The collector", reference::<CollectorConfig>())
+                .json(502, "The collector could not be reached", reference::<ErrorBody>()),
+        )
+        .route(
+            "PUT",
+            "/devices/collectors/{id}",
+            device_change("Replaces a collector once it answers")
+                .body(reference::<CollectorConfig>(), new_collector)
+                .json(200, "The collector", reference::<CollectorConfig>())
+                .json(502, "The collector could not be reached", reference::<ErrorBody>()),
+        )
+        .route(
+            "DELETE",
+            "/devices/collectors/{id}",
+            device_change("Removes a collector").empty(204, "The collector is removed"),
+        )
+        .route(
+            "POST",
+            "/devices/heaters",
+            device_change("Adds a heater once it answers")
+                .body(reference::<HeaterConfig>(), new_heater.clone())
+                .json(201, "The heater, without its password", reference::<HeaterConfig>())
+                .json(502, "The heater could not be reached", reference::<ErrorBody>()),
+        )
+        .route(
+            "PUT",
+            "/devices/heaters/{id}",
+            device_change("Replaces a heater once it answers, the password is kept when left out")
+                .body(reference::<HeaterConfig>(), new_heater)
+                .json(200, "The heater, without its password", reference::<HeaterConfig>())
+                .json(502, "The heater could not be reached", reference::<ErrorBody>()),
+        )
+        .route(
+            "DELETE",
+            "/devices/heaters/{id}",
+            device_change("Removes a heater").empty(204, "The heater is removed"),
+        )
+        .route(
+            "GET",
+            "/history",
+            stored(
+                "Downsampled readings of one or more rooms",
+                "The rooms, with a point for every step",
+                reference::<History>(),
+            )
+            .query("room", "Rooms, given several times or separated by commas", string())
+            .query("step", "Seconds in each point, about 200 points by default", integer()),
+        )
+        .route(
+            "GET",
+            "/readings",
+            stored(
+                "Every stored reading of a room",
+                "The readings, oldest first",
+                array(reference::<EnvData>()),
+            )
+            .query("room", "The room", string()),
+        )
+        .route(
+            "GET",
+            "/thermostats",
+            Operation::new("Every thermostat").json(
+                200,
+                "The thermostats",
+                array(reference::<ThermostatStatus>()),
+            ),
+        )
+        .route("GET", "/thermostats/{room}", thermostat("The thermostat of the room"))
+        .route(
+            "PUT",
+            "/thermostats/{room}",
+            thermostat_change("Changes the setpoint or mode, fields left out are kept")
+                .body(reference::<ThermostatUpdate>(), json!({"setpoint": 21.0})),
+        )
+        .route(
+            "GET",
+            "/thermostats/{room}/schedule",
+            Operation::new("The weekly schedule of the thermostat")
+                .json(200, "The blocks", array(reference::<Block>()))
+                .json(404, "No thermostat in that room", reference::<ErrorBody>()),
+        )
+        .route(
+            "PUT",
+            "/thermostats/{room}/schedule",
+            Operation::new("Replaces the weekly schedule")
+                .body(
+                    array(reference::<Block>()),
+                    json!([{"days": ["Sat", "Sun"], "start": "08:00", "setpoint": 21.0}]),
+                )
+                .json(200, "The blocks", array(reference::<Block>()))
+                .json(400, "A block is not right", reference::<ErrorBody>())
+                .json(404, "No thermostat in that room", reference::<ErrorBody>())
+                .json(500, "The config could not be saved", reference::<ErrorBody>()),
+        )
+        .route(
+            "DELETE",
+            "/thermostats/{room}/schedule",
+            thermostat_change("Removes the schedule, the setpoint is used instead"),
+        )
+        .route(
+            "PUT",
+            "/thermostats/{room}/override",
+            thermostat_change("Holds a setpoint for `duration` seconds or until the next block")
+                .body(
+                    reference::<OverrideRequest>(),
+                    json!({"setpoint": 23.0, "duration": 3600}),
+                ),
+        )
+        .route(
+            "DELETE",
+            "/thermostats/{room}/override",
+            thermostat_change("Goes back to the schedule"),
+        )
+        .route(
+            "GET",
+            "/dashboard",
+            Operation::new("The dashboard").media(200, "The page", "text/html"),
+        )
+        .route(
+            "GET",
+            "/dashboard/{name}",
+            Operation::new("A script or style sheet of the dashboard")
+                .media(200, "The file", "text/javascript")
+                .text(404, "No such file"),
+        )
+        .route(
+            "GET",
+            "/metrics",
+            Operation::new("Metrics for Prometheus, from what the devices last answered")
+                .text(200, "The metrics in the Prometheus text format"),
+        )
+        .route(
+            "GET",
+            "/openapi.json",
+            Operation::new("This document").json(200, "The document", any()),
+        )
+        .build();
+
+    document["components"]["securitySchemes"] = json!({
+        "token": { "type": "http", "scheme": "bearer" },
+    });
+    document["security"] = json!([{ "token": [] }, {}]);
+    document
+}
+
+#[get("/openapi.json")]
+async fn openapi() -> impl Responder {
+    web::Json(document())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::Alerts;
+    use crate::config::Config;
+    use crate::devices::Devices;
+    use crate::live::Feed;
+    use crate::metrics::HttpMetrics;
+    use crate::model::Models;
+    use crate::status::Readings;
+    use crate::storage::{Sqlite, Storage};
+    use crate::thermostat::Thermostats;
+    use actix_web::dev::Service;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{init_service, read_body, TestRequest};
+    use actix_web::{App, HttpRequest, HttpResponse};
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use util::openapi::{check_response, operations};
+
+    const METHODS: [Method; 4] = [Method::GET, Method::POST, Method::PUT, Method::DELETE];
+
+    /// Answers the requests no route took, with the patterns of every route.
+    /// actix does not list the routes, but they are in the debug output of the resource map
+    async fn unrouted(req: HttpRequest) -> HttpResponse {
+        let map = format!("{:?}", req.resource_map());
+        let patterns: Vec<&str> = map
+            .split("patterns: Single(\"")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+        HttpResponse::build(StatusCode::IM_A_TEAPOT).json(patterns)
+    }
+
+    #[actix_web::test]
+    async fn is_document_matching() {
+        let dir = std::env::temp_dir().join(format!("hevn-openapi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("aggregator.toml");
+        std::fs::write(
+            &path,
+            r#"
+[[collector]]
+room = "Bedroom"
+url = "http://127.0.0.1:1"
+
+[[heater]]
+room = "Bedroom"
+address = "127.0.0.1:1"
+
+[[thermostat]]
+room = "Bedroom"
+collector = "bedroom"
+heater = "bedroom"
+setpoint = 20.0
+"#,
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap();
+        let thermostats = Thermostats::new(
+            &config.thermostats,
+            config.modes,
+            config.timezone,
+            Models::load(dir.join("models.json")),
+        );
+        let storage: Arc<dyn Storage> = Arc::new(Sqlite::open(&dir.join("hevn.db")).unwrap());
+        let app = init_service(
+            App::new()
+                .configure(crate::routes)
+                .app_data(web::Data::from(storage))
+                .app_data(web::Data::new(Devices::new(config, path)))
+                .app_data(web::Data::new(thermostats))
+                .app_data(web::Data::new(reqwest::Client::new()))
+                .app_data(web::Data::new(Readings::new(Duration::from_secs(5))))
+                .app_data(web::Data::new(Feed::new(Duration::from_secs(5))))
+                .app_data(web::Data::new(Alerts::new(
+                    Vec::new(),
+                    Duration::from_secs(60),
+                )))
+                .app_data(web::Data::new(HttpMetrics::default()))
+                .default_service(web::to(unrouted)),
+        )
+        .await;
+        let call = |method: &Method, uri: &str, body: &Value| {
+            let req = TestRequest::default().method(method.clone()).uri(uri);
+            let req = if body.is_null() {
+                req
+            } else {
+                req.set_json(body)
+            };
+            app.call(req.to_request())
+        };
+
+        // Every route is documented, and every documented route is there
+        let resp = call(&Method::GET, "/nothing", &Value::Null).await.unwrap();
+        let patterns: Vec<String> = serde_json::from_slice(&read_body(resp).await).unwrap();
+        let mut routes = BTreeSet::new();
+        for pattern in patterns {
+            let uri = pattern
+                .split('/')
+                .map(|s| if s.starts_with('{') { "attic" } else { s })
+                .collect::<Vec<_>>()
+                .join("/");
+            for method in &METHODS {
+                let resp = call(method, &uri, &Value::Null).await.unwrap();
+                if resp.status() != StatusCode::IM_A_TEAPOT {
+                    routes.insert((method.to_string(), pattern.clone()));
+                }
+            }
+        }
+        let document = document();
+        let documented: BTreeSet<(String, String)> = operations(&document)
+            .into_iter()
+            .map(|(method, path, _)| (method, path))
+            .collect();
+        assert_eq!(documented, routes);
+
+        for (method, path, operation) in operations(&document) {
+            for id in ["bedroom", "attic"] {
+                let uri = ["{id}", "{room}", "{name}"]
+                    .iter()
+                    .fold(path.clone(), |uri, name| uri.replace(name, id));
+                let example = &operation["requestBody"]["content"]["application/json"]["example"];
+                let method = Method::from_bytes(method.as_bytes()).unwrap();
+                let resp = call(&method, &uri, example).await.unwrap();
+                let status = resp.status().as_u16();
+                let body = read_body(resp).await;
+                check_response(&document, method.as_str(), &path, status, &body)
+                    .unwrap_or_else(|e| panic!("{}, {}", e, String::from_utf8_lossy(&body)));
+            }
+        }
+
+        let resp = call(&Method::GET, "/?format=status", &Value::Null)
+            .await
+            .unwrap();
+        let body = read_body(resp).await;
+        check_response(&document, "GET", "/", 200, &body).unwrap();
+        let status: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status[0]["error"]["kind"], "unreachable");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use util::openapi::{array, integer, number, object, string, string_enum, Schema};
+
+/// A setpoint starting at a time on some days, it lasts until the next block starts
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Block {
+    pub days: Vec<Weekday>,
+    /// Local time, like "06:30"
+    #[serde(with = "hour_minute")]
+    pub start: NaiveTime,
+    /// In °C
+    pub setpoint: f32,
+}
+
+/// A setpoint used instead of the schedule for a while
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+    /// In °C
+    pub setpoint: f32,
+    /// Seconds since the unix epoch
+    pub until: i64,
+}
+
+impl Override {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        now.timestamp() < self.until
+    }
+}
+
+impl Schema for Block {
+    const NAME: &'static str = "Block";
+
+    fn schema() -> serde_json::Value {
+        let day = string_enum(&["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"]);
+        object(
+            &[
+                ("days", array(day)),
+                ("start", string()),
+                ("setpoint", number()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for Override {
+    const NAME: &'static str = "Override";
+
+    fn schema() -> serde_json::Value {
+        object(&[("setpoint", number()), ("until", integer())], &[])
+    }
+}
+
+/// The block in effect at the given time, blocks from last week carry over
+pub fn active(blocks: &[Block], tz: Tz, now: DateTime<Utc>) -> Option<&Block> {
+    let local = now.with_timezone(&tz).naive_local();
+    let minutes = |day: Weekday, time: NaiveTime| {
+        day.num_days_from_monday() as i64 * 24 * 60 + (time - NaiveTime::MIN).num_minutes()
+    };
+    let now = minutes(local.weekday(), local.time());
+
+    // The latest start before now, or the last start of the week if there is none
+    blocks
+        .iter()
+        .flat_map(|b| b.days.iter().map(move |d| (minutes(*d, b.start), b)))
+        .max_by_key(|(start, _)| {
+            if *start <= now {
+                *start
+            } else {
+                *start - 7 * 24 * 60
+            }
+        })
+        .map(|(_, b)| b)
+}
+
+/// When the next block starts after the given time
+pub fn next_start(blocks: &[Block], tz: Tz, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    next(blocks, tz, now).map(|(start, _)| start)
+}
+
+/// The next block to start after the given time, and when
+pub fn next(blocks: &[Block], tz: Tz, now: DateTime<Utc>) -> Option<(DateTime<Utc>, &Block)> {
+    let today = now.with_timezone(&tz).date_naive();
+    (0..=7)
+        .map(|days| today + Duration::days(days))
+        .flat_map(|date| {
+            blocks
+                .iter()
+                .filter(move |b| b.days.contains(&date.weekday()))
+                .map(move |b| (resolve(tz, date.and_time(b.start)), b))
+        })
+        .filter(|(start, _)| *start > now)
+        .min_by_key(|(start, _)| *start)
+}
+
+/// Turns a local time into an instant, times skipped by daylight saving
+/// are moved to when the clock jumps and repeated times use the first one
+fn resolve(tz: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    let mut local = local;
+    loop {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(t) => return t.with_timezone(&Utc),
+            LocalResult::Ambiguous(first, _) => return first.with_timezone(&Utc),
+            // Gaps are whole minutes, so step until the clock exists again
+            LocalResult::None => local += Duration::minutes(1),
+        }
+    }
+}
+
+mod hour_minute {
+    use chrono::NaiveTime;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.format("%H:%M").to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&text, "%H:%M")
+            .map_err(|_| serde::de::Error::custom(format!("{} is not a time like 06:30", text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Oslo;
+
+    fn blocks() -> Vec<Block> {
+        let weekend = vec![Weekday::Sat, Weekday::Sun];
+        vec![
+            Block {
+                days: weekend.clone(),
+                start: NaiveTime::from_hms_opt(2, 30, 0).unwrap(),
+                setpoint: 18.0,
+            },
+            Block {
+                days: weekend,
+                start: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+                setpoint: 21.0,
+            },
+            Block {
+                days: vec![Weekday::Mon],
+                start: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+                setpoint: 20.0,
+            },
+        ]
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn is_active_block() {
+        let blocks = blocks();
+        // Saturday 09:00 in Oslo
+        assert_eq!(
+            active(&blocks, Oslo, utc("2026-06-06T07:00:00Z"))
+                .unwrap()
+                .setpoint,
+            21.0
+        );
+        // Monday 05:00 in Oslo is still the weekend block
+        assert_eq!(
+            active(&blocks, Oslo, utc("2026-06-08T03:00:00Z"))
+                .unwrap()
+                .setpoint,
+            21.0
+        );
+        // Monday 07:00 in Oslo
+        assert_eq!(
+            active(&blocks, Oslo, utc("2026-06-08T05:00:00Z"))
+                .unwrap()
+                .setpoint,
+            20.0
+        );
+        assert!(active(&[], Oslo, utc("2026-06-08T05:00:00Z")).is_none());
+    }
+
+    #[test]
+    fn is_right_across_spring_forward() {
+        let blocks = blocks();
+        // 2026-03-29 the clocks in Oslo go from 02:00 to 03:00, so 02:30 never happens
+        let next = next_start(&blocks, Oslo, utc("2026-03-28T23:00:00Z")).unwrap();
+        assert_eq!(next, utc("2026-03-29T01:00:00Z"));
+        // Just after the jump the 02:30 block is the one in effect
+        assert_eq!(
+            active(&blocks, Oslo, utc("2026-03-29T01:00:00Z"))
+                .unwrap()
+                .setpoint,
+            18.0
+        );
+        // 08:00 is now two hours ahead of UTC
+        let next = next_start(&blocks, Oslo, utc("2026-03-29T01:00:00Z")).unwrap();
+        assert_eq!(next, utc("2026-03-29T06:00:00Z"));
+    }
+
+    #[test]
+    fn is_right_across_fall_back() {
+        let blocks = blocks();
+        // 2026-10-25 the clocks in Oslo go from 03:00 back to 02:00, so 02:30 happens twice
+        let next = next_start(&blocks, Oslo, utc("2026-10-24T23:00:00Z")).unwrap();
+        assert_eq!(next, utc("2026-10-25T00:30:00Z"));
+        // The second 02:30 does not start the block again
+        let next = next_start(&blocks, Oslo, utc("2026-10-25T00:30:00Z")).unwrap();
+        assert_eq!(next, utc("2026-10-25T07:00:00Z"));
+    }
+}
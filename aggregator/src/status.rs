@@ -0,0 +1,379 @@
+use crate::breaker::Down;
+use crate::config::CollectorConfig;
+use crate::devices::Devices;
+use futures::future::join_all;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use util::openapi::{self, integer, nullable, object, reference, string, string_enum, Schema};
+use util::EnvData;
+
+/// Why a collector could not be read
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Timeout,
+    Unreachable,
+    BadStatus,
+    InvalidData,
+    /// Not contacted because it has failed too many times
+    Down,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct FetchError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        let kind = if e.is_timeout() {
+            ErrorKind::Timeout
+        } else if e.is_status() {
+            ErrorKind::BadStatus
+        } else if e.is_decode() || e.is_body() {
+            ErrorKind::InvalidData
+        } else {
+            ErrorKind::Unreachable
+        };
+        Self {
+            kind,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<Down> for FetchError {
+    fn from(e: Down) -> Self {
+        Self {
+            kind: ErrorKind::Down,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Reads the data at `url`, telling why when it can not
+pub async fn fetch_env_data(url: &str, client: &reqwest::Client) -> Result<EnvData, FetchError> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.json().await?)
+}
+
+/// The outcome of reading one collector
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CollectorStatus {
+    pub id: String,
+    pub room: String,
+    /// How long the request took, in milliseconds
+    pub latency: u64,
+    /// Whether the reading was fetched for an earlier request
+    pub cached: bool,
+    /// Seconds since the unix epoch of the last reading that got through
+    pub last_success: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<EnvData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<FetchError>,
+}
+
+impl Schema for ErrorKind {
+    const NAME: &'static str = "ErrorKind";
+
+    fn schema() -> serde_json::Value {
+        string_enum(&[
+            "timeout",
+            "unreachable",
+            "bad_status",
+            "invalid_data",
+            "down",
+        ])
+    }
+}
+
+impl Schema for FetchError {
+    const NAME: &'static str = "FetchError";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[("kind", reference::<ErrorKind>()), ("message", string())],
+            &[],
+        )
+    }
+}
+
+impl Schema for CollectorStatus {
+    const NAME: &'static str = "CollectorStatus";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("id", string()),
+                ("room", string()),
+                ("latency", integer()),
+                ("cached", openapi::boolean()),
+                ("last_success", nullable(integer())),
+            ],
+            &[
+                ("data", reference::<EnvData>()),
+                ("error", reference::<FetchError>()),
+            ],
+        )
+    }
+}
+
+/// When each collector was last read, by id
+#[derive(Default)]
+struct LastSuccess {
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl LastSuccess {
+    /// Keeps the newest of the times seen for the collector and returns it
+    fn update(&self, id: &str, timestamp: u64) -> u64 {
+        let mut seen = self.seen.lock().unwrap();
+        let last = seen.entry(id.to_string()).or_insert(timestamp);
+        *last = (*last).max(timestamp);
+        *last
+    }
+
+    fn get(&self, id: &str) -> Option<u64> {
+        self.seen.lock().unwrap().get(id).copied()
+    }
+}
+
+/// How the readings of the collectors are given
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Only the readings that got through
+    #[default]
+    Array,
+    /// Every collector, with its reading or why there is none
+    Status,
+}
+
+#[derive(Deserialize)]
+pub struct ReadQuery {
+    #[serde(default)]
+    pub format: Format,
+    /// Seconds old a reading may be, overrides the config and `Cache-Control`
+    pub max_age: Option<u64>,
+}
+
+/// The max age asked for in a `Cache-Control` header, `no-cache` asks for a fresh reading
+pub fn cache_control_max_age(header: &str) -> Option<u64> {
+    header.split(',').map(str::trim).find_map(|directive| {
+        if directive.eq_ignore_ascii_case("no-cache") {
+            Some(0)
+        } else {
+            directive.strip_prefix("max-age=")?.parse().ok()
+        }
+    })
+}
+
+/// A fetch of one url
+struct Cached {
+    started: Instant,
+    latency: u64,
+    result: Result<EnvData, FetchError>,
+}
+
+/// Reads the collectors, callers asking at the same time share one fetch
+/// and readings are given out again until they are older than the max age
+pub struct Readings {
+    max_age: Duration,
+    cache: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<Cached>>>>>,
+    last_success: LastSuccess,
+}
+
+impl Readings {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            cache: Mutex::new(HashMap::new()),
+            last_success: LastSuccess::default(),
+        }
+    }
+
+    /// The reading at `path` on the collector and how long it took to fetch,
+    /// waits for the fetch in flight instead of starting another one
+    pub async fn fetch(
+        &self,
+        devices: &Devices,
+        collector: &CollectorConfig,
+        path: &str,
+        client: &reqwest::Client,
+        max_age: Duration,
+    ) -> (Result<EnvData, FetchError>, u64, bool) {
+        let asked = Instant::now();
+        let slot = self
+            .cache
+            .lock()
+            .unwrap()
+            .entry(format!("{}/{}", collector.sensor_url(), path))
+            .or_default()
+            .clone();
+        let mut slot = slot.lock().await;
+        if let Some(cached) = slot.as_ref() {
+            if asked.saturating_duration_since(cached.started) <= max_age {
+                return (cached.result.clone(), cached.latency, true);
+            }
+        }
+
+        let started = Instant::now();
+        let result = devices.read_collector(collector, path, client).await;
+        let latency = started.elapsed().as_millis() as u64;
+        *slot = Some(Cached {
+            started,
+            latency,
+            result: result.clone(),
+        });
+        (result, latency, false)
+    }
+
+    /// Reads `path` on every collector at the same time,
+    /// readings up to `max_age` seconds old are used when it is given
+    pub async fn read_all(
+        &self,
+        devices: &Devices,
+        client: &reqwest::Client,
+        path: &str,
+        max_age: Option<u64>,
+    ) -> Vec<CollectorStatus> {
+        let max_age = max_age.map_or(self.max_age, Duration::from_secs);
+        let collectors = devices.collector_configs().await;
+        let results = join_all(
+            collectors
+                .iter()
+                .map(|collector| self.fetch(devices, collector, path, client, max_age)),
+        )
+        .await;
+
+        let mut statuses = Vec::new();
+        for (collector, (result, latency, cached)) in collectors.into_iter().zip(results) {
+            let (data, error, last) = match result {
+                Ok(data) => {
+                    let timestamp = if data.timestamp > 0 {
+                        data.timestamp
+                    } else {
+                        util::now()
+                    };
+                    let last = self.last_success.update(&collector.id, timestamp);
+                    (Some(data), None, Some(last))
+                }
+                Err(e) => {
+                    if !cached && e.kind != ErrorKind::Down {
+                        error!("Could not read collector {}, {}", collector.id, e);
+                    }
+                    (None, Some(e), self.last_success.get(&collector.id))
+                }
+            };
+            statuses.push(CollectorStatus {
+                id: collector.id,
+                room: collector.room,
+                latency,
+                cached,
+                last_success: last,
+                data,
+                error,
+            });
+        }
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::path::PathBuf;
+
+    #[test]
+    fn is_newest_success_kept() {
+        let last_success = LastSuccess::default();
+        assert_eq!(last_success.get("bedroom"), None);
+        assert_eq!(last_success.update("bedroom", 200), 200);
+        assert_eq!(last_success.update("bedroom", 100), 200);
+        assert_eq!(last_success.get("bedroom"), Some(200));
+        assert_eq!(last_success.get("kitchen"), None);
+    }
+
+    #[tokio::test]
+    async fn is_refused_connection_unreachable() {
+        let client = reqwest::Client::new();
+        let e = fetch_env_data("http://127.0.0.1:1/data", &client)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unreachable);
+    }
+
+    #[tokio::test]
+    async fn is_fetch_reused() {
+        let config = Config::parse(
+            r#"
+[[collector]]
+room = "Bedroom"
+url = "http://127.0.0.1:1"
+"#,
+        )
+        .unwrap();
+        let collector = config.collectors[0].clone();
+        let devices = Devices::new(config, PathBuf::from("unused.toml"));
+        let readings = Readings::new(Duration::from_secs(60));
+        let client = reqwest::Client::new();
+
+        let fetch = |max_age| readings.fetch(&devices, &collector, "data", &client, max_age);
+        let (_, _, cached) = fetch(readings.max_age).await;
+        assert!(!cached);
+        let (result, _, cached) = fetch(readings.max_age).await;
+        assert!(cached);
+        assert_eq!(result.unwrap_err().kind, ErrorKind::Unreachable);
+        let (_, _, cached) = fetch(Duration::ZERO).await;
+        assert!(!cached);
+    }
+
+    #[test]
+    fn is_cache_control_parsed() {
+        assert_eq!(cache_control_max_age("max-age=10"), Some(10));
+        assert_eq!(cache_control_max_age("no-store, max-age=0"), Some(0));
+        assert_eq!(cache_control_max_age("no-cache"), Some(0));
+        assert_eq!(cache_control_max_age("private"), None);
+        assert_eq!(cache_control_max_age("max-age=soon"), None);
+    }
+
+    #[test]
+    fn is_failure_listed() {
+        let status = CollectorStatus {
+            id: "kitchen".to_string(),
+            room: "Kitchen".to_string(),
+            latency: 5000,
+            cached: false,
+            last_success: Some(1700000000),
+            data: None,
+            error: Some(FetchError {
+                kind: ErrorKind::Timeout,
+                message: "operation timed out".to_string(),
+            }),
+        };
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            serde_json::json!({
+                "id": "kitchen",
+                "room": "Kitchen",
+                "latency": 5000,
+                "cached": false,
+                "last_success": 1700000000,
+                "error": {"kind": "timeout", "message": "operation timed out"},
+            })
+        );
+    }
+}
@@ -0,0 +1,529 @@
+use crate::devices::Devices;
+use crate::error::ErrorBody;
+use crate::status::Readings;
+use actix_web::{get, http::StatusCode, web, HttpResponse, ResponseError};
+use log::{error, info};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use util::openapi::{boolean, integer, number, object, string, Schema};
+use util::EnvData;
+
+/// A reading of the state of a heater
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeaterSample {
+    pub heater: String,
+    pub room: String,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub is_on: bool,
+    /// In watts
+    pub power: f32,
+}
+
+impl Schema for HeaterSample {
+    const NAME: &'static str = "HeaterSample";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("heater", string()),
+                ("room", string()),
+                ("timestamp", integer()),
+                ("is_on", boolean()),
+                ("power", number()),
+            ],
+            &[],
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct StorageError {
+    message: String,
+}
+
+impl std::error::Error for StorageError {}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for StorageError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: "storage",
+            message: self.to_string(),
+        })
+    }
+}
+
+/// The answer when the readings are not stored
+pub fn not_enabled() -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorBody {
+        error: "not_enabled",
+        message: "Storage is not enabled".to_string(),
+    })
+}
+
+/// The answer to a query that is not right
+pub fn invalid_query(message: impl ToString) -> HttpResponse {
+    HttpResponse::BadRequest().json(ErrorBody {
+        error: "invalid",
+        message: message.to_string(),
+    })
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self {
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Summary of the readings of a room in a time span
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    /// Seconds since the unix epoch
+    pub start: u64,
+    pub count: u64,
+    /// Min, sum and max, in tenths like the readings
+    pub temperature: (i64, i64, i64),
+    pub humidity: (i64, i64, i64),
+}
+
+/// Somewhere to keep readings over time
+pub trait Storage: Send + Sync {
+    fn add_readings(&self, readings: &[EnvData]) -> Result<(), StorageError>;
+
+    fn add_heater_samples(&self, samples: &[HeaterSample]) -> Result<(), StorageError>;
+
+    /// Readings of a room from `from` up to but not including `to`, oldest first
+    fn readings(&self, room: &str, from: u64, to: u64) -> Result<Vec<EnvData>, StorageError>;
+
+    /// Samples of a heater from `from` up to but not including `to`, oldest first
+    fn heater_samples(
+        &self,
+        heater: &str,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<HeaterSample>, StorageError>;
+
+    /// Removes everything older than `timestamp`, returns how much was removed
+    fn remove_before(&self, timestamp: u64) -> Result<usize, StorageError>;
+
+    /// The readings of a room in buckets of `step` seconds from `from`,
+    /// oldest first and only the buckets with readings
+    fn buckets(
+        &self,
+        room: &str,
+        from: u64,
+        to: u64,
+        step: u64,
+    ) -> Result<Vec<Bucket>, StorageError> {
+        let mut buckets: Vec<Bucket> = Vec::new();
+        for r in self.readings(room, from, to)? {
+            let start = from + (r.timestamp - from) / step * step;
+            let (t, h) = (r.temperature as i64, r.humidity as i64);
+            match buckets.last_mut() {
+                Some(b) if b.start == start => {
+                    b.count += 1;
+                    b.temperature = (
+                        b.temperature.0.min(t),
+                        b.temperature.1 + t,
+                        b.temperature.2.max(t),
+                    );
+                    b.humidity = (b.humidity.0.min(h), b.humidity.1 + h, b.humidity.2.max(h));
+                }
+                _ => buckets.push(Bucket {
+                    start,
+                    count: 1,
+                    temperature: (t, t, t),
+                    humidity: (h, h, h),
+                }),
+            }
+        }
+        Ok(buckets)
+    }
+}
+
+/// Runs a call to the storage on the blocking thread pool
+pub async fn blocking<T, F>(storage: &Arc<dyn Storage>, f: F) -> Result<T, StorageError>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn Storage) -> Result<T, StorageError> + Send + 'static,
+{
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || f(storage.as_ref()))
+        .await
+        .map_err(|e| StorageError {
+            message: e.to_string(),
+        })?
+}
+
+/// Keeps everything in a single SQLite file
+pub struct Sqlite {
+    connection: Mutex<Connection>,
+}
+
+impl Sqlite {
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    fn new(connection: Connection) -> Result<Self, StorageError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS readings (
+                room TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                temperature INTEGER NOT NULL,
+                humidity INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS readings_by_room ON readings (room, timestamp);
+            CREATE TABLE IF NOT EXISTS heaters (
+                heater TEXT NOT NULL,
+                room TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                is_on INTEGER NOT NULL,
+                power REAL NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS heaters_by_heater ON heaters (heater, timestamp);",
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic while holding the lock leaves nothing half written, sqlite rolls it back
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for Sqlite {
+    fn add_readings(&self, readings: &[EnvData]) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT INTO readings (room, timestamp, temperature, humidity) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for r in readings {
+                insert.execute(params![r.room, r.timestamp, r.temperature, r.humidity])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn add_heater_samples(&self, samples: &[HeaterSample]) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT INTO heaters (heater, room, timestamp, is_on, power) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for s in samples {
+                insert.execute(params![s.heater, s.room, s.timestamp, s.is_on, s.power])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn readings(&self, room: &str, from: u64, to: u64) -> Result<Vec<EnvData>, StorageError> {
+        let connection = self.connection();
+        let mut select = connection.prepare_cached(
+            "SELECT room, timestamp, temperature, humidity FROM readings
+            WHERE room = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY timestamp",
+        )?;
+        let rows = select.query_map(params![room, from, to], |row| {
+            Ok(EnvData {
+                room: row.get(0)?,
+                timestamp: row.get(1)?,
+                temperature: row.get(2)?,
+                humidity: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn heater_samples(
+        &self,
+        heater: &str,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<HeaterSample>, StorageError> {
+        let connection = self.connection();
+        let mut select = connection.prepare_cached(
+            "SELECT heater, room, timestamp, is_on, power FROM heaters
+            WHERE heater = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY timestamp",
+        )?;
+        let rows = select.query_map(params![heater, from, to], |row| {
+            Ok(HeaterSample {
+                heater: row.get(0)?,
+                room: row.get(1)?,
+                timestamp: row.get(2)?,
+                is_on: row.get(3)?,
+                power: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn buckets(
+        &self,
+        room: &str,
+        from: u64,
+        to: u64,
+        step: u64,
+    ) -> Result<Vec<Bucket>, StorageError> {
+        let connection = self.connection();
+        let mut select = connection.prepare_cached(
+            "SELECT (timestamp - ?2) / ?4 AS bucket, COUNT(*),
+                MIN(temperature), SUM(temperature), MAX(temperature),
+                MIN(humidity), SUM(humidity), MAX(humidity)
+            FROM readings WHERE room = ?1 AND timestamp >= ?2 AND timestamp < ?3
+            GROUP BY bucket ORDER BY bucket",
+        )?;
+        let rows = select.query_map(params![room, from, to, step], |row| {
+            Ok(Bucket {
+                start: from + row.get::<_, u64>(0)? * step,
+                count: row.get(1)?,
+                temperature: (row.get(2)?, row.get(3)?, row.get(4)?),
+                humidity: (row.get(5)?, row.get(6)?, row.get(7)?),
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn remove_before(&self, timestamp: u64) -> Result<usize, StorageError> {
+        let connection = self.connection();
+        let readings =
+            connection.execute("DELETE FROM readings WHERE timestamp < ?1", [timestamp])?;
+        let heaters =
+            connection.execute("DELETE FROM heaters WHERE timestamp < ?1", [timestamp])?;
+        Ok(readings + heaters)
+    }
+}
+
+/// Reads every collector and heater each `interval` and stores the results,
+/// dropping whatever is older than `retention` days.
+/// Collectors are read through the same cache as `/`,
+/// with readings up to half an interval old so none is stored twice
+pub async fn poll(
+    storage: Arc<dyn Storage>,
+    devices: web::Data<Devices>,
+    client: web::Data<reqwest::Client>,
+    cache: web::Data<Readings>,
+    interval: Duration,
+    retention: u64,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    let mut last_cleanup = 0;
+    loop {
+        ticks.tick().await;
+
+        let statuses = cache
+            .read_all(&devices, &client, "data", Some(interval.as_secs() / 2))
+            .await;
+        let readings = statuses
+            .into_iter()
+            .filter_map(|status| {
+                let room = status.room;
+                status.data.map(|data| EnvData { room, ..data })
+            })
+            .collect::<Vec<_>>();
+
+        let mut samples = Vec::new();
+        for heater in devices.heaters().await {
+            match heater.get_status().await {
+                Ok(status) => samples.push(HeaterSample {
+                    heater: heater.get_id().to_string(),
+                    room: heater.get_room().to_string(),
+                    timestamp: util::now(),
+                    is_on: status.is_on,
+                    power: status.power,
+                }),
+                Err(e) => error!("Could not read heater {}, {}", heater.get_id(), e),
+            }
+        }
+
+        let result = blocking(&storage, move |s| {
+            s.add_readings(&readings)?;
+            s.add_heater_samples(&samples)
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Could not store readings, {}", e);
+        }
+
+        let now = util::now();
+        if retention > 0 && now - last_cleanup >= 60 * 60 {
+            last_cleanup = now;
+            let before = now.saturating_sub(retention * 24 * 60 * 60);
+            match blocking(&storage, move |s| s.remove_before(before)).await {
+                Ok(removed) => info!("Removed {} stored readings older than {}", removed, before),
+                Err(e) => error!("Could not remove old readings, {}", e),
+            }
+        }
+    }
+}
+
+/// A time range, from the start of the epoch until now by default
+#[derive(Deserialize)]
+struct Range {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+impl Range {
+    fn bounds(&self) -> (u64, u64) {
+        (
+            self.from.unwrap_or(0),
+            self.to.unwrap_or_else(|| util::now() + 1),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct RoomRange {
+    room: String,
+    #[serde(flatten)]
+    range: Range,
+}
+
+/// Every stored reading of a room
+#[get("/readings")]
+async fn stored_readings(
+    storage: Option<web::Data<dyn Storage>>,
+    query: Result<web::Query<RoomRange>, actix_web::Error>,
+) -> Result<HttpResponse, StorageError> {
+    let storage = match storage {
+        Some(storage) => storage.into_inner(),
+        None => return Ok(not_enabled()),
+    };
+    let query = match query {
+        Ok(query) => query,
+        Err(e) => return Ok(invalid_query(e)),
+    };
+    let (from, to) = query.range.bounds();
+    let room = query.into_inner().room;
+    let readings = blocking(&storage, move |s| s.readings(&room, from, to)).await?;
+    Ok(HttpResponse::Ok().json(readings))
+}
+
+/// Every stored sample of a heater
+#[get("/heater/{id}/samples")]
+async fn stored_heater_samples(
+    id: web::Path<String>,
+    storage: Option<web::Data<dyn Storage>>,
+    query: Result<web::Query<Range>, actix_web::Error>,
+) -> Result<HttpResponse, StorageError> {
+    let storage = match storage {
+        Some(storage) => storage.into_inner(),
+        None => return Ok(not_enabled()),
+    };
+    let query = match query {
+        Ok(query) => query,
+        Err(e) => return Ok(invalid_query(e)),
+    };
+    let (from, to) = query.bounds();
+    let samples = blocking(&storage, move |s| s.heater_samples(&id, from, to)).await?;
+    Ok(HttpResponse::Ok().json(samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_stored() {
+        let storage = Sqlite::new(Connection::open_in_memory().unwrap()).unwrap();
+        let reading = |room: &str, timestamp| EnvData {
+            room: room.to_string(),
+            temperature: 215,
+            humidity: 400,
+            timestamp,
+        };
+        storage
+            .add_readings(&[
+                reading("Bedroom", 100),
+                reading("Kitchen", 150),
+                reading("Bedroom", 200),
+                reading("Bedroom", 300),
+            ])
+            .unwrap();
+        storage
+            .add_heater_samples(&[HeaterSample {
+                heater: "bedroom".to_string(),
+                room: "Bedroom".to_string(),
+                timestamp: 120,
+                is_on: true,
+                power: 1200.0,
+            }])
+            .unwrap();
+
+        let buckets = storage.buckets("Bedroom", 50, 400, 100).unwrap();
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(buckets[0].start, 50);
+        assert_eq!(buckets[2].start, 250);
+        assert_eq!(buckets[1].temperature, (215, 215, 215));
+        // The default implementation gives the same buckets
+        struct Plain(Sqlite);
+        impl Storage for Plain {
+            fn add_readings(&self, r: &[EnvData]) -> Result<(), StorageError> {
+                self.0.add_readings(r)
+            }
+            fn add_heater_samples(&self, s: &[HeaterSample]) -> Result<(), StorageError> {
+                self.0.add_heater_samples(s)
+            }
+            fn readings(
+                &self,
+                room: &str,
+                from: u64,
+                to: u64,
+            ) -> Result<Vec<EnvData>, StorageError> {
+                self.0.readings(room, from, to)
+            }
+            fn heater_samples(
+                &self,
+                h: &str,
+                from: u64,
+                to: u64,
+            ) -> Result<Vec<HeaterSample>, StorageError> {
+                self.0.heater_samples(h, from, to)
+            }
+            fn remove_before(&self, timestamp: u64) -> Result<usize, StorageError> {
+                self.0.remove_before(timestamp)
+            }
+        }
+        let plain = Plain(storage);
+        assert_eq!(plain.buckets("Bedroom", 50, 400, 100).unwrap(), buckets);
+        let storage = plain.0;
+
+        let readings = storage.readings("Bedroom", 100, 300).unwrap();
+        assert_eq!(
+            readings,
+            vec![reading("Bedroom", 100), reading("Bedroom", 200)]
+        );
+        assert!(storage.heater_samples("bedroom", 0, 1000).unwrap()[0].is_on);
+
+        assert_eq!(storage.remove_before(200).unwrap(), 3);
+        assert_eq!(storage.readings("Bedroom", 0, 1000).unwrap().len(), 2);
+        assert!(storage
+            .heater_samples("bedroom", 0, 1000)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,843 @@
+use crate::config::{Invalid, Mode, ModesConfig, ThermostatConfig};
+use crate::devices::{DeviceError, Devices};
+use crate::heaters;
+use crate::live::{Event, Feed};
+use crate::model::{Models, ThermalModel};
+use crate::schedule::{self, Block, Override};
+use crate::status::Readings;
+use actix_web::{delete, get, put, web, Responder};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, RwLock};
+use util::openapi::{
+    boolean, integer, nullable, number, object, reference, string, string_enum, Schema,
+};
+
+/// What the thermostat last did and why
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Decision {
+    /// Seconds since the unix epoch
+    pub at: u64,
+    /// Temperature in the room in °C, if it could be read
+    pub temperature: Option<f32>,
+    pub heater_on: bool,
+    /// Whether the heater was switched
+    pub switched: bool,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct ThermostatStatus {
+    room: String,
+    collector: String,
+    heater: String,
+    setpoint: f32,
+    hysteresis: f32,
+    mode: Mode,
+    #[serde(rename = "override")]
+    temporary: Option<Override>,
+    /// The setpoint held right now, none when the heater is kept off
+    target: Option<f32>,
+    /// Where the target comes from
+    source: &'static str,
+    last_decision: Option<Decision>,
+    model: ThermalModel,
+}
+
+/// Changes to a thermostat, fields left out are kept
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThermostatUpdate {
+    setpoint: Option<f32>,
+    mode: Option<Mode>,
+}
+
+/// A temporary setpoint, lasting `duration` seconds or until the next block
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverrideRequest {
+    setpoint: f32,
+    duration: Option<i64>,
+}
+
+impl Schema for Decision {
+    const NAME: &'static str = "Decision";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[
+                ("at", integer()),
+                ("temperature", nullable(number())),
+                ("heater_on", boolean()),
+                ("switched", boolean()),
+                ("reason", string()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for ThermostatStatus {
+    const NAME: &'static str = "Thermostat";
+
+    fn schema() -> serde_json::Value {
+        let source = string_enum(&[
+            "off",
+            "away",
+            "frost_protect",
+            "override",
+            "schedule",
+            "setpoint",
+            "preheat",
+        ]);
+        object(
+            &[
+                ("room", string()),
+                ("collector", string()),
+                ("heater", string()),
+                ("setpoint", number()),
+                ("hysteresis", number()),
+                ("mode", reference::<Mode>()),
+                ("override", nullable(reference::<Override>())),
+                ("target", nullable(number())),
+                ("source", source),
+                ("last_decision", nullable(reference::<Decision>())),
+                ("model", reference::<ThermalModel>()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for ThermostatUpdate {
+    const NAME: &'static str = "ThermostatUpdate";
+
+    fn schema() -> serde_json::Value {
+        object(
+            &[],
+            &[("setpoint", number()), ("mode", reference::<Mode>())],
+        )
+    }
+}
+
+impl Schema for OverrideRequest {
+    const NAME: &'static str = "OverrideRequest";
+
+    fn schema() -> serde_json::Value {
+        object(&[("setpoint", number())], &[("duration", integer())])
+    }
+}
+
+/// The setpoint to hold at the given time and where it comes from
+pub fn target(
+    config: &ThermostatConfig,
+    modes: &ModesConfig,
+    tz: Tz,
+    now: DateTime<Utc>,
+) -> (Option<f32>, &'static str) {
+    match config.mode {
+        Mode::Off => (None, "off"),
+        Mode::Away => (Some(modes.away), "away"),
+        Mode::FrostProtect => (Some(modes.frost_protect), "frost_protect"),
+        Mode::Auto => match &config.temporary {
+            Some(o) if o.is_active(now) => (Some(o.setpoint), "override"),
+            _ => match schedule::active(&config.schedule, tz, now) {
+                Some(block) => (Some(block.setpoint), "schedule"),
+                None => (Some(config.setpoint), "setpoint"),
+            },
+        },
+    }
+}
+
+/// The setpoint of the next block if heating has to start now to reach it on time
+pub fn preheat(
+    config: &ThermostatConfig,
+    model: &ThermalModel,
+    tz: Tz,
+    now: DateTime<Utc>,
+    temperature: f32,
+    baseline: f32,
+) -> Option<f32> {
+    if config.mode != Mode::Auto || config.max_preheat == 0 || !model.is_ready() {
+        return None;
+    }
+    if config.temporary.as_ref().is_some_and(|o| o.is_active(now)) {
+        return None;
+    }
+    let (start, block) = schedule::next(&config.schedule, tz, now)?;
+    let max = config.max_preheat as f32;
+    let lead = model
+        .time_to_reach(temperature, block.setpoint, baseline)
+        .map_or(max, |hours| (hours * 3600.0).min(max));
+    let starts_in = (start - now).num_seconds() as f32;
+    if starts_in <= lead {
+        Some(block.setpoint)
+    } else {
+        None
+    }
+}
+
+/// Whether the heater should be on, and why.
+/// `in_state` is how long the heater has been in its current state.
+pub fn decide(
+    config: &ThermostatConfig,
+    setpoint: Option<f32>,
+    temperature: Option<f32>,
+    heater_on: bool,
+    in_state: Duration,
+) -> (bool, String) {
+    let setpoint = match setpoint {
+        Some(setpoint) => setpoint,
+        None => return (false, "Mode is off".to_string()),
+    };
+    let temperature = match temperature {
+        Some(t) => t,
+        None => return (false, "No reading from the collector".to_string()),
+    };
+
+    let low = setpoint - config.hysteresis;
+    let high = setpoint + config.hysteresis;
+    if !heater_on && temperature < low {
+        if in_state < Duration::from_secs(config.min_off) {
+            (
+                false,
+                format!(
+                    "{} °C is below {} °C, but was turned off recently",
+                    temperature, low
+                ),
+            )
+        } else {
+            (true, format!("{} °C is below {} °C", temperature, low))
+        }
+    } else if heater_on && temperature > high {
+        if in_state < Duration::from_secs(config.min_on) {
+            (
+                true,
+                format!(
+                    "{} °C is above {} °C, but was turned on recently",
+                    temperature, high
+                ),
+            )
+        } else {
+            (false, format!("{} °C is above {} °C", temperature, high))
+        }
+    } else if temperature < low {
+        (true, format!("{} °C is below {} °C", temperature, low))
+    } else if temperature > high {
+        (false, format!("{} °C is above {} °C", temperature, high))
+    } else {
+        (
+            heater_on,
+            format!("{} °C is within {} to {} °C", temperature, low, high),
+        )
+    }
+}
+
+/// Readings with the heater on or off the whole time, to learn the model from
+struct Segment {
+    at: Instant,
+    temperature: f32,
+    heater_on: bool,
+}
+
+/// Shortest segment learned from, shorter ones are mostly noise
+const MIN_SEGMENT: Duration = Duration::from_secs(15 * 60);
+
+struct State {
+    /// Last known state of the heater and when it changed
+    heater_on: Option<(bool, Instant)>,
+    last_decision: Option<Decision>,
+    /// Setpoint of the block being heated up for
+    preheating: Option<f32>,
+    segment: Option<Segment>,
+}
+
+/// Holds a room at its setpoint with the heater
+pub struct Thermostat {
+    config: RwLock<ThermostatConfig>,
+    modes: ModesConfig,
+    timezone: Tz,
+    models: Arc<Models>,
+    state: RwLock<State>,
+    /// Wakes the loop when the config changes
+    changed: Notify,
+}
+
+impl Thermostat {
+    pub fn new(
+        config: ThermostatConfig,
+        modes: ModesConfig,
+        timezone: Tz,
+        models: Arc<Models>,
+    ) -> Self {
+        Self {
+            config: RwLock::new(config),
+            modes,
+            timezone,
+            models,
+            state: RwLock::new(State {
+                heater_on: None,
+                last_decision: None,
+                preheating: None,
+                segment: None,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    async fn status(&self) -> ThermostatStatus {
+        let config = self.config.read().await.clone();
+        let now = Utc::now();
+        let state = self.state.read().await;
+        let model = self.models.get(&config.room).await;
+        let (target, source) = match (
+            target(&config, &self.modes, self.timezone, now),
+            state.preheating,
+        ) {
+            ((Some(t), "schedule" | "setpoint"), Some(p)) if p > t => (Some(p), "preheat"),
+            (target, _) => target,
+        };
+        ThermostatStatus {
+            room: config.room,
+            collector: config.collector,
+            heater: config.heater,
+            setpoint: config.setpoint,
+            hysteresis: config.hysteresis,
+            mode: config.mode,
+            temporary: config.temporary.filter(|o| o.is_active(now)),
+            target,
+            source,
+            last_decision: state.last_decision.clone(),
+            model,
+        }
+    }
+
+    /// Changes the config, saves it and wakes the loop
+    async fn change<F>(&self, devices: &Devices, f: F) -> Result<(), DeviceError>
+    where
+        F: FnOnce(&mut ThermostatConfig) -> Result<(), Invalid>,
+    {
+        let mut config = self.config.write().await;
+        let mut changed = config.clone();
+        f(&mut changed).map_err(DeviceError::Invalid)?;
+        changed.check().map_err(DeviceError::Invalid)?;
+        devices.save_thermostat(changed.clone()).await?;
+        *config = changed;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Reads the room, switches the heater if needed and records the decision
+    async fn step(
+        &self,
+        devices: &Devices,
+        client: &reqwest::Client,
+        readings: &Readings,
+        feed: &Feed,
+    ) {
+        let config = self.config.read().await.clone();
+        let now = Utc::now();
+        let (setpoint, source) = target(&config, &self.modes, self.timezone, now);
+
+        // Readings fetched for `/` or the other pollers are fine while under half a step old
+        let max_age = Duration::from_secs(config.interval) / 2;
+        let read = |id| read_temperature(devices, client, readings, id, max_age);
+        let temperature = read(&config.collector).await;
+        let baseline = match &config.outside {
+            Some(outside) => read(outside).await.unwrap_or(config.baseline),
+            None => config.baseline,
+        };
+        let heater = match devices.heater(&config.heater).await {
+            Some(heater) => heater,
+            None => {
+                error!("Thermostat in {}: no heater {}", config.room, config.heater);
+                return;
+            }
+        };
+        let is_on = match heater.get_status().await {
+            Ok(status) => status.is_on,
+            Err(e) => {
+                error!("Thermostat in {}: {}", config.room, e);
+                return;
+            }
+        };
+
+        let mut state = self.state.write().await;
+        // Starts counting when the heater was switched by someone else,
+        // a heater not seen before may be switched right away
+        let since = match state.heater_on {
+            Some((on, since)) if on == is_on => Some(since),
+            Some(_) => Some(Instant::now()),
+            None => None,
+        };
+        let in_state = since.map_or(Duration::MAX, |s| s.elapsed());
+
+        let mut model = self.models.get(&config.room).await;
+        if let Some(temperature) = temperature {
+            state.segment = match state.segment.take() {
+                Some(segment)
+                    if segment.heater_on == is_on && segment.at.elapsed() < MIN_SEGMENT =>
+                {
+                    Some(segment)
+                }
+                Some(segment) if segment.heater_on == is_on => {
+                    let hours = segment.at.elapsed().as_secs_f32() / 3600.0;
+                    model.learn(segment.temperature, temperature, hours, is_on, baseline);
+                    self.models.set(&config.room, model).await;
+                    None
+                }
+                _ => None,
+            };
+        } else {
+            state.segment = None;
+        }
+
+        state.preheating = match (setpoint, temperature) {
+            (Some(current), Some(temperature)) if source != "override" => {
+                preheat(&config, &model, self.timezone, now, temperature, baseline)
+                    .filter(|p| *p > current)
+            }
+            _ => None,
+        };
+        let (heater_on, mut reason) = decide(
+            &config,
+            state.preheating.or(setpoint),
+            temperature,
+            is_on,
+            in_state,
+        );
+        if state.preheating.is_some() {
+            reason = format!("Heating up for the next block, {}", reason);
+        }
+
+        let switched = heater_on != is_on;
+        if switched {
+            let result = if heater_on {
+                heater.turn_on().await
+            } else {
+                heater.turn_off().await
+            };
+            match result {
+                Ok(r) => {
+                    info!("Thermostat in {}: {}, {}", config.room, r, reason);
+                    feed.publish(Event::heater(&heater, heater_on));
+                    let by = format!("by the thermostat, {}", reason);
+                    devices.notify(heaters::switched(&heater, heater_on, &by));
+                }
+                Err(e) => {
+                    error!("Thermostat in {}: {}", config.room, e);
+                    return;
+                }
+            }
+        }
+        let since = match since {
+            Some(since) if !switched => since,
+            _ => Instant::now(),
+        };
+        state.heater_on = Some((heater_on, since));
+        if state.segment.is_none() || switched {
+            state.segment = temperature.map(|temperature| Segment {
+                at: Instant::now(),
+                temperature,
+                heater_on,
+            });
+        }
+        let decision = Decision {
+            at: util::now(),
+            temperature,
+            heater_on,
+            switched,
+            reason,
+        };
+        feed.publish(Event::Decision {
+            room: config.room.clone(),
+            decision: decision.clone(),
+        });
+        state.last_decision = Some(decision);
+    }
+
+    /// How long to sleep, waking up when the schedule or an override moves the setpoint
+    async fn sleep_time(&self) -> Duration {
+        let config = self.config.read().await;
+        let now = Utc::now();
+        let interval = Duration::from_secs(config.interval);
+        let next_start = schedule::next_start(&config.schedule, self.timezone, now);
+        let until = config
+            .temporary
+            .as_ref()
+            .filter(|o| o.is_active(now))
+            .and_then(|o| DateTime::from_timestamp(o.until, 0));
+        [next_start, until]
+            .into_iter()
+            .flatten()
+            .filter_map(|t| (t - now).to_std().ok())
+            .fold(interval, Duration::min)
+    }
+
+    /// Runs the thermostat until the program stops
+    pub async fn run(
+        self: Arc<Self>,
+        devices: web::Data<Devices>,
+        client: web::Data<reqwest::Client>,
+        readings: web::Data<Readings>,
+        feed: web::Data<Feed>,
+    ) {
+        loop {
+            self.step(&devices, &client, &readings, &feed).await;
+            tokio::select! {
+                _ = tokio::time::sleep(self.sleep_time().await) => (),
+                _ = self.changed.notified() => (),
+            }
+        }
+    }
+}
+
+/// Temperature from the collector with the given id, in °C,
+/// through the same cache as `/`
+async fn read_temperature(
+    devices: &Devices,
+    client: &reqwest::Client,
+    readings: &Readings,
+    id: &str,
+    max_age: Duration,
+) -> Option<f32> {
+    let collector = devices.collector(id).await?;
+    match readings
+        .fetch(devices, &collector, "data", client, max_age)
+        .await
+    {
+        (Ok(data), _, _) => Some(data.temperature as f32 / 10.0),
+        (Err(e), _, cached) => {
+            if !cached {
+                error!("Could not read collector {}, {}", id, e);
+            }
+            None
+        }
+    }
+}
+
+/// All the thermostats, one per room
+pub struct Thermostats {
+    thermostats: Vec<Arc<Thermostat>>,
+}
+
+impl Thermostats {
+    pub fn new(
+        configs: &[ThermostatConfig],
+        modes: ModesConfig,
+        timezone: Tz,
+        models: Models,
+    ) -> Self {
+        let models = Arc::new(models);
+        Self {
+            thermostats: configs
+                .iter()
+                .map(|c| Arc::new(Thermostat::new(c.clone(), modes, timezone, models.clone())))
+                .collect(),
+        }
+    }
+
+    /// Starts a task for each thermostat
+    pub fn start(
+        &self,
+        devices: web::Data<Devices>,
+        client: web::Data<reqwest::Client>,
+        readings: web::Data<Readings>,
+        feed: web::Data<Feed>,
+    ) {
+        for thermostat in &self.thermostats {
+            actix_web::rt::spawn(thermostat.clone().run(
+                devices.clone(),
+                client.clone(),
+                readings.clone(),
+                feed.clone(),
+            ));
+        }
+    }
+
+    async fn find(&self, room: &str) -> Result<&Arc<Thermostat>, DeviceError> {
+        for thermostat in &self.thermostats {
+            if thermostat
+                .config
+                .read()
+                .await
+                .room
+                .eq_ignore_ascii_case(room)
+            {
+                return Ok(thermostat);
+            }
+        }
+        Err(DeviceError::NotFound(room.to_string()))
+    }
+}
+
+#[get("/thermostats")]
+async fn list_thermostats(thermostats: web::Data<Thermostats>) -> impl Responder {
+    let mut resp = Vec::new();
+    for thermostat in &thermostats.thermostats {
+        resp.push(thermostat.status().await);
+    }
+    web::Json(resp)
+}
+
+#[get("/thermostats/{room}")]
+async fn get_thermostat(
+    room: web::Path<String>,
+    thermostats: web::Data<Thermostats>,
+) -> Result<impl Responder, DeviceError> {
+    Ok(web::Json(thermostats.find(&room).await?.status().await))
+}
+
+#[put("/thermostats/{room}")]
+async fn update_thermostat(
+    room: web::Path<String>,
+    devices: web::Data<Devices>,
+    thermostats: web::Data<Thermostats>,
+    update: web::Json<ThermostatUpdate>,
+) -> Result<impl Responder, DeviceError> {
+    let thermostat = thermostats.find(&room).await?;
+    thermostat
+        .change(&devices, |config| {
+            if let Some(setpoint) = update.setpoint {
+                config.setpoint = setpoint;
+            }
+            if let Some(mode) = update.mode {
+                config.mode = mode;
+            }
+            info!(
+                "Thermostat in {} set to {} °C, {:?}",
+                config.room, config.setpoint, config.mode
+            );
+            Ok(())
+        })
+        .await?;
+    Ok(web::Json(thermostat.status().await))
+}
+
+#[get("/thermostats/{room}/schedule")]
+async fn get_schedule(
+    room: web::Path<String>,
+    thermostats: web::Data<Thermostats>,
+) -> Result<impl Responder, DeviceError> {
+    let thermostat = thermostats.find(&room).await?;
+    let schedule = thermostat.config.read().await.schedule.clone();
+    Ok(web::Json(schedule))
+}
+
+#[put("/thermostats/{room}/schedule")]
+async fn update_schedule(
+    room: web::Path<String>,
+    devices: web::Data<Devices>,
+    thermostats: web::Data<Thermostats>,
+    schedule: web::Json<Vec<Block>>,
+) -> Result<impl Responder, DeviceError> {
+    let thermostat = thermostats.find(&room).await?;
+    thermostat
+        .change(&devices, |config| {
+            config.schedule = schedule.into_inner();
+            info!("Thermostat in {} has a new schedule", config.room);
+            Ok(())
+        })
+        .await?;
+    let schedule = thermostat.config.read().await.schedule.clone();
+    Ok(web::Json(schedule))
+}
+
+#[delete("/thermostats/{room}/schedule")]
+async fn remove_schedule(
+    room: web::Path<String>,
+    devices: web::Data<Devices>,
+    thermostats: web::Data<Thermostats>,
+) -> Result<impl Responder, DeviceError> {
+    let thermostat = thermostats.find(&room).await?;
+    thermostat
+        .change(&devices, |config| {
+            config.schedule.clear();
+            info!("Thermostat in {} has no schedule", config.room);
+            Ok(())
+        })
+        .await?;
+    Ok(web::Json(thermostat.status().await))
+}
+
+#[put("/thermostats/{room}/override")]
+async fn set_override(
+    room: web::Path<String>,
+    devices: web::Data<Devices>,
+    thermostats: web::Data<Thermostats>,
+    request: web::Json<OverrideRequest>,
+) -> Result<impl Responder, DeviceError> {
+    let thermostat = thermostats.find(&room).await?;
+    let now = Utc::now();
+    thermostat
+        .change(&devices, |config| {
+            let until = match request.duration {
+                Some(duration) if duration > 0 => now.timestamp() + duration,
+                Some(_) => {
+                    return Err(Invalid {
+                        field: "duration",
+                        message: "must be more than 0",
+                    })
+                }
+                None => schedule::next_start(&config.schedule, thermostat.timezone, now)
+                    .map(|t| t.timestamp())
+                    .ok_or(Invalid {
+                        field: "duration",
+                        message: "is needed when there is no schedule",
+                    })?,
+            };
+            config.temporary = Some(Override {
+                setpoint: request.setpoint,
+                until,
+            });
+            info!(
+                "Thermostat in {} overridden to {} °C until {}",
+                config.room, request.setpoint, until
+            );
+            Ok(())
+        })
+        .await?;
+    Ok(web::Json(thermostat.status().await))
+}
+
+#[delete("/thermostats/{room}/override")]
+async fn remove_override(
+    room: web::Path<String>,
+    devices: web::Data<Devices>,
+    thermostats: web::Data<Thermostats>,
+) -> Result<impl Responder, DeviceError> {
+    let thermostat = thermostats.find(&room).await?;
+    thermostat
+        .change(&devices, |config| {
+            config.temporary = None;
+            info!("Thermostat in {} is back on schedule", config.room);
+            Ok(())
+        })
+        .await?;
+    Ok(web::Json(thermostat.status().await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, Weekday};
+
+    fn config() -> ThermostatConfig {
+        ThermostatConfig {
+            room: "Bedroom".to_string(),
+            collector: "bedroom".to_string(),
+            heater: "bedroom".to_string(),
+            setpoint: 20.0,
+            hysteresis: 0.5,
+            min_on: 300,
+            min_off: 300,
+            interval: 60,
+            mode: Mode::Auto,
+            outside: None,
+            baseline: 10.0,
+            max_preheat: 3 * 60 * 60,
+            temporary: None,
+            schedule: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn is_decided() {
+        let config = config();
+        let long = Duration::from_secs(600);
+        let short = Duration::from_secs(10);
+        let setpoint = Some(20.0);
+
+        assert!(decide(&config, setpoint, Some(19.4), false, long).0);
+        assert!(!decide(&config, setpoint, Some(19.4), false, short).0);
+        assert!(!decide(&config, setpoint, Some(20.6), true, long).0);
+        assert!(decide(&config, setpoint, Some(20.6), true, short).0);
+
+        // Within the hysteresis the heater is left alone
+        assert!(decide(&config, setpoint, Some(20.2), true, long).0);
+        assert!(!decide(&config, setpoint, Some(19.8), false, long).0);
+    }
+
+    #[test]
+    fn is_off_without_reading() {
+        let config = config();
+        assert!(!decide(&config, Some(20.0), None, true, Duration::ZERO).0);
+        assert!(!decide(&config, None, Some(10.0), true, Duration::ZERO).0);
+    }
+
+    #[test]
+    fn is_target() {
+        let mut config = config();
+        let modes = ModesConfig::default();
+        let now = DateTime::from_timestamp(1_780_000_000, 0).unwrap();
+        assert_eq!(
+            target(&config, &modes, Tz::UTC, now),
+            (Some(20.0), "setpoint")
+        );
+
+        config.schedule.push(Block {
+            days: vec![Weekday::Mon, Weekday::Sat],
+            start: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            setpoint: 21.0,
+        });
+        assert_eq!(
+            target(&config, &modes, Tz::UTC, now),
+            (Some(21.0), "schedule")
+        );
+
+        config.temporary = Some(Override {
+            setpoint: 23.0,
+            until: now.timestamp() + 60,
+        });
+        assert_eq!(
+            target(&config, &modes, Tz::UTC, now),
+            (Some(23.0), "override")
+        );
+        let later = now + chrono::Duration::seconds(60);
+        assert_eq!(
+            target(&config, &modes, Tz::UTC, later),
+            (Some(21.0), "schedule")
+        );
+
+        config.mode = Mode::Away;
+        assert_eq!(target(&config, &modes, Tz::UTC, now), (Some(16.0), "away"));
+        config.mode = Mode::Off;
+        assert_eq!(target(&config, &modes, Tz::UTC, now), (None, "off"));
+    }
+
+    #[test]
+    fn is_preheating() {
+        let mut config = config();
+        config.schedule.push(Block {
+            days: vec![Weekday::Mon],
+            start: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            setpoint: 21.0,
+        });
+        let mut model = ThermalModel {
+            heating_rate: 2.0,
+            cooling: 0.1,
+            heating_samples: 0,
+            cooling_samples: 0,
+        };
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        // Monday, heating from 18 to 21 °C takes almost three hours
+        let early = at("2026-06-08T02:00:00Z");
+        let late = at("2026-06-08T05:00:00Z");
+
+        assert_eq!(preheat(&config, &model, Tz::UTC, late, 18.0, 10.0), None);
+        model.heating_samples = 3;
+        model.cooling_samples = 3;
+        assert_eq!(preheat(&config, &model, Tz::UTC, early, 18.0, 10.0), None);
+        assert_eq!(
+            preheat(&config, &model, Tz::UTC, late, 18.0, 10.0),
+            Some(21.0)
+        );
+
+        config.max_preheat = 30 * 60;
+        assert_eq!(preheat(&config, &model, Tz::UTC, late, 18.0, 10.0), None);
+    }
+}
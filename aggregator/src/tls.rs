@@ -0,0 +1,263 @@
+use crate::config::CollectorTlsConfig;
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use log::{error, info};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::ServerConfig;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use util::tls::{load_certs, load_key, tls_error, TlsError};
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = load_certs(cert)?;
+    let signing_key = sign::any_supported_type(&load_key(key)?)
+        .map_err(|_| tls_error(key, "not a supported private key"))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Gives out the certificate in use, which can be swapped while running
+pub struct Certificates {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub fn load(cert: PathBuf, key: PathBuf) -> Result<Self, TlsError> {
+        let current = load_certified_key(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Reads the files again, the old certificate is kept when they are not right
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let new = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(new);
+        Ok(())
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reloads the certificates on SIGHUP
+pub async fn reload_on_hangup(certificates: Arc<Certificates>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Could not listen for SIGHUP, {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match certificates.reload() {
+            Ok(()) => info!("Reloaded the TLS certificates"),
+            Err(e) => error!("Could not reload the TLS certificates, {}", e),
+        }
+    }
+}
+
+/// The certificate chain and its key in one PEM, as the client takes them
+fn client_identity(cert: &Path, key: &Path) -> Result<reqwest::Identity, TlsError> {
+    // Checks both files first, the client only says that the PEM is not right
+    load_certs(cert)?;
+    load_key(key)?;
+    let mut pem = std::fs::read(cert).map_err(|e| tls_error(cert, e))?;
+    pem.push(b'\n');
+    pem.extend(std::fs::read(key).map_err(|e| tls_error(key, e))?);
+    reqwest::Identity::from_pem(&pem).map_err(|e| tls_error(cert, e))
+}
+
+/// Presents the aggregator's certificate to the collectors and only trusts the house CA
+pub fn collector_client(
+    builder: reqwest::ClientBuilder,
+    config: &CollectorTlsConfig,
+) -> Result<reqwest::ClientBuilder, TlsError> {
+    let ca_path = Path::new(&config.ca);
+    let ca = std::fs::read(ca_path).map_err(|e| tls_error(ca_path, e))?;
+    let ca = reqwest::Certificate::from_pem(&ca).map_err(|e| tls_error(ca_path, e))?;
+    let identity = client_identity(Path::new(&config.cert), Path::new(&config.key))?;
+    Ok(builder
+        .use_rustls_tls()
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(ca)
+        .identity(identity))
+}
+
+/// Where a request to `host` and `path` is found over HTTPS on `port`
+fn https_url(host: &str, port: u16, path: &str) -> String {
+    let host = match host.rsplit_once(':') {
+        // Leaves the colons of an IPv6 address alone
+        Some((name, p)) if !p.contains(']') && p.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    match port {
+        443 => format!("https://{}{}", host, path),
+        _ => format!("https://{}:{}{}", host, port, path),
+    }
+}
+
+/// Sends everything asked for over HTTP to the same place over HTTPS
+pub fn redirect_server(
+    bind: SocketAddr,
+    https_port: u16,
+) -> std::io::Result<actix_web::dev::Server> {
+    let server = HttpServer::new(move || {
+        App::new().default_service(web::to(move |req: HttpRequest| async move {
+            let path = req
+                .uri()
+                .path_and_query()
+                .map_or("/", |p| p.as_str())
+                .to_string();
+            let url = https_url(req.connection_info().host(), https_port, &path);
+            HttpResponse::PermanentRedirect()
+                .insert_header((header::LOCATION, url))
+                .finish()
+        }))
+    })
+    .bind(bind)?
+    .run();
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn is_redirect_url() {
+        assert_eq!(
+            https_url("fasteraune.com:80", 443, "/hevn/read?format=status"),
+            "https://fasteraune.com/hevn/read?format=status"
+        );
+        assert_eq!(
+            https_url("192.168.0.2", 8443, "/"),
+            "https://192.168.0.2:8443/"
+        );
+        assert_eq!(https_url("[::1]:80", 8443, "/"), "https://[::1]:8443/");
+    }
+
+    /// Writes a new self-signed certificate for localhost and its key
+    fn self_signed(dir: &Path) -> (PathBuf, PathBuf, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        File::create(&cert_path)
+            .unwrap()
+            .write_all(pem.as_bytes())
+            .unwrap();
+        File::create(&key_path)
+            .unwrap()
+            .write_all(cert.serialize_private_key_pem().as_bytes())
+            .unwrap();
+        (cert_path, key_path, pem)
+    }
+
+    #[actix_web::test]
+    async fn is_https_served_and_reloaded() {
+        let dir = std::env::temp_dir().join(format!("hevn-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key, first) = self_signed(&dir);
+        let certificates = Arc::new(Certificates::load(cert, key).unwrap());
+
+        let server = HttpServer::new(|| App::new().route("/", web::get().to(HttpResponse::Ok)))
+            .workers(1)
+            .bind_rustls("127.0.0.1:0", certificates.server_config())
+            .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let get = |pem: String| async move {
+            let client = reqwest::Client::builder()
+                .add_root_certificate(reqwest::Certificate::from_pem(pem.as_bytes()).unwrap())
+                .build()
+                .unwrap();
+            client
+                .get(format!("https://localhost:{}/", port))
+                .send()
+                .await
+        };
+        assert!(get(first.clone()).await.unwrap().status().is_success());
+
+        let (_, _, second) = self_signed(&dir);
+        certificates.reload().unwrap();
+        assert!(get(first).await.is_err());
+        assert!(get(second).await.unwrap().status().is_success());
+
+        handle.stop(false).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn is_client_certificate_required() {
+        use crate::certs::{run, CertCommand};
+
+        let dir = std::env::temp_dir().join(format!("hevn-mtls-{}", std::process::id()));
+        run(CertCommand::Ca { dir: dir.clone() }).unwrap();
+        for (name, hosts) in [
+            ("bedroom", vec!["localhost", "127.0.0.1"]),
+            ("aggregator", vec![]),
+        ] {
+            run(CertCommand::Device {
+                name: name.to_string(),
+                hosts: hosts.into_iter().map(String::from).collect(),
+                dir: dir.clone(),
+            })
+            .unwrap();
+        }
+        let server_config = util::tls::mutual_server_config(
+            &dir.join("bedroom.pem"),
+            &dir.join("bedroom-key.pem"),
+            &dir.join("ca.pem"),
+        )
+        .unwrap();
+
+        let server = HttpServer::new(|| App::new().route("/data", web::get().to(HttpResponse::Ok)))
+            .workers(1)
+            .bind_rustls("127.0.0.1:0", server_config)
+            .unwrap();
+        let url = format!("https://localhost:{}/data", server.addrs()[0].port());
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let config = CollectorTlsConfig {
+            ca: dir.join("ca.pem").display().to_string(),
+            cert: dir.join("aggregator.pem").display().to_string(),
+            key: dir.join("aggregator-key.pem").display().to_string(),
+        };
+        let client = collector_client(reqwest::Client::builder(), &config)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(client.get(&url).send().await.unwrap().status().is_success());
+
+        let ca = std::fs::read(&config.ca).unwrap();
+        let anonymous = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca).unwrap())
+            .build()
+            .unwrap();
+        assert!(anonymous.get(&url).send().await.is_err());
+
+        handle.stop(false).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
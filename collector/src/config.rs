@@ -0,0 +1,363 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Kinds of sensors the collector can read from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Dht11,
+    /// Made up readings, for running without a Raspberry Pi
+    Simulated,
+}
+
+impl Backend {
+    pub fn model(&self) -> &'static str {
+        match self {
+            Backend::Dht11 => "DHT11",
+            Backend::Simulated => "Simulated",
+        }
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dht11" => Ok(Backend::Dht11),
+            "simulated" => Ok(Backend::Simulated),
+            _ => Err(format!("Unknown sensor backend {}", s)),
+        }
+    }
+}
+
+/// How far off the prediction a reading may be before it is discarded
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// Number of standard deviations allowed
+    pub factor: f32,
+    /// Readings to discard before accepting whatever comes
+    pub max_tries: usize,
+    pub min_temperature_deviation: f32,
+    pub min_humidity_deviation: f32,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            factor: 2.0,
+            max_tries: 16,
+            min_temperature_deviation: 10.0,
+            min_humidity_deviation: 20.0,
+        }
+    }
+}
+
+/// Offsets added to every reading, in tenths like the readings themselves
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Calibration {
+    pub temperature_offset: i16,
+    pub humidity_offset: i16,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Consecutive failed reads before the sensor is reported as failing
+    pub max_failures: u32,
+    /// Seconds since the last good read before the sensor is reported as stale
+    pub max_age: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 3,
+            max_age: 60,
+        }
+    }
+}
+
+/// Everything about a single sensor, can be changed while running
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SensorConfig {
+    /// Used in the urls, defaults to the room in lowercase
+    pub name: String,
+    pub room: String,
+    pub gpio: u8,
+    pub backend: Backend,
+    /// Limit of data to store
+    pub limit: usize,
+    pub filter: Filter,
+    pub calibration: Calibration,
+    pub health: HealthConfig,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            room: String::new(),
+            gpio: 14,
+            backend: Backend::Dht11,
+            limit: 5,
+            filter: Filter::default(),
+            calibration: Calibration::default(),
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PushConfig {
+    /// Url of the aggregator ingest endpoint
+    pub url: String,
+    /// Token used to authenticate against the aggregator
+    pub token: Option<String>,
+    /// Seconds between each reading
+    #[serde(default = "default_push_interval")]
+    pub interval: u64,
+    /// Max number of readings in each request to the aggregator
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// File to keep readings in while the aggregator is unreachable
+    #[serde(default = "default_buffer_file")]
+    pub buffer_file: PathBuf,
+    /// Max number of readings kept in the buffer file
+    #[serde(default = "default_buffer_limit")]
+    pub buffer_limit: usize,
+}
+
+fn default_push_interval() -> u64 {
+    60
+}
+
+fn default_batch_size() -> usize {
+    50
+}
+
+fn default_buffer_file() -> PathBuf {
+    PathBuf::from("push_buffer.jsonl")
+}
+
+fn default_buffer_limit() -> usize {
+    10000
+}
+
+impl PushConfig {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            token: None,
+            interval: default_push_interval(),
+            batch_size: default_batch_size(),
+            buffer_file: default_buffer_file(),
+            buffer_limit: default_buffer_limit(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Host of the mqtt broker
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Readings are published to <prefix>/<room>/state
+    #[serde(default = "default_mqtt_prefix")]
+    pub prefix: String,
+    /// Prefix Home Assistant listens to for discovery
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_prefix() -> String {
+    "hevn".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl MqttConfig {
+    pub fn new(host: String) -> Self {
+        Self {
+            host,
+            port: default_mqtt_port(),
+            username: None,
+            password: None,
+            prefix: default_mqtt_prefix(),
+            discovery_prefix: default_discovery_prefix(),
+        }
+    }
+}
+
+/// Certificate and key for serving HTTPS, clients must have a certificate
+/// signed by `client_ca`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// The house CA the aggregator's certificate is signed by
+    pub client_ca: PathBuf,
+}
+
+/// Configuration of the collector, the bind address, tls, push and mqtt
+/// settings are only read on startup
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// The sensor used when there are no [[sensor]] tables
+    #[serde(flatten)]
+    pub sensor: SensorConfig,
+    #[serde(rename = "sensor")]
+    pub sensors: Vec<SensorConfig>,
+    pub push: Option<PushConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 5000,
+            sensor: SensorConfig::default(),
+            sensors: Vec::new(),
+            push: None,
+            mqtt: None,
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&file)?)
+    }
+
+    /// All the sensors with their names filled in
+    pub fn sensors(&self) -> Result<Vec<SensorConfig>, Box<dyn std::error::Error>> {
+        let mut sensors = if self.sensors.is_empty() {
+            vec![self.sensor.clone()]
+        } else {
+            self.sensors.clone()
+        };
+
+        for (i, sensor) in sensors.iter_mut().enumerate() {
+            if sensor.room.is_empty() {
+                return Err(format!("Sensor {} has no room", i + 1).into());
+            }
+            if sensor.name.is_empty() {
+                sensor.name = sensor.room.to_lowercase().replace(' ', "_");
+            }
+        }
+        for (i, sensor) in sensors.iter().enumerate() {
+            if sensors[..i].iter().any(|s| s.name == sensor.name) {
+                return Err(format!("There are several sensors named {}", sensor.name).into());
+            }
+        }
+        Ok(sensors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_config() {
+        let config: Config = toml::from_str(
+            r#"
+            room = "Bedroom"
+            port = 5001
+            backend = "simulated"
+
+            [filter]
+            factor = 3.0
+
+            [calibration]
+            temperature_offset = -5
+
+            [push]
+            url = "http://aggregator:65535/ingest"
+
+            [tls]
+            cert = "certs/bedroom.pem"
+            key = "certs/bedroom-key.pem"
+            client_ca = "certs/ca.pem"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.sensor.room, "Bedroom");
+        assert_eq!(config.port, 5001);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.sensor.backend, Backend::Simulated);
+        assert_eq!(config.sensor.filter.factor, 3.0);
+        assert_eq!(config.sensor.filter.max_tries, 16);
+        assert_eq!(config.sensor.calibration.temperature_offset, -5);
+        assert_eq!(config.push.as_ref().unwrap().interval, 60);
+        assert!(config.mqtt.is_none());
+        assert_eq!(
+            config.tls.as_ref().unwrap().client_ca,
+            PathBuf::from("certs/ca.pem")
+        );
+
+        let sensors = config.sensors().unwrap();
+        assert_eq!(sensors.len(), 1);
+        assert_eq!(sensors[0].name, "bedroom");
+    }
+
+    #[test]
+    fn is_several_sensors() {
+        let config: Config = toml::from_str(
+            r#"
+            [[sensor]]
+            room = "Living Room"
+            gpio = 14
+
+            [[sensor]]
+            name = "hall"
+            room = "Kitchen"
+            gpio = 15
+            limit = 10
+            "#,
+        )
+        .unwrap();
+
+        let sensors = config.sensors().unwrap();
+        assert_eq!(sensors.len(), 2);
+        assert_eq!(sensors[0].name, "living_room");
+        assert_eq!(sensors[1].name, "hall");
+        assert_eq!(sensors[1].gpio, 15);
+        assert_eq!(sensors[1].limit, 10);
+
+        let config: Config = toml::from_str(
+            r#"
+            [[sensor]]
+            room = "Kitchen"
+
+            [[sensor]]
+            room = "kitchen"
+            "#,
+        )
+        .unwrap();
+        assert!(config.sensors().is_err());
+    }
+}
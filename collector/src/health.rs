@@ -0,0 +1,100 @@
+use crate::config::HealthConfig;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use util::{CollectorHealth, SensorStatus};
+
+/// Keeps track of how well the sensor has been doing
+pub struct Health {
+    started: Instant,
+    last_read: Mutex<Option<Instant>>,
+    failures: Mutex<u32>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_read: Mutex::new(None),
+            failures: Mutex::new(0),
+        }
+    }
+
+    pub async fn success(&self) {
+        let mut last_read = self.last_read.lock().await;
+        let mut failures = self.failures.lock().await;
+        *last_read = Some(Instant::now());
+        *failures = 0;
+    }
+
+    pub async fn failure(&self) {
+        let mut failures = self.failures.lock().await;
+        *failures += 1;
+    }
+
+    /// Is the last good read older than what we accept
+    pub async fn is_stale(&self, config: &HealthConfig) -> bool {
+        let last_read = self.last_read.lock().await;
+        match *last_read {
+            Some(t) => t.elapsed() > Duration::from_secs(config.max_age),
+            None => true,
+        }
+    }
+
+    pub async fn report(&self, config: &HealthConfig) -> CollectorHealth {
+        let last_read = self.last_read.lock().await;
+        let failures = self.failures.lock().await;
+        let since_last_read = last_read.map(|t| t.elapsed());
+
+        let sensor = if *failures >= config.max_failures {
+            SensorStatus::Failing
+        } else if since_last_read.is_some() {
+            SensorStatus::Ok
+        } else {
+            SensorStatus::Unknown
+        };
+
+        let fresh = since_last_read.is_some_and(|d| d <= Duration::from_secs(config.max_age));
+
+        CollectorHealth {
+            healthy: sensor == SensorStatus::Ok && fresh,
+            sensor,
+            seconds_since_last_read: since_last_read.map(|d| d.as_secs()),
+            consecutive_failures: *failures,
+            uptime: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn is_unhealthy_after_failures() {
+        let config = HealthConfig {
+            max_failures: 2,
+            max_age: 60,
+        };
+        let health = Health::new();
+        assert_eq!(health.report(&config).await.sensor, SensorStatus::Unknown);
+
+        health.success().await;
+        assert!(health.report(&config).await.healthy);
+
+        health.failure().await;
+        health.failure().await;
+        let report = health.report(&config).await;
+        assert!(!report.healthy);
+        assert_eq!(report.sensor, SensorStatus::Failing);
+        assert_eq!(report.consecutive_failures, 2);
+
+        health.success().await;
+        assert_eq!(health.report(&config).await.consecutive_failures, 0);
+    }
+}
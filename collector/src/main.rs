@@ -1,13 +1,24 @@
 extern crate util;
-use actix_web::{get, web, App, HttpServer, Responder, Result};
-use reader::read_dht11;
+use actix_web::{error, get, web, App, HttpResponse, HttpServer, Responder, Result};
+use config::{Backend, Config, MqttConfig, PushConfig};
+use log::{error, info, warn};
+use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio::sync::Mutex;
-use util::{Collector, EnvData};
+mod config;
+mod health;
+mod mqtt;
+mod openapi;
+mod push;
 mod reader;
+mod sensor;
 mod stored_data;
 
-use stored_data::StoredData;
+use mqtt::Mqtt;
+use push::{Buffer, Pusher};
+use sensor::{Sensor, Sensors};
+use util::CollectorHealth;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -15,134 +26,455 @@ use stored_data::StoredData;
     about = "Collector for DHT11 sensor using Raspberry Pi"
 )]
 struct Opt {
+    /// Config file, the flags below override what is in it
+    #[structopt(short = "c", long = "config", default_value = "collector.toml")]
+    config: PathBuf,
+
     #[structopt(short = "r", long = "room")]
-    room: String,
+    room: Option<String>,
+
+    #[structopt(short = "h", long = "host")]
+    host: Option<String>,
 
-    #[structopt(short = "h", long = "host", default_value = "0.0.0.0")]
-    host: String,
+    #[structopt(short = "p", long = "port")]
+    port: Option<u16>,
 
-    #[structopt(short = "p", long = "port", default_value = "5000")]
-    port: String,
+    #[structopt(short = "g", long = "gpio")]
+    gpio_pin: Option<u8>,
 
-    #[structopt(short = "g", long = "gpio", default_value = "14")]
-    gpio_pin: u8,
+    /// Kind of sensor, dht11 or simulated
+    #[structopt(short = "s", long = "sensor")]
+    backend: Option<Backend>,
 
     /// Limit of data to store
-    #[structopt(short = "l", long = "limit", default_value = "5")]
-    limit: usize,
-}
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
 
-#[get("/predict")]
-async fn predict(stored_data: web::Data<StoredData>) -> Result<impl Responder> {
-    let possible_expected_data = stored_data.predict(stored_data.get_timestamp().await).await;
-    let deviation = stored_data.get_expected_deviation(2.0).await;
-    return Ok(format!("{:?}, {:?}", possible_expected_data, deviation));
+    /// Consecutive failed reads before the sensor is reported as failing
+    #[structopt(long = "max-failures")]
+    max_failures: Option<u32>,
+
+    /// Seconds since the last good read before the sensor is reported as stale
+    #[structopt(long = "max-age")]
+    max_age: Option<u64>,
+
+    /// Url of the aggregator ingest endpoint, enables push mode
+    #[structopt(long = "push-url")]
+    push_url: Option<String>,
+
+    /// Token used to authenticate against the aggregator
+    #[structopt(long = "push-token")]
+    push_token: Option<String>,
+
+    /// Seconds between each reading in push mode
+    #[structopt(long = "push-interval")]
+    push_interval: Option<u64>,
+
+    /// Max number of readings in each request to the aggregator
+    #[structopt(long = "batch-size")]
+    batch_size: Option<usize>,
+
+    /// File to keep readings in while the aggregator is unreachable
+    #[structopt(long = "buffer-file")]
+    buffer_file: Option<PathBuf>,
+
+    /// Max number of readings kept in the buffer file
+    #[structopt(long = "buffer-limit")]
+    buffer_limit: Option<usize>,
+
+    /// Host of the mqtt broker, enables publishing to mqtt
+    #[structopt(long = "mqtt-host")]
+    mqtt_host: Option<String>,
+
+    #[structopt(long = "mqtt-port")]
+    mqtt_port: Option<u16>,
+
+    #[structopt(long = "mqtt-username")]
+    mqtt_username: Option<String>,
+
+    #[structopt(long = "mqtt-password")]
+    mqtt_password: Option<String>,
+
+    /// Readings are published to <prefix>/<room>/state
+    #[structopt(long = "mqtt-prefix")]
+    mqtt_prefix: Option<String>,
+
+    /// Prefix Home Assistant listens to for discovery
+    #[structopt(long = "discovery-prefix")]
+    discovery_prefix: Option<String>,
 }
 
-#[get("/read")]
-async fn read(pin: web::Data<Pin>, collector: web::Data<Collector>) -> Result<impl Responder> {
-    let my_pin = pin.pin.lock().await;
-    loop {
-        match read_dht11(*my_pin) {
-            Ok((temperature, humidity)) => {
-                return Ok(web::Json(EnvData::new(
-                    collector.room(),
-                    temperature,
-                    humidity,
-                )))
+impl Opt {
+    /// Reads the config file, if there is one, and puts the flags on top
+    fn load_config(&self) -> Result<Config, Box<dyn std::error::Error>> {
+        let mut config = if self.config.exists() {
+            Config::from_file(&self.config)
+                .map_err(|e| format!("{}: {}", self.config.display(), e))?
+        } else {
+            Config::default()
+        };
+
+        let sensor_flags = [
+            ("--room", self.room.is_some()),
+            ("--gpio", self.gpio_pin.is_some()),
+            ("--sensor", self.backend.is_some()),
+            ("--limit", self.limit.is_some()),
+            ("--max-failures", self.max_failures.is_some()),
+            ("--max-age", self.max_age.is_some()),
+        ];
+        if !config.sensors.is_empty() {
+            if let Some((flag, _)) = sensor_flags.iter().find(|(_, given)| *given) {
+                return Err(format!(
+                    "{} only applies to a single sensor, set it in the [[sensor]] tables of {}",
+                    flag,
+                    self.config.display()
+                )
+                .into());
             }
-            Err(e) => {
-                println!("{:?}", e);
+        }
+
+        if let Some(room) = &self.room {
+            config.sensor.room = room.clone();
+        }
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(gpio) = self.gpio_pin {
+            config.sensor.gpio = gpio;
+        }
+        if let Some(backend) = self.backend {
+            config.sensor.backend = backend;
+        }
+        if let Some(limit) = self.limit {
+            config.sensor.limit = limit;
+        }
+        if let Some(max_failures) = self.max_failures {
+            config.sensor.health.max_failures = max_failures;
+        }
+        if let Some(max_age) = self.max_age {
+            config.sensor.health.max_age = max_age;
+        }
+
+        if let Some(url) = &self.push_url {
+            config.push = Some(PushConfig {
+                url: url.clone(),
+                ..config.push.unwrap_or_else(|| PushConfig::new(url.clone()))
+            });
+        }
+        if let Some(push) = config.push.as_mut() {
+            if let Some(token) = &self.push_token {
+                push.token = Some(token.clone());
+            }
+            if let Some(interval) = self.push_interval {
+                push.interval = interval;
+            }
+            if let Some(batch_size) = self.batch_size {
+                push.batch_size = batch_size;
+            }
+            if let Some(buffer_file) = &self.buffer_file {
+                push.buffer_file = buffer_file.clone();
+            }
+            if let Some(buffer_limit) = self.buffer_limit {
+                push.buffer_limit = buffer_limit;
             }
         }
+
+        if let Some(host) = &self.mqtt_host {
+            config.mqtt = Some(MqttConfig {
+                host: host.clone(),
+                ..config.mqtt.unwrap_or_else(|| MqttConfig::new(host.clone()))
+            });
+        }
+        if let Some(mqtt) = config.mqtt.as_mut() {
+            if let Some(port) = self.mqtt_port {
+                mqtt.port = port;
+            }
+            if let Some(username) = &self.mqtt_username {
+                mqtt.username = Some(username.clone());
+            }
+            if let Some(password) = &self.mqtt_password {
+                mqtt.password = Some(password.clone());
+            }
+            if let Some(prefix) = &self.mqtt_prefix {
+                mqtt.prefix = prefix.clone();
+            }
+            if let Some(discovery_prefix) = &self.discovery_prefix {
+                mqtt.discovery_prefix = discovery_prefix.clone();
+            }
+        }
+
+        if config.sensors.is_empty() && config.sensor.room.is_empty() {
+            return Err("No room given, set it in the config file or with --room".into());
+        }
+        Ok(config)
+    }
+}
+
+/// Finds the sensor or responds with 404
+fn find<'a>(sensors: &'a Sensors, name: &str) -> Result<&'a Sensor> {
+    sensors
+        .get(name)
+        .ok_or_else(|| error::ErrorNotFound(format!("No sensor named {}", name)))
+}
+
+fn health_response(report: CollectorHealth) -> HttpResponse {
+    if report.healthy {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
     }
 }
 
+#[get("/predict")]
+async fn predict(sensors: web::Data<Sensors>) -> Result<impl Responder> {
+    Ok(sensors.first().predict().await)
+}
+
+#[get("/read")]
+async fn read(sensors: web::Data<Sensors>) -> Result<impl Responder> {
+    Ok(web::Json(sensors.first().read().await?))
+}
+
 #[get("/data")]
 async fn data(
-    pin: web::Data<Pin>,
-    collector: web::Data<Collector>,
-    stored_data: web::Data<StoredData>,
+    sensors: web::Data<Sensors>,
+    mqtt: web::Data<Mqtt>,
 ) -> Result<impl Responder, actix_web::Error> {
-    let my_pin = pin.pin.lock().await;
-
-    let possible_expected_data = stored_data.predict(stored_data.get_timestamp().await).await;
-    let deviation = stored_data.get_expected_deviation(2.0).await;
-    let mut tries = 0;
-
-    loop {
-        match read_dht11(*my_pin) {
-            Ok((temp, humi)) => {
-                // Check if the data is valid
-                if let Some((devi_temp, devi_humi)) = deviation {
-                    if let Some(true) = possible_expected_data.as_ref().map(|data| {
-                        dbg!(
-                            temp,
-                            data.temperature as f32 - devi_temp,
-                            data.temperature as f32 + devi_temp,
-                            humi,
-                            data.humidity as f32 - devi_humi,
-                            data.humidity as f32 + devi_humi,
-                            tries
-                        );
-                        ((data.temperature as f32 - temp as f32).abs() > devi_temp
-                            || (data.humidity as f32 - humi as f32).abs() > devi_humi)
-                            && tries < 16
-                    }) {
-                        tries += 1;
-                        continue;
-                    }
-                }
+    Ok(web::Json(sensors.first().take_reading(&mqtt).await?))
+}
 
-                let env_data = EnvData::new(collector.room(), temp, humi);
+#[get("/health")]
+async fn get_health(sensors: web::Data<Sensors>) -> impl Responder {
+    health_response(sensors.first().health().await)
+}
 
-                // Store the data
-                stored_data.add(env_data.clone()).await;
-                if stored_data.len().await > stored_data.get_lim() {
-                    stored_data.remove().await;
-                }
+#[get("/info")]
+async fn get_info(sensors: web::Data<Sensors>) -> impl Responder {
+    web::Json(sensors.first().info().await)
+}
 
-                return Ok(web::Json(env_data));
-            }
-            Err(e) => {
-                println!("{}", e);
-            }
-        }
+#[get("/sensors")]
+async fn list_sensors(sensors: web::Data<Sensors>) -> impl Responder {
+    let mut infos = Vec::new();
+    for sensor in sensors.iter() {
+        infos.push(sensor.info().await);
     }
+    web::Json(infos)
+}
+
+#[get("/sensor/{name}/predict")]
+async fn sensor_predict(
+    name: web::Path<String>,
+    sensors: web::Data<Sensors>,
+) -> Result<impl Responder> {
+    Ok(find(&sensors, &name)?.predict().await)
+}
+
+#[get("/sensor/{name}/read")]
+async fn sensor_read(
+    name: web::Path<String>,
+    sensors: web::Data<Sensors>,
+) -> Result<impl Responder> {
+    Ok(web::Json(find(&sensors, &name)?.read().await?))
+}
+
+#[get("/sensor/{name}/data")]
+async fn sensor_data(
+    name: web::Path<String>,
+    sensors: web::Data<Sensors>,
+    mqtt: web::Data<Mqtt>,
+) -> Result<impl Responder> {
+    Ok(web::Json(find(&sensors, &name)?.take_reading(&mqtt).await?))
+}
+
+#[get("/sensor/{name}/health")]
+async fn sensor_health(
+    name: web::Path<String>,
+    sensors: web::Data<Sensors>,
+) -> Result<impl Responder> {
+    Ok(health_response(find(&sensors, &name)?.health().await))
+}
+
+#[get("/sensor/{name}/info")]
+async fn sensor_info(
+    name: web::Path<String>,
+    sensors: web::Data<Sensors>,
+) -> Result<impl Responder> {
+    Ok(web::Json(find(&sensors, &name)?.info().await))
 }
 
-struct Pin {
-    pin: Mutex<u8>,
+/// Every route of the collector
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(data)
+        .service(read)
+        .service(predict)
+        .service(get_health)
+        .service(get_info)
+        .service(list_sensors)
+        .service(sensor_predict)
+        .service(sensor_read)
+        .service(sensor_data)
+        .service(sensor_health)
+        .service(sensor_info)
+        .service(openapi::openapi);
 }
 
-impl Pin {
-    fn new(pin: u8) -> Self {
-        Self {
-            pin: Mutex::new(pin),
+/// Reloads the sensor settings on SIGHUP
+async fn reload_on_hangup(opt: Opt, config: Config, sensors: web::Data<Sensors>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Could not listen for SIGHUP, {}", e);
+            return;
         }
+    };
+    // Mqtt discovery and availability are only announced for the rooms read on startup
+    let announced = config.sensors().unwrap_or_default();
+
+    while hangup.recv().await.is_some() {
+        let new_sensors = match opt.load_config().and_then(|c| Ok((c.sensors()?, c))) {
+            Ok((new_sensors, new_config)) => {
+                if new_config.host != config.host
+                    || new_config.port != config.port
+                    || new_config.push != config.push
+                    || new_config.mqtt != config.mqtt
+                    || new_config.tls != config.tls
+                {
+                    warn!(
+                        "Bind, tls, push and mqtt settings are only read on startup, restart to apply them"
+                    );
+                }
+                new_sensors
+            }
+            Err(e) => {
+                warn!("Keeping the old config, {}", e);
+                continue;
+            }
+        };
+        if config.mqtt.is_some() {
+            for sensor in &new_sensors {
+                if let Some(old) = announced
+                    .iter()
+                    .find(|s| s.name == sensor.name && s.room != sensor.room)
+                {
+                    warn!(
+                        "Sensor {} moved from {} to {}, mqtt still announces {}, restart to apply it",
+                        sensor.name, old.room, sensor.room, old.room
+                    );
+                }
+            }
+        }
+        for name in sensors.reload(new_sensors).await {
+            warn!("Sensor {} is added or removed, restart to apply it", name);
+        }
+        info!("Reloaded {}", opt.config.display());
     }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    TermLogger::init(
+        LevelFilter::Info,
+        simplelog::Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )
+    .expect("Could not start the logger");
     let opt = Opt::from_args();
+    let config = opt
+        .load_config()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
 
-    let host = opt.host.clone();
+    let sensors = web::Data::new(Sensors::new(
+        config
+            .sensors()
+            .map_err(|e| std::io::Error::other(e.to_string()))?,
+    ));
 
-    let stored_data = web::Data::new(StoredData::new(opt.limit));
-    let my_pin = web::Data::new(Pin::new(opt.gpio_pin));
-    let my_collector = web::Data::new(Collector::new(opt.room.clone(), opt.host.clone()));
+    let my_mqtt = web::Data::new(match &config.mqtt {
+        Some(mqtt) => {
+            let mut infos = Vec::new();
+            for sensor in sensors.iter() {
+                infos.push(sensor.info().await);
+            }
+            Mqtt::connect(mqtt, &infos)
+        }
+        None => Mqtt::disabled(),
+    });
 
-    HttpServer::new(move || {
+    if let Some(push) = config.push.clone() {
+        let pusher = Pusher::new(
+            push.url,
+            push.token,
+            push.batch_size,
+            Buffer::new(push.buffer_file, push.buffer_limit),
+        );
+        let interval = Duration::from_secs(push.interval);
+        let sensors = sensors.clone();
+        let mqtt = my_mqtt.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let mut readings = Vec::new();
+                for sensor in sensors.iter() {
+                    match sensor.take_reading(&mqtt).await {
+                        Ok(reading) => readings.push(reading),
+                        Err(e) => warn!("No reading from {} to push, {}", sensor.name(), e),
+                    }
+                }
+                if let Err(e) = pusher.push(readings).await {
+                    error!("Could not buffer readings, {}", e);
+                }
+            }
+        });
+    }
+
+    let bind = format!("{}:{}", config.host, config.port);
+    let tls_config = match &config.tls {
+        Some(tls) => Some(
+            util::tls::mutual_server_config(&tls.cert, &tls.key, &tls.client_ca)
+                .map_err(|e| std::io::Error::other(e.to_string()))?,
+        ),
+        None => None,
+    };
+    actix_web::rt::spawn(reload_on_hangup(opt, config, sensors.clone()));
+
+    let server = HttpServer::new(move || {
         App::new()
-            .service(data)
-            .service(read)
-            .service(predict)
-            .app_data(my_pin.clone())
-            .app_data(my_collector.clone())
-            .app_data(stored_data.clone())
-    })
-    .bind(format!("{}:{}", host, opt.port))?
-    .run()
-    .await
+            .configure(routes)
+            .app_data(sensors.clone())
+            .app_data(my_mqtt.clone())
+    });
+    match tls_config {
+        Some(tls_config) => server.bind_rustls(bind, tls_config)?.run().await,
+        None => server.bind(bind)?.run().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_sensor_flag_rejected_with_several_sensors() {
+        let path = std::env::temp_dir().join(format!("hevn-collector-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[[sensor]]\nroom = \"Bedroom\"\n\n[[sensor]]\nroom = \"Kitchen\"\ngpio = 17\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+
+        let opt = Opt::from_iter(["collector", "--config", config, "--port", "5001"]);
+        assert_eq!(opt.load_config().unwrap().port, 5001);
+
+        let opt = Opt::from_iter(["collector", "--config", config, "--gpio", "4"]);
+        let e = opt.load_config().unwrap_err();
+        assert!(e.to_string().starts_with("--gpio only applies"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+    }
 }
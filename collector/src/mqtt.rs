@@ -0,0 +1,243 @@
+use crate::config::MqttConfig;
+use log::warn;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::time::Duration;
+use util::{CollectorInfo, EnvData};
+
+/// Topic the readings of a room are published to
+pub fn state_topic(prefix: &str, room: &str) -> String {
+    format!("{}/{}/state", prefix, slug(room))
+}
+
+/// Topic telling if the collector is online
+pub fn availability_topic(prefix: &str, name: &str) -> String {
+    format!("{}/{}/availability", prefix, slug(name))
+}
+
+/// Lowercase the name and replace everything mqtt and Home Assistant might dislike
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Home Assistant discovery topics and payloads for the temperature and humidity entities
+pub fn discovery(
+    discovery_prefix: &str,
+    prefix: &str,
+    availability: &str,
+    info: &CollectorInfo,
+) -> Vec<(String, Value)> {
+    let room = slug(&info.room);
+    let device = json!({
+        "identifiers": [format!("hevn_{}", room)],
+        "name": format!("Hevn {}", info.room),
+        "model": info.sensor,
+        "manufacturer": "Hevn",
+        "sw_version": info.version,
+    });
+
+    [
+        ("temperature", "°C", "{{ value_json.temperature / 10 }}"),
+        ("humidity", "%", "{{ value_json.humidity / 10 }}"),
+    ]
+    .iter()
+    .map(|(class, unit, template)| {
+        let id = format!("hevn_{}_{}", room, class);
+        (
+            format!("{}/sensor/{}/config", discovery_prefix, id),
+            json!({
+                "name": format!("{} {}", info.room, class),
+                "unique_id": id,
+                "device_class": class,
+                "state_class": "measurement",
+                "unit_of_measurement": unit,
+                "state_topic": state_topic(prefix, &info.room),
+                "value_template": template,
+                "availability_topic": availability,
+                "device": device,
+            }),
+        )
+    })
+    .collect()
+}
+
+/// Publishes the readings to a mqtt broker, does nothing when not configured
+pub struct Mqtt {
+    client: Option<AsyncClient>,
+    prefix: String,
+}
+
+impl Mqtt {
+    pub fn disabled() -> Self {
+        Self {
+            client: None,
+            prefix: String::new(),
+        }
+    }
+
+    /// Connects to the broker and keeps the connection going in the background,
+    /// the collector is named after the first sensor
+    pub fn connect(config: &MqttConfig, infos: &[CollectorInfo]) -> Self {
+        let name = infos
+            .first()
+            .map(|i| i.room.as_str())
+            .unwrap_or("collector");
+        let availability = availability_topic(&config.prefix, name);
+
+        let mut options =
+            MqttOptions::new(format!("hevn-{}", slug(name)), &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some((username, password)) = config.username.clone().zip(config.password.clone()) {
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 16);
+
+        let announce = client.clone();
+        let configs = infos
+            .iter()
+            .flat_map(|info| {
+                discovery(
+                    &config.discovery_prefix,
+                    &config.prefix,
+                    &availability,
+                    info,
+                )
+            })
+            .collect::<Vec<_>>();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    // Retained messages are gone if the broker restarted, so send them on every connect
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        let announce = announce.clone();
+                        let availability = availability.clone();
+                        let configs = configs.clone();
+                        tokio::spawn(async move {
+                            for (topic, config) in configs {
+                                let _ = announce
+                                    .publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                                    .await;
+                            }
+                            let _ = announce
+                                .publish(availability, QoS::AtLeastOnce, true, "online")
+                                .await;
+                        });
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        warn!("Mqtt connection error, {}", e);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+
+        Self {
+            client: Some(client),
+            prefix: config.prefix.clone(),
+        }
+    }
+
+    /// Queues the readings without waiting, they are dropped when the queue is full
+    /// so a slow or unreachable broker can never hold up the collector
+    pub fn publish(&self, data: &EnvData) {
+        if let Some(client) = &self.client {
+            let payload = serde_json::to_string(data).unwrap();
+            if let Err(e) = client.try_publish(
+                state_topic(&self.prefix, &data.room),
+                QoS::AtLeastOnce,
+                false,
+                payload,
+            ) {
+                warn!("Dropped the {} readings for mqtt, {}", data.room, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> CollectorInfo {
+        CollectorInfo::new(
+            "living_room".to_string(),
+            "Living Room".to_string(),
+            "DHT11".to_string(),
+            14,
+            "0.1.0".to_string(),
+        )
+    }
+
+    #[test]
+    fn is_discovery() {
+        assert_eq!(state_topic("hevn", "Living Room"), "hevn/living_room/state");
+
+        let availability = availability_topic("hevn", "Living Room");
+        let configs = discovery("homeassistant", "hevn", &availability, &info());
+        assert_eq!(configs.len(), 2);
+        let (topic, config) = &configs[0];
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/hevn_living_room_temperature/config"
+        );
+        assert_eq!(config["state_topic"], "hevn/living_room/state");
+        assert_eq!(
+            config["availability_topic"],
+            "hevn/living_room/availability"
+        );
+        assert_eq!(config["device_class"], "temperature");
+    }
+
+    /// Needs a broker, run with `docker run -p 1883:1883 eclipse-mosquitto mosquitto -c /mosquitto-no-auth.conf`
+    /// and `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn is_published_to_mosquitto() {
+        let host = std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let info = info();
+        let mut config = MqttConfig::new(host.clone());
+        config.prefix = "hevn-test".to_string();
+        let mqtt = Mqtt::connect(&config, std::slice::from_ref(&info));
+
+        let (listener, mut eventloop) =
+            AsyncClient::new(MqttOptions::new("hevn-test-listener", host, 1883), 16);
+        listener
+            .subscribe("hevn-test/living_room/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let data = EnvData::new(info.room.clone(), 215, 400);
+        let mut seen_state = false;
+        let mut seen_online = false;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while !(seen_state && seen_online) && tokio::time::Instant::now() < deadline {
+            mqtt.publish(&data);
+            let event = tokio::time::timeout(Duration::from_secs(1), eventloop.poll()).await;
+            if let Ok(Ok(Event::Incoming(Packet::Publish(p)))) = event {
+                if p.topic.ends_with("/state") {
+                    assert_eq!(serde_json::from_slice::<EnvData>(&p.payload).unwrap(), data);
+                    seen_state = true;
+                } else if p.topic.ends_with("/availability") {
+                    seen_online = &p.payload[..] == b"online";
+                }
+            }
+        }
+        assert!(seen_state && seen_online);
+    }
+}
@@ -0,0 +1,132 @@
+use actix_web::{get, web, Responder};
+use serde_json::Value;
+use util::openapi::{any, array, reference, Operation, Spec};
+use util::{CollectorHealth, CollectorInfo, EnvData, SensorStatus};
+
+fn reading(summary: &str) -> Operation {
+    Operation::new(summary)
+        .json(200, "The reading", reference::<EnvData>())
+        .text(503, "The sensor could not be read")
+}
+
+fn health() -> Operation {
+    Operation::new("Health of the sensor")
+        .json(200, "The sensor is healthy", reference::<CollectorHealth>())
+        .json(503, "The sensor is failing", reference::<CollectorHealth>())
+}
+
+/// Adds the routes of one sensor under `prefix`
+fn sensor_routes(spec: Spec, prefix: &str, not_found: bool) -> Spec {
+    let route = |spec: Spec, path: &str, operation: Operation| {
+        let operation = if not_found {
+            operation.text(404, "No sensor with that name")
+        } else {
+            operation
+        };
+        spec.route("GET", &format!("{}{}", prefix, path), operation)
+    };
+    let spec = route(
+        spec,
+        "/data",
+        reading("Reads the sensor, discarding readings far off the prediction, and stores it"),
+    );
+    let spec = route(
+        spec,
+        "/read",
+        reading("Reads the sensor without any filtering"),
+    );
+    let spec = route(
+        spec,
+        "/predict",
+        Operation::new("The expected reading and how far off it may be")
+            .text(200, "The prediction"),
+    );
+    let spec = route(spec, "/health", health());
+    route(
+        spec,
+        "/info",
+        Operation::new("What the sensor is and where").json(
+            200,
+            "The sensor",
+            reference::<CollectorInfo>(),
+        ),
+    )
+}
+
+/// The OpenAPI document of the collector
+pub fn document() -> Value {
+    let spec = Spec::new("hevn collector", env!("CARGO_PKG_VERSION"))
+        .schema::<EnvData>()
+        .schema::<SensorStatus>()
+        .schema::<CollectorHealth>()
+        .schema::<CollectorInfo>()
+        .route(
+            "GET",
+            "/sensors",
+            Operation::new("Every sensor of the collector").json(
+                200,
+                "The sensors",
+                array(reference::<CollectorInfo>()),
+            ),
+        )
+        .route(
+            "GET",
+            "/openapi.json",
+            Operation::new("This document").json(200, "The document", any()),
+        );
+    let spec = sensor_routes(spec, "", false);
+    sensor_routes(spec, "/sensor/{name}", true).build()
+}
+
+#[get("/openapi.json")]
+async fn openapi() -> impl Responder {
+    web::Json(document())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, SensorConfig};
+    use crate::mqtt::Mqtt;
+    use crate::sensor::Sensors;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use util::openapi::{check_response, operations};
+
+    #[actix_web::test]
+    async fn is_document_matching() {
+        let sensors = web::Data::new(Sensors::new(vec![SensorConfig {
+            name: "bedroom".to_string(),
+            room: "Bedroom".to_string(),
+            backend: Backend::Simulated,
+            ..SensorConfig::default()
+        }]));
+        let app = init_service(
+            App::new()
+                .configure(crate::routes)
+                .app_data(sensors)
+                .app_data(web::Data::new(Mqtt::disabled())),
+        )
+        .await;
+
+        let document = document();
+        let mut paths: Vec<String> = operations(&document)
+            .into_iter()
+            .map(|(_, path, _)| path)
+            .collect();
+        paths.push("/sensor/{name}/info".to_string());
+        for (i, path) in paths.iter().enumerate() {
+            // The last one asks for a sensor that is not there
+            let name = if i + 1 == paths.len() {
+                "attic"
+            } else {
+                "bedroom"
+            };
+            let req = TestRequest::get().uri(&path.replace("{name}", name));
+            let resp = call_service(&app, req.to_request()).await;
+            let status = resp.status().as_u16();
+            let body = read_body(resp).await;
+            check_response(&document, "GET", path, status, &body).unwrap();
+        }
+    }
+}
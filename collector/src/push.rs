@@ -0,0 +1,171 @@
+use log::warn;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use util::EnvData;
+
+/// Readings waiting to be delivered, one json object per line
+pub struct Buffer {
+    path: PathBuf,
+    lim: usize,
+}
+
+impl Buffer {
+    pub fn new(path: PathBuf, lim: usize) -> Self {
+        Self { path, lim }
+    }
+
+    pub fn load(&self) -> std::io::Result<Vec<EnvData>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut res = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(data) => res.push(data),
+                Err(e) => warn!("Skipping broken buffered reading, {}", e),
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn push(&self, data: &EnvData) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(data)?)
+    }
+
+    /// Replaces the content of the buffer, keeping at most <lim> of the newest readings
+    pub fn replace(&self, data: &[EnvData]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let skip = data.len().saturating_sub(self.lim);
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        for d in &data[skip..] {
+            writeln!(file, "{}", serde_json::to_string(d)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// Sends readings to the aggregator
+pub struct Pusher {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    batch_size: usize,
+    buffer: Buffer,
+}
+
+impl Pusher {
+    pub fn new(url: String, token: Option<String>, batch_size: usize, buffer: Buffer) -> Self {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(std::time::Duration::from_secs(5))
+            .build()
+            .unwrap();
+        Self {
+            client,
+            url,
+            token,
+            batch_size: batch_size.max(1),
+            buffer,
+        }
+    }
+
+    async fn send(&self, batch: &[EnvData]) -> Result<(), reqwest::Error> {
+        let mut req = self.client.post(&self.url).json(batch);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Sends the buffered readings and the new ones,
+    /// whatever is not delivered is kept on disk for the next round
+    pub async fn push(&self, readings: Vec<EnvData>) -> std::io::Result<()> {
+        let mut pending = self.buffer.load()?;
+        let buffered = pending.len();
+        pending.extend(readings);
+
+        let mut sent = 0;
+        for batch in pending.chunks(self.batch_size) {
+            if let Err(e) = self.send(batch).await {
+                warn!("Could not push to {}, {}", self.url, e);
+                break;
+            }
+            sent += batch.len();
+        }
+
+        if sent == pending.len() {
+            if buffered > 0 {
+                self.buffer.replace(&[])?;
+            }
+        } else if sent == 0 && pending.len() <= self.buffer.lim {
+            for data in &pending[buffered..] {
+                self.buffer.push(data)?;
+            }
+        } else {
+            // Also drops the oldest readings once the buffer is full
+            self.buffer.replace(&pending[sent..])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_buffer_round_trip() {
+        let path = std::env::temp_dir().join(format!("hevn-buffer-{}.jsonl", std::process::id()));
+        let buffer = Buffer::new(path.clone(), 2);
+        assert!(buffer.load().unwrap().is_empty());
+
+        let readings = (0..3)
+            .map(|i| EnvData::new("Bedroom".to_string(), 200 + i, 400))
+            .collect::<Vec<_>>();
+        for r in &readings {
+            buffer.push(r).unwrap();
+        }
+        assert_eq!(buffer.load().unwrap(), readings);
+
+        buffer.replace(&readings).unwrap();
+        assert_eq!(buffer.load().unwrap(), readings[1..]);
+
+        buffer.replace(&[]).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn is_buffer_capped_while_offline() {
+        let path = std::env::temp_dir().join(format!("hevn-offline-{}.jsonl", std::process::id()));
+        let pusher = Pusher::new(
+            "http://127.0.0.1:1/ingest".to_string(),
+            None,
+            10,
+            Buffer::new(path.clone(), 3),
+        );
+
+        let readings = (0..5)
+            .map(|i| EnvData::new("Bedroom".to_string(), 200 + i, 400))
+            .collect::<Vec<_>>();
+        for r in &readings {
+            pusher.push(vec![r.clone()]).await.unwrap();
+        }
+        assert_eq!(pusher.buffer.load().unwrap(), readings[2..]);
+
+        pusher.buffer.replace(&[]).unwrap();
+    }
+}
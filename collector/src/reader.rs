@@ -1,21 +1,45 @@
+use crate::config::Backend;
 use dht11::Dht11;
+use log::warn;
 use rppal::gpio::Gpio;
 use rppal::hal::Delay;
 
+/// How many measurements to attempt before giving up on the sensor
+const MAX_TRIES: usize = 16;
+
+pub fn read_sensor(backend: Backend, pin: u8) -> Result<(i16, u16), Box<dyn std::error::Error>> {
+    match backend {
+        Backend::Dht11 => read_dht11(pin),
+        Backend::Simulated => Ok(read_simulated()),
+    }
+}
+
 pub fn read_dht11(pin: u8) -> Result<(i16, u16), Box<dyn std::error::Error>> {
     let my_pin = Gpio::new()?.get(pin)?.into_io(rppal::gpio::Mode::Output);
     let mut dht11 = Dht11::new(my_pin);
 
     let mut delay = Delay::new();
 
+    let mut tries = 0;
     loop {
         match dht11.perform_measurement(&mut delay) {
             Ok(res) => {
                 return Ok((res.temperature, res.humidity));
             }
             Err(err) => {
-                println!("Error reading retrying.., {:?}", err);
+                tries += 1;
+                if tries >= MAX_TRIES {
+                    return Err(format!("Sensor failed after {} tries, {:?}", tries, err).into());
+                }
+                warn!("Could not read the DHT11, retrying, {:?}", err);
             }
         }
     }
 }
+
+/// Slowly drifting readings around 21 °C and 40 %
+fn read_simulated() -> (i16, u16) {
+    let secs = util::now();
+    let drift = ((secs / 60) % 20) as i16 - 10;
+    (210 + drift, (400 + drift * 2) as u16)
+}
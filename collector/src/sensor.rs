@@ -0,0 +1,218 @@
+use crate::config::SensorConfig;
+use crate::health::Health;
+use crate::mqtt::Mqtt;
+use crate::reader::read_sensor;
+use crate::stored_data::StoredData;
+use actix_web::{error, Result};
+use log::{debug, warn};
+use tokio::sync::{Mutex, RwLock};
+use util::{CollectorHealth, CollectorInfo, EnvData};
+
+/// A sensor with its settings and everything we remember about it
+pub struct Sensor {
+    name: String,
+    config: RwLock<SensorConfig>,
+    /// Only one read from the sensor at the time
+    lock: Mutex<()>,
+    stored_data: StoredData,
+    health: Health,
+}
+
+impl Sensor {
+    pub fn new(config: SensorConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            stored_data: StoredData::new(config.limit),
+            config: RwLock::new(config),
+            lock: Mutex::new(()),
+            health: Health::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn config(&self) -> SensorConfig {
+        self.config.read().await.clone()
+    }
+
+    /// Changes the settings, keeping the stored data
+    pub async fn reload(&self, config: SensorConfig) {
+        let mut current = self.config.write().await;
+        if current.limit != config.limit {
+            self.stored_data.set_lim(config.limit).await;
+        }
+        *current = config;
+    }
+
+    pub async fn info(&self) -> CollectorInfo {
+        let config = self.config.read().await;
+        CollectorInfo::new(
+            config.name.clone(),
+            config.room.clone(),
+            config.backend.model().to_string(),
+            config.gpio,
+            env!("CARGO_PKG_VERSION").to_string(),
+        )
+    }
+
+    /// Reads the sensor and keeps the health up to date,
+    /// the read blocks on the gpio so it runs off the async workers
+    pub async fn sample(&self) -> Result<(i16, u16)> {
+        let _lock = self.lock.lock().await;
+        let config = self.config().await;
+        let (backend, gpio) = (config.backend, config.gpio);
+        let read = tokio::task::spawn_blocking(move || {
+            read_sensor(backend, gpio).map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        match read {
+            Ok((temp, humi)) => {
+                self.health.success().await;
+                Ok((
+                    temp + config.calibration.temperature_offset,
+                    (humi as i16 + config.calibration.humidity_offset).max(0) as u16,
+                ))
+            }
+            Err(e) => {
+                warn!("Could not read sensor {}, {}", self.name, e);
+                self.health.failure().await;
+                Err(error::ErrorServiceUnavailable(e))
+            }
+        }
+    }
+
+    /// The expected reading right now and how far off it may be
+    pub async fn predict(&self) -> String {
+        let filter = self.config.read().await.filter.clone();
+        let stored_data = &self.stored_data;
+        let possible_expected_data = stored_data.predict(stored_data.get_timestamp().await).await;
+        let deviation = stored_data
+            .get_expected_deviation(
+                filter.factor,
+                filter.min_temperature_deviation,
+                filter.min_humidity_deviation,
+            )
+            .await;
+        format!("{:?}, {:?}", possible_expected_data, deviation)
+    }
+
+    /// Reads the sensor without any filtering
+    pub async fn read(&self) -> Result<EnvData> {
+        let (temperature, humidity) = self.sample().await?;
+        Ok(EnvData::new(
+            self.config.read().await.room.clone(),
+            temperature,
+            humidity,
+        ))
+    }
+
+    /// Reads the sensor, discarding readings that are too far off the prediction,
+    /// and stores the accepted reading
+    pub async fn take_reading(&self, mqtt: &Mqtt) -> Result<EnvData> {
+        let filter = self.config.read().await.filter.clone();
+        let stored_data = &self.stored_data;
+        let possible_expected_data = stored_data.predict(stored_data.get_timestamp().await).await;
+        let deviation = stored_data
+            .get_expected_deviation(
+                filter.factor,
+                filter.min_temperature_deviation,
+                filter.min_humidity_deviation,
+            )
+            .await;
+        let mut tries = 0;
+
+        loop {
+            let (temp, humi) = self.sample().await?;
+
+            // Check if the data is valid
+            if let Some((devi_temp, devi_humi)) = deviation {
+                if let Some(true) = possible_expected_data.as_ref().map(|expected| {
+                    debug!(
+                        "Sensor {} read {} and {}, expected {} ± {} and {} ± {}, try {}",
+                        self.name,
+                        temp,
+                        humi,
+                        expected.temperature,
+                        devi_temp,
+                        expected.humidity,
+                        devi_humi,
+                        tries
+                    );
+                    ((expected.temperature as f32 - temp as f32).abs() > devi_temp
+                        || (expected.humidity as f32 - humi as f32).abs() > devi_humi)
+                        && tries < filter.max_tries
+                }) {
+                    tries += 1;
+                    continue;
+                }
+            }
+
+            let env_data = EnvData::new(self.config.read().await.room.clone(), temp, humi);
+
+            // Store the data
+            stored_data.add(env_data.clone()).await;
+            if stored_data.len().await > stored_data.get_lim() {
+                stored_data.remove().await;
+            }
+
+            mqtt.publish(&env_data);
+
+            return Ok(env_data);
+        }
+    }
+
+    pub async fn health(&self) -> CollectorHealth {
+        let config = self.config().await;
+        // Nobody has asked for data in a while, so check the sensor ourselves
+        if self.health.is_stale(&config.health).await {
+            let _ = self.sample().await;
+        }
+        self.health.report(&config.health).await
+    }
+}
+
+/// All the sensors of the collector, the first one is the default
+pub struct Sensors {
+    sensors: Vec<Sensor>,
+}
+
+impl Sensors {
+    pub fn new(configs: Vec<SensorConfig>) -> Self {
+        Self {
+            sensors: configs.into_iter().map(Sensor::new).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Sensor> {
+        self.sensors.iter().find(|s| s.name() == name)
+    }
+
+    pub fn first(&self) -> &Sensor {
+        &self.sensors[0]
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Sensor> {
+        self.sensors.iter()
+    }
+
+    /// Changes the settings of the sensors we already have,
+    /// returns the names of the sensors that could not be added or removed
+    pub async fn reload(&self, configs: Vec<SensorConfig>) -> Vec<String> {
+        let mut ignored = Vec::new();
+        for config in &configs {
+            match self.get(&config.name) {
+                Some(sensor) => sensor.reload(config.clone()).await,
+                None => ignored.push(config.name.clone()),
+            }
+        }
+        for sensor in self.iter() {
+            if !configs.iter().any(|c| c.name == sensor.name()) {
+                ignored.push(sensor.name().to_string());
+            }
+        }
+        ignored
+    }
+}
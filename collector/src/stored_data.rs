@@ -1,4 +1,5 @@
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use util::EnvData;
@@ -61,7 +62,7 @@ pub fn linear_regression<T: Stats>(xs: &[T], ys: &[T]) -> Option<(T, T)> {
 
 pub struct StoredData {
     s_data: Mutex<VecDeque<(Duration, EnvData)>>,
-    lim: usize,
+    lim: AtomicUsize,
     p_start: Mutex<Instant>,
 }
 
@@ -69,7 +70,7 @@ impl StoredData {
     pub fn new(lim: usize) -> Self {
         StoredData {
             s_data: Mutex::new(VecDeque::new()),
-            lim,
+            lim: AtomicUsize::new(lim),
             p_start: Mutex::new(Instant::now()),
         }
     }
@@ -96,13 +97,27 @@ impl StoredData {
     }
 
     pub fn get_lim(&self) -> usize {
-        self.lim
+        self.lim.load(Ordering::Relaxed)
     }
 
-    pub async fn get_expected_deviation<T: Stats + From<i16>>(&self, factor: T) -> Option<(T, T)> {
+    /// Changes the limit, dropping the oldest data if there is too much
+    pub async fn set_lim(&self, lim: usize) {
+        let mut s_data = self.s_data.lock().await;
+        self.lim.store(lim, Ordering::Relaxed);
+        while s_data.len() > lim {
+            s_data.pop_front();
+        }
+    }
+
+    pub async fn get_expected_deviation<T: Stats + From<i16>>(
+        &self,
+        factor: T,
+        min_temp: T,
+        min_humi: T,
+    ) -> Option<(T, T)> {
         let s_data = self.s_data.lock().await;
         let len = s_data.len();
-        if len < self.lim {
+        if len < self.get_lim() {
             return None;
         }
         let humis = s_data
@@ -117,11 +132,11 @@ impl StoredData {
         let mut std_temp: T = std_dev(&temps, mean(&temps)?)?;
         let mut std_humi: T = std_dev(&humis, mean(&humis)?)?;
 
-        if std_temp < 10i16.into() {
-            std_temp = 10i16.into();
+        if std_temp < min_temp {
+            std_temp = min_temp;
         }
-        if std_humi < 20i16.into() {
-            std_humi = 20i16.into();
+        if std_humi < min_humi {
+            std_humi = min_humi;
         }
 
         Some((std_temp * factor, std_humi * factor))
@@ -133,7 +148,7 @@ impl StoredData {
     pub async fn predict(&self, timestamp: Duration) -> Option<EnvData> {
         let s_data = self.s_data.lock().await;
 
-        if s_data.len() < self.lim {
+        if s_data.len() < self.get_lim() {
             return None;
         }
 
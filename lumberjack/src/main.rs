@@ -17,6 +17,10 @@ struct Opt {
 
     #[structopt(short = "d", long = "database_url", default_value = "")]
     database_url: String,
+
+    /// API token with the read scope, made with `aggregator token add`
+    #[structopt(short = "t", long = "token")]
+    token: Option<String>,
 }
 
 #[tokio::main]
@@ -25,7 +29,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let opt = Opt::from_args();
 
-    let res: Vec<EnvData> = client.get(opt.url.as_str()).send().await?.json().await?;
+    let mut request = client.get(opt.url.as_str());
+    if let Some(token) = &opt.token {
+        request = request.bearer_auth(token);
+    }
+    let res: Vec<EnvData> = request.send().await?.error_for_status()?.json().await?;
 
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
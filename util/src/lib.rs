@@ -1,8 +1,10 @@
+pub mod openapi;
+pub mod tls;
 mod util;
 
-
 pub use crate::util::{
-    Collector, CollectorError, EnvData, ShellyS1, ShellyS1Error, ShellyStatus, SmartAppliance,
+    now, Collector, CollectorError, CollectorHealth, CollectorInfo, EnvData, SensorStatus,
+    ShellyS1, ShellyS1Error, ShellyStatus, SmartAppliance, API_VERSION,
 };
 
 #[cfg(test)]
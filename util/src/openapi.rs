@@ -0,0 +1,498 @@
+use crate::util::{CollectorHealth, CollectorInfo, EnvData, SensorStatus, ShellyStatus};
+use serde_json::{json, Map, Value};
+
+/// A type sent or taken as JSON, described in the OpenAPI documents
+pub trait Schema {
+    /// Name of the schema under `components/schemas`
+    const NAME: &'static str;
+    fn schema() -> Value;
+}
+
+pub fn reference<T: Schema>() -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", T::NAME) })
+}
+
+pub fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+pub fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+pub fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+pub fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+pub fn string_enum(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+/// Anything at all
+pub fn any() -> Value {
+    json!({})
+}
+
+pub fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// At least one of the schemas
+pub fn any_of(schemas: Vec<Value>) -> Value {
+    json!({ "anyOf": schemas })
+}
+
+/// Allows null as well, for `Option` fields that are always sent
+pub fn nullable(schema: Value) -> Value {
+    // Fields next to a `$ref` are ignored, so it goes in an `allOf`
+    let mut schema = if schema.get("$ref").is_some() {
+        json!({ "allOf": [schema] })
+    } else {
+        schema
+    };
+    schema["nullable"] = Value::Bool(true);
+    schema
+}
+
+/// An object with exactly these fields, the optional ones may be left out
+pub fn object(required: &[(&str, Value)], optional: &[(&str, Value)]) -> Value {
+    let properties: Map<String, Value> = required
+        .iter()
+        .chain(optional)
+        .map(|(name, schema)| (name.to_string(), schema.clone()))
+        .collect();
+    let names: Vec<&str> = required.iter().map(|(name, _)| *name).collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": names,
+        "additionalProperties": false,
+    })
+}
+
+/// What a route takes and answers
+pub struct Operation {
+    value: Map<String, Value>,
+}
+
+impl Operation {
+    pub fn new(summary: &str) -> Self {
+        let mut value = Map::new();
+        value.insert("summary".to_string(), json!(summary));
+        value.insert("responses".to_string(), json!({}));
+        Self { value }
+    }
+
+    pub fn query(mut self, name: &str, description: &str, schema: Value) -> Self {
+        self.parameter(json!({
+            "name": name,
+            "in": "query",
+            "description": description,
+            "schema": schema,
+        }));
+        self
+    }
+
+    /// A JSON body, the example is sent by the tests
+    pub fn body(mut self, schema: Value, example: Value) -> Self {
+        self.value.insert(
+            "requestBody".to_string(),
+            json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema, "example": example } },
+            }),
+        );
+        self
+    }
+
+    pub fn json(self, status: u16, description: &str, schema: Value) -> Self {
+        self.response(
+            status,
+            json!({
+                "description": description,
+                "content": { "application/json": { "schema": schema } },
+            }),
+        )
+    }
+
+    pub fn text(self, status: u16, description: &str) -> Self {
+        self.media(status, description, "text/plain")
+    }
+
+    /// A body that is not JSON, like a page or a script
+    pub fn media(self, status: u16, description: &str, media_type: &str) -> Self {
+        self.response(
+            status,
+            json!({
+                "description": description,
+                "content": { media_type: { "schema": string() } },
+            }),
+        )
+    }
+
+    /// A response without a body
+    pub fn empty(self, status: u16, description: &str) -> Self {
+        self.response(status, json!({ "description": description }))
+    }
+
+    fn response(mut self, status: u16, response: Value) -> Self {
+        self.value["responses"][status.to_string()] = response;
+        self
+    }
+
+    fn parameter(&mut self, parameter: Value) {
+        match self.value.get_mut("parameters") {
+            Some(Value::Array(parameters)) => parameters.push(parameter),
+            _ => {
+                self.value
+                    .insert("parameters".to_string(), json!([parameter]));
+            }
+        }
+    }
+}
+
+/// An OpenAPI 3 document
+pub struct Spec {
+    document: Value,
+}
+
+impl Spec {
+    pub fn new(title: &str, version: &str) -> Self {
+        Self {
+            document: json!({
+                "openapi": "3.0.3",
+                "info": { "title": title, "version": version },
+                "paths": {},
+                "components": { "schemas": {} },
+            }),
+        }
+    }
+
+    /// Adds the schema of `T` so it can be referred to
+    pub fn schema<T: Schema>(mut self) -> Self {
+        self.document["components"]["schemas"][T::NAME] = T::schema();
+        self
+    }
+
+    /// Adds a route, the `{name}`s in the path are taken as string parameters
+    pub fn route(mut self, method: &str, path: &str, mut operation: Operation) -> Self {
+        for name in path
+            .split('/')
+            .filter_map(|s| s.strip_prefix('{')?.strip_suffix('}'))
+        {
+            operation.parameter(json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": string(),
+            }));
+        }
+        self.document["paths"][path][method.to_lowercase()] = Value::Object(operation.value);
+        self
+    }
+
+    pub fn build(self) -> Value {
+        self.document
+    }
+}
+
+/// Checks that `value` is what `schema` describes, `$ref`s are looked up in `document`
+pub fn check(document: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+    if value.is_null() && schema["nullable"] == Value::Bool(true) {
+        return Ok(());
+    }
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/components/schemas/");
+        let schema = &document["components"]["schemas"][name];
+        if schema.is_null() {
+            return Err(format!("no schema named {}", name));
+        }
+        return check(document, schema, value).map_err(|e| format!("{}: {}", name, e));
+    }
+    if let Some(schemas) = schema["allOf"].as_array() {
+        for schema in schemas {
+            check(document, schema, value)?;
+        }
+        return Ok(());
+    }
+    if let Some(schemas) = schema["anyOf"].as_array() {
+        let errors: Vec<String> = schemas
+            .iter()
+            .filter_map(|schema| check(document, schema, value).err())
+            .collect();
+        return if errors.len() < schemas.len() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        };
+    }
+    let matches = match schema["type"].as_str() {
+        Some("string") => value.as_str().is_some_and(|s| {
+            schema["enum"]
+                .as_array()
+                .is_none_or(|values| values.iter().any(|v| v == s))
+        }),
+        Some("integer") => value.is_i64() || value.is_u64(),
+        Some("number") => value.is_number(),
+        Some("boolean") => value.is_boolean(),
+        Some("array") => match value.as_array() {
+            Some(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    check(document, &schema["items"], item)
+                        .map_err(|e| format!("[{}] {}", i, e))?;
+                }
+                true
+            }
+            None => false,
+        },
+        Some("object") => match value.as_object() {
+            Some(fields) => return check_object(document, schema, fields),
+            None => false,
+        },
+        None => true,
+        Some(_) => return Err(format!("unknown schema {}", schema)),
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(format!("{} is not {}", value, schema))
+    }
+}
+
+fn check_object(
+    document: &Value,
+    schema: &Value,
+    fields: &Map<String, Value>,
+) -> Result<(), String> {
+    let properties = schema["properties"]
+        .as_object()
+        .cloned()
+        .unwrap_or_default();
+    for name in schema["required"].as_array().into_iter().flatten() {
+        let name = name.as_str().unwrap_or_default();
+        if !fields.contains_key(name) {
+            return Err(format!("{} is missing", name));
+        }
+    }
+    for (name, value) in fields {
+        match properties.get(name) {
+            Some(property) => {
+                check(document, property, value).map_err(|e| format!("{}: {}", name, e))?
+            }
+            None => return Err(format!("{} is not documented", name)),
+        }
+    }
+    Ok(())
+}
+
+/// Checks a response against what the document says `method` `path` answers with `status`
+pub fn check_response(
+    document: &Value,
+    method: &str,
+    path: &str,
+    status: u16,
+    body: &[u8],
+) -> Result<(), String> {
+    let operation = &document["paths"][path][method.to_lowercase()];
+    if operation.is_null() {
+        return Err(format!("{} {} is not documented", method, path));
+    }
+    let response = &operation["responses"][status.to_string()];
+    if response.is_null() {
+        return Err(format!("{} {} does not answer {}", method, path, status));
+    }
+    let schema = &response["content"]["application/json"]["schema"];
+    if schema.is_null() {
+        return Ok(());
+    }
+    let value: Value = serde_json::from_slice(body)
+        .map_err(|e| format!("{} {} answered {} with {}", method, path, status, e))?;
+    check(document, schema, &value).map_err(|e| format!("{} {} {}: {}", method, path, status, e))
+}
+
+/// The operations of the document, as method, path and operation
+pub fn operations(document: &Value) -> Vec<(String, String, &Value)> {
+    let mut operations = Vec::new();
+    for (path, methods) in document["paths"].as_object().into_iter().flatten() {
+        for (method, operation) in methods.as_object().into_iter().flatten() {
+            operations.push((method.to_uppercase(), path.clone(), operation));
+        }
+    }
+    operations
+}
+
+impl Schema for EnvData {
+    const NAME: &'static str = "EnvData";
+
+    fn schema() -> Value {
+        object(
+            &[
+                ("room", string()),
+                ("temperature", integer()),
+                ("humidity", integer()),
+                ("timestamp", integer()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for SensorStatus {
+    const NAME: &'static str = "SensorStatus";
+
+    fn schema() -> Value {
+        string_enum(&["ok", "failing", "unknown"])
+    }
+}
+
+impl Schema for CollectorHealth {
+    const NAME: &'static str = "CollectorHealth";
+
+    fn schema() -> Value {
+        object(
+            &[
+                ("healthy", boolean()),
+                ("sensor", reference::<SensorStatus>()),
+                ("seconds_since_last_read", nullable(integer())),
+                ("consecutive_failures", integer()),
+                ("uptime", integer()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for CollectorInfo {
+    const NAME: &'static str = "CollectorInfo";
+
+    fn schema() -> Value {
+        object(
+            &[
+                ("id", string()),
+                ("room", string()),
+                ("sensor", string()),
+                ("gpio", integer()),
+                ("version", string()),
+                ("api_version", integer()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for ShellyStatus {
+    const NAME: &'static str = "ShellyStatus";
+
+    fn schema() -> Value {
+        object(
+            &[
+                ("is_on", boolean()),
+                ("has_timer", boolean()),
+                ("timer_started", integer()),
+                ("timer_duration", integer()),
+                ("timer_remaining", integer()),
+                ("overpower", boolean()),
+                ("power", number()),
+                ("meter_overpower", number()),
+                ("timestamp", integer()),
+                ("temperature", number()),
+            ],
+            &[],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> Value {
+        Spec::new("test", "0")
+            .schema::<EnvData>()
+            .schema::<SensorStatus>()
+            .schema::<CollectorHealth>()
+            .schema::<CollectorInfo>()
+            .schema::<ShellyStatus>()
+            .route(
+                "GET",
+                "/sensor/{name}/data",
+                Operation::new("A reading")
+                    .json(200, "The reading", reference::<EnvData>())
+                    .text(404, "No such sensor"),
+            )
+            .build()
+    }
+
+    fn check_type<T: Schema + serde::Serialize>(value: T) -> Result<(), String> {
+        check(
+            &document(),
+            &reference::<T>(),
+            &serde_json::to_value(value).unwrap(),
+        )
+    }
+
+    #[test]
+    fn is_schema_matching() {
+        check_type(EnvData::new("Bedroom".to_string(), 215, 400)).unwrap();
+        check_type(ShellyStatus::default()).unwrap();
+        check_type(CollectorHealth {
+            healthy: true,
+            sensor: SensorStatus::Ok,
+            seconds_since_last_read: None,
+            consecutive_failures: 0,
+            uptime: 10,
+        })
+        .unwrap();
+        check_type(CollectorInfo::new(
+            "bedroom".to_string(),
+            "Bedroom".to_string(),
+            "DHT11".to_string(),
+            14,
+            "0.1.0".to_string(),
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn is_drift_found() {
+        let document = document();
+        let data = reference::<EnvData>();
+        let e = check(
+            &document,
+            &data,
+            &json!({"room": "Bedroom", "temperature": 215}),
+        );
+        assert_eq!(e.unwrap_err(), "EnvData: humidity is missing");
+        let e = check(
+            &document,
+            &data,
+            &json!({"room": "Bedroom", "temperature": 21.5, "humidity": 400, "timestamp": 0}),
+        );
+        assert!(e
+            .unwrap_err()
+            .starts_with("EnvData: temperature: 21.5 is not"));
+        let e = check(
+            &document,
+            &data,
+            &json!({"room": "Bedroom", "temperature": 215, "humidity": 400, "timestamp": 0, "pressure": 1}),
+        );
+        assert_eq!(e.unwrap_err(), "EnvData: pressure is not documented");
+        let maybe = nullable(data.clone());
+        assert!(check(&document, &maybe, &Value::Null).is_ok());
+        assert!(check(&document, &data, &Value::Null).is_err());
+        assert!(check(&document, &maybe, &json!({"room": "Bedroom"})).is_err());
+
+        let path = "/sensor/{name}/data";
+        assert!(check_response(&document, "GET", path, 404, b"No sensor named attic").is_ok());
+        assert!(check_response(&document, "GET", path, 500, b"").is_err());
+        assert!(check_response(&document, "POST", path, 200, b"").is_err());
+        assert_eq!(
+            document["paths"][path]["get"]["parameters"][0]["name"],
+            "name"
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct TlsError {
+    message: String,
+}
+
+impl std::error::Error for TlsError {}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+pub fn tls_error(path: &Path, message: impl fmt::Display) -> TlsError {
+    TlsError {
+        message: format!("{}: {}", path.display(), message),
+    }
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let file = File::open(path).map_err(|e| tls_error(path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|e| tls_error(path, e))?;
+    if certs.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first private key in the file, in PKCS#8, PKCS#1 or SEC1
+pub fn load_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let file = File::open(path).map_err(|e| tls_error(path, e))?;
+    let items =
+        rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| tls_error(path, e))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| tls_error(path, "no private key found"))
+}
+
+/// Serves `cert` and only lets in clients with a certificate signed by `client_ca`
+pub fn mutual_server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: &Path,
+) -> Result<ServerConfig, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for ca in load_certs(client_ca)? {
+        roots
+            .add(&ca)
+            .map_err(|e| tls_error(client_ca, format!("not a CA certificate, {}", e)))?;
+    }
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| tls_error(key, e))
+}
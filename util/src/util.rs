@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fmt;
+use std::time::{Duration, SystemTime};
 
 //Different kinds of appliences currently supported
 pub enum Appliences {
@@ -33,6 +34,9 @@ pub struct EnvData {
     pub room: String,
     pub temperature: i16,
     pub humidity: u16,
+    /// Seconds since the unix epoch when the data was read
+    #[serde(default)]
+    pub timestamp: u64,
 }
 
 impl EnvData {
@@ -41,10 +45,19 @@ impl EnvData {
             room,
             temperature,
             humidity,
+            timestamp: now(),
         }
     }
 }
 
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 impl fmt::Display for EnvData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -57,6 +70,53 @@ impl fmt::Display for EnvData {
     }
 }
 
+/// Version of the HTTP API the collectors serve
+pub const API_VERSION: u32 = 1;
+
+/// State of the sensor as seen by the collector
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorStatus {
+    Ok,
+    Failing,
+    Unknown,
+}
+
+/// Data type for the health report of a collector
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CollectorHealth {
+    pub healthy: bool,
+    pub sensor: SensorStatus,
+    pub seconds_since_last_read: Option<u64>,
+    pub consecutive_failures: u32,
+    pub uptime: u64,
+}
+
+/// Data type for the static information about a collector
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CollectorInfo {
+    /// Name of the sensor on the collector
+    pub id: String,
+    pub room: String,
+    pub sensor: String,
+    pub gpio: u8,
+    pub version: String,
+    pub api_version: u32,
+}
+
+impl CollectorInfo {
+    pub fn new(id: String, room: String, sensor: String, gpio: u8, version: String) -> Self {
+        Self {
+            id,
+            room,
+            sensor,
+            gpio,
+            version,
+            api_version: API_VERSION,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Collector {
     room: String,
@@ -79,7 +139,7 @@ impl Collector {
 
 #[derive(Debug, Default)]
 pub struct CollectorError {
-    error: Option<Box<dyn std::error::Error>>,
+    error: Option<Box<dyn std::error::Error + Send + Sync>>,
 }
 
 impl std::error::Error for CollectorError {}
@@ -128,11 +188,56 @@ impl SmartAppliance for Collector {
 pub struct ShellyS1 {
     room: String,
     url: String,
+    channel: usize,
+    credentials: Option<(String, String)>,
+    timeout: Duration,
 }
 
 impl ShellyS1 {
     pub fn new(room: String, url: String) -> Self {
-        Self { room, url }
+        Self {
+            room,
+            url,
+            channel: 0,
+            credentials: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Which relay to use
+    pub fn channel(mut self, channel: usize) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Username and password for devices with restricted login
+    pub fn credentials(mut self, username: String, password: String) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn get(&self, url: &str) -> Result<reqwest::blocking::Response, ShellyS1Error> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .map_err(|e| ShellyS1Error {
+                error: Some(Box::new(e)),
+            })?;
+        let mut request = client.get(url);
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        request
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| ShellyS1Error {
+                error: Some(Box::new(e)),
+            })
     }
 }
 
@@ -142,18 +247,18 @@ impl fmt::Display for ShellyS1 {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ShellyStatus {
-    is_on: bool,
-    has_timer: bool,
-    timer_started: u32,
-    timer_duration: u32,
-    timer_remaining: u32,
-    overpower: bool,
-    power: f32,
-    meter_overpower: f32,
-    timestamp: u32,
-    temperature: f32,
+    pub is_on: bool,
+    pub has_timer: bool,
+    pub timer_started: u32,
+    pub timer_duration: u32,
+    pub timer_remaining: u32,
+    pub overpower: bool,
+    pub power: f32,
+    pub meter_overpower: f32,
+    pub timestamp: u32,
+    pub temperature: f32,
 }
 
 impl Default for ShellyStatus {
@@ -200,11 +305,21 @@ impl SmartInfo for ShellyS1 {
 
 #[derive(Debug, Default)]
 pub struct ShellyS1Error {
-    error: Option<Box<dyn std::error::Error>>,
+    error: Option<Box<dyn std::error::Error + Send + Sync>>,
 }
 
 impl std::error::Error for ShellyS1Error {}
 
+impl ShellyS1Error {
+    /// Whether the device did not answer in time
+    pub fn is_timeout(&self) -> bool {
+        self.error
+            .as_ref()
+            .and_then(|e| e.downcast_ref::<reqwest::Error>())
+            .is_some_and(|e| e.is_timeout())
+    }
+}
+
 impl fmt::Display for ShellyS1Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.error {
@@ -220,39 +335,35 @@ impl SmartAppliance for ShellyS1 {
 
     fn get_status(&self) -> Result<Self::Status, Self::Error> {
         let url = format!("http://{}/status", self.url);
-        let response = reqwest::blocking::get(&url).map_err(|e| ShellyS1Error {
-            error: Some(Box::new(e)),
-        })?;
+        let response = self.get(&url)?;
         let status: Value = response.json().map_err(|e| ShellyS1Error {
             error: Some(Box::new(e)),
         })?;
+        let relay = &status["relays"][self.channel];
+        let meter = &status["meters"][self.channel];
         Ok(ShellyStatus {
-            is_on: status["relays"][0]["ison"].as_bool().unwrap_or(false),
-            has_timer: status["relays"][0]["has_timer"].as_bool().unwrap_or(false),
-            timer_started: status["relays"][0]["timer_started"].as_u64().unwrap_or(0) as u32,
-            timer_duration: status["relays"][0]["timer_duration"].as_u64().unwrap_or(0) as u32,
-            timer_remaining: status["relays"][0]["timer_remaining"].as_u64().unwrap_or(0) as u32,
-            overpower: status["relays"][0]["overpower"].as_bool().unwrap_or(false),
-            power: status["meters"][0]["power"].as_f64().unwrap_or(0.0) as f32,
-            meter_overpower: status["meters"][0]["overpower"].as_f64().unwrap_or(0.0) as f32,
-            timestamp: status["meters"][0]["timestamp"].as_u64().unwrap_or(0) as u32,
+            is_on: relay["ison"].as_bool().unwrap_or(false),
+            has_timer: relay["has_timer"].as_bool().unwrap_or(false),
+            timer_started: relay["timer_started"].as_u64().unwrap_or(0) as u32,
+            timer_duration: relay["timer_duration"].as_u64().unwrap_or(0) as u32,
+            timer_remaining: relay["timer_remaining"].as_u64().unwrap_or(0) as u32,
+            overpower: relay["overpower"].as_bool().unwrap_or(false),
+            power: meter["power"].as_f64().unwrap_or(0.0) as f32,
+            meter_overpower: meter["overpower"].as_f64().unwrap_or(0.0) as f32,
+            timestamp: meter["timestamp"].as_u64().unwrap_or(0) as u32,
             temperature: status["temperature"].as_f64().unwrap_or(0.0) as f32,
         })
     }
 
     fn turn_on(&self) -> Result<(), Self::Error> {
-        let url = format!("http://{}/relay/0?turn=on", self.url);
-        reqwest::blocking::get(&url).map_err(|e| ShellyS1Error {
-            error: Some(Box::new(e)),
-        })?;
+        let url = format!("http://{}/relay/{}?turn=on", self.url, self.channel);
+        self.get(&url)?;
         Ok(())
     }
 
     fn turn_off(&self) -> Result<(), Self::Error> {
-        let url = format!("http://{}/relay/0?turn=off", self.url);
-        reqwest::blocking::get(&url).map_err(|e| ShellyS1Error {
-            error: Some(Box::new(e)),
-        })?;
+        let url = format!("http://{}/relay/{}?turn=off", self.url, self.channel);
+        self.get(&url)?;
         Ok(())
     }
 }